@@ -0,0 +1,211 @@
+// Measures the throughput of the stable diffusion models, e.g. to compare the attention
+// backends and precisions or to check for performance regressions.
+//
+// The clip text encoder, the unet, and the vae decoder are timed separately. The unet is run
+// for a few warmup iterations, during which the kernels get selected, before the measured
+// iterations. See the stable-diffusion example for how to get the v1.5 weight files.
+//
+// cargo run --release --example benchmark --features clap -- --device cuda --dtype f16
+use clap::Parser;
+use diffusers::models::{attention::AttentionBackend, unet_2d};
+use diffusers::pipelines::stable_diffusion;
+use std::time::{Duration, Instant};
+use tch::{nn, nn::Module, Device, Kind, Tensor};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The device on which all the models are run.
+    #[arg(long, value_enum, default_value = "cuda")]
+    device: DeviceArg,
+
+    /// The precision used for the weights and computations of all the models.
+    #[arg(long, value_enum, default_value = "f32")]
+    dtype: Dtype,
+
+    /// The attention implementation used by the unet.
+    #[arg(long, value_enum, default_value = "standard")]
+    attention: AttentionBackendArg,
+
+    /// The size of the sliced attention or 0 for automatic slicing (disabled by default)
+    #[arg(long)]
+    sliced_attention_size: Option<i64>,
+
+    /// The height in pixels of the generated image.
+    #[arg(long, default_value_t = 512)]
+    height: i64,
+
+    /// The width in pixels of the generated image.
+    #[arg(long, default_value_t = 512)]
+    width: i64,
+
+    /// The number of images per unet forward pass, classifier-free guidance doubles the batch
+    /// size used by the unet.
+    #[arg(long, default_value_t = 1)]
+    batch_size: i64,
+
+    /// The number of unet forward passes run before the measurements start.
+    #[arg(long, default_value_t = 3)]
+    warmup: usize,
+
+    /// The number of measured unet forward passes.
+    #[arg(long, default_value_t = 20)]
+    iterations: usize,
+
+    /// The UNet weight file, in .ot or .safetensors format.
+    #[arg(long, value_name = "FILE", default_value = "data/unet.safetensors")]
+    unet_weights: String,
+
+    /// The CLIP weight file, in .ot or .safetensors format.
+    #[arg(long, value_name = "FILE", default_value = "data/pytorch_model.safetensors")]
+    clip_weights: String,
+
+    /// The VAE weight file, in .ot or .safetensors format.
+    #[arg(long, value_name = "FILE", default_value = "data/vae.safetensors")]
+    vae_weights: String,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum DeviceArg {
+    Cpu,
+    Cuda,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Dtype {
+    F32,
+    Bf16,
+    F16,
+}
+
+impl Dtype {
+    fn kind(&self) -> Kind {
+        match self {
+            Self::F32 => Kind::Float,
+            Self::Bf16 => Kind::BFloat16,
+            Self::F16 => Kind::Half,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum AttentionBackendArg {
+    Standard,
+    Sdpa,
+    Auto,
+}
+
+impl AttentionBackendArg {
+    fn backend(&self) -> AttentionBackend {
+        match self {
+            Self::Standard => AttentionBackend::Standard,
+            Self::Sdpa => AttentionBackend::ScaledDotProduct,
+            Self::Auto => AttentionBackend::Auto,
+        }
+    }
+}
+
+/// Runs `f` and returns its result together with the elapsed time, waiting for the queued
+/// CUDA kernels to complete so that the asynchronous execution is accounted for.
+fn timed<T>(device: Device, f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let res = f();
+    if let Device::Cuda(index) = device {
+        tch::Cuda::synchronize(index as i64)
+    }
+    (res, start.elapsed())
+}
+
+/// The peak resident memory of the process in megabytes as reported by linux, this does not
+/// include the memory allocated on the GPU.
+fn peak_host_memory_mb() -> Option<f64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb: f64 =
+        line.trim_start_matches("VmHWM:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb / 1024.)
+}
+
+/// Same as `StableDiffusionConfig::build_unet` with the weights converted to `kind`.
+fn build_unet(
+    config: &stable_diffusion::StableDiffusionConfig,
+    unet_weights: &str,
+    device: Device,
+    kind: Kind,
+) -> anyhow::Result<unet_2d::UNet2DConditionModel> {
+    let mut vs = nn::VarStore::new(device);
+    let unet = unet_2d::UNet2DConditionModel::new(vs.root(), 4, 4, config.unet.clone());
+    vs.load(unet_weights)?;
+    vs.set_kind(kind);
+    Ok(unet)
+}
+
+fn run(args: Args) -> anyhow::Result<()> {
+    tch::maybe_init_cuda();
+    let device = match args.device {
+        DeviceArg::Cpu => Device::Cpu,
+        DeviceArg::Cuda => {
+            if !tch::Cuda::is_available() {
+                anyhow::bail!("cuda is not available")
+            }
+            Device::Cuda(0)
+        }
+    };
+    let kind = args.dtype.kind();
+    let mut config = stable_diffusion::StableDiffusionConfig::v1_5(
+        args.sliced_attention_size,
+        Some(args.height),
+        Some(args.width),
+    );
+    config.unet.attention_backend = args.attention.backend();
+    (config.clip_kind, config.vae_kind) = (kind, kind);
+    let _no_grad_guard = tch::no_grad_guard();
+
+    println!("Building the models.");
+    let text_model = config.build_clip_transformer_with_kind(&args.clip_weights, device, kind)?;
+    let vae = config.build_vae_with_kind(&args.vae_weights, device, kind)?;
+    let unet = build_unet(&config, &args.unet_weights, device, kind)?;
+
+    let bsize = args.batch_size;
+    let latent_size = [bsize, 4, config.height / 8, config.width / 8];
+    // The prompts and the negative prompts, padded to the clip context length.
+    let tokens = Tensor::zeros([2 * bsize, 77], (Kind::Int64, device));
+    let (text_embeddings, clip_time) = timed(device, || text_model.forward(&tokens));
+    let text_embeddings = text_embeddings.to_kind(kind);
+
+    let latents = Tensor::randn(latent_size, (kind, device));
+    let latent_model_input = Tensor::cat(&[&latents, &latents], 0);
+    for _ in 0..args.warmup {
+        let _ = timed(device, || unet.forward(&latent_model_input, 999., &text_embeddings));
+    }
+    let mut unet_time = Duration::ZERO;
+    for _ in 0..args.iterations {
+        let (_, elapsed) =
+            timed(device, || unet.forward(&latent_model_input, 999., &text_embeddings));
+        unet_time += elapsed;
+    }
+    let (_, vae_time) = timed(device, || vae.decode(&latents));
+
+    println!(
+        "{:?} {:?} {:?} attention, {}x{} batch of {bsize}",
+        args.device, args.dtype, args.attention, config.width, config.height
+    );
+    println!("clip: {:.1}ms", clip_time.as_secs_f64() * 1e3);
+    let unet_step = unet_time.as_secs_f64() / args.iterations.max(1) as f64;
+    println!("unet: {:.1}ms per step, {:.2} it/s", unet_step * 1e3, 1. / unet_step);
+    println!("vae:  {:.1}ms", vae_time.as_secs_f64() * 1e3);
+    let estimate = stable_diffusion::estimate_memory(&config, bsize, kind);
+    println!(
+        "estimated memory: {:.0}MB of weights, {:.0}MB of unet activations",
+        estimate.weights() as f64 / 1048576.,
+        estimate.unet_activations as f64 / 1048576.
+    );
+    if let Some(peak_mb) = peak_host_memory_mb() {
+        println!("peak host memory: {peak_mb:.0}MB");
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    run(Args::parse())
+}
@@ -3,6 +3,7 @@
 // This has to be copied in data/controlnet.safetensors
 use clap::Parser;
 use diffusers::pipelines::stable_diffusion;
+use diffusers::schedulers;
 use diffusers::transformers::clip;
 use tch::{nn, nn::Module, Device, Kind, Tensor};
 
@@ -88,33 +89,6 @@ struct Args {
     control_type: ControlType,
 }
 
-fn output_filename(
-    basename: &str,
-    sample_idx: i64,
-    num_samples: i64,
-    timestep_idx: Option<usize>,
-) -> String {
-    let filename = if num_samples > 1 {
-        match basename.rsplit_once('.') {
-            None => format!("{basename}.{sample_idx}.png"),
-            Some((filename_no_extension, extension)) => {
-                format!("{filename_no_extension}.{sample_idx}.{extension}")
-            }
-        }
-    } else {
-        basename.to_string()
-    };
-    match timestep_idx {
-        None => filename,
-        Some(timestep_idx) => match filename.rsplit_once('.') {
-            None => format!("{filename}-{timestep_idx}.png"),
-            Some((filename_no_extension, extension)) => {
-                format!("{filename_no_extension}-{timestep_idx}.{extension}")
-            }
-        },
-    }
-}
-
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
 enum ControlType {
     Canny,
@@ -177,6 +151,7 @@ fn run(args: Args) -> anyhow::Result<()> {
     let clip_device = device_setup.get("clip");
     let vae_device = device_setup.get("vae");
     let unet_device = device_setup.get("unet");
+    schedulers::check_inference_steps(n_steps, sd_config.scheduler.train_timesteps)?;
     let scheduler = sd_config.build_scheduler(n_steps);
 
     let tokenizer = clip::Tokenizer::create(vocab_file, &sd_config.clip)?;
@@ -192,9 +167,9 @@ fn run(args: Args) -> anyhow::Result<()> {
 
     println!("Building the Clip transformer.");
     let text_model = sd_config.build_clip_transformer(&clip_weights, clip_device)?;
-    let text_embeddings = text_model.forward(&tokens);
-    let uncond_embeddings = text_model.forward(&uncond_tokens);
-    let text_embeddings = Tensor::cat(&[uncond_embeddings, text_embeddings], 0).to(unet_device);
+    // Both prompts are padded to the clip context length so they can be encoded in a single pass.
+    let tokens = Tensor::cat(&[uncond_tokens, tokens], 0);
+    let text_embeddings = text_model.forward(&tokens).to(unet_device);
 
     println!("Building the autoencoder.");
     let vae = sd_config.build_vae(&vae_weights, vae_device)?;
@@ -242,9 +217,13 @@ fn run(args: Args) -> anyhow::Result<()> {
                 let image = vae.decode(&(&latents / 0.18215));
                 let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
                 let image = (image * 255.).to_kind(Kind::Uint8);
-                let final_image =
-                    output_filename(&final_image, idx + 1, num_samples, Some(timestep_index + 1));
-                tch::vision::image::save(&image, final_image)?;
+                let naming = stable_diffusion::NamingOptions {
+                    first_index: (idx + 1) as usize,
+                    count: Some(num_samples as usize),
+                    step: Some(timestep_index + 1),
+                    ..Default::default()
+                };
+                stable_diffusion::save_images(&[image], &final_image, &naming)?;
             }
         }
 
@@ -253,8 +232,12 @@ fn run(args: Args) -> anyhow::Result<()> {
         let image = vae.decode(&(&latents / 0.18215));
         let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
         let image = (image * 255.).to_kind(Kind::Uint8);
-        let final_image = output_filename(&final_image, idx + 1, num_samples, None);
-        tch::vision::image::save(&image, final_image)?;
+        let naming = stable_diffusion::NamingOptions {
+            first_index: (idx + 1) as usize,
+            count: Some(num_samples as usize),
+            ..Default::default()
+        };
+        stable_diffusion::save_images(&[image], &final_image, &naming)?;
     }
 
     drop(no_grad_guard);
@@ -10,6 +10,8 @@
 // prompt = "A fantasy landscape, trending on artstation"
 use clap::Parser;
 use diffusers::pipelines::stable_diffusion;
+use diffusers::preprocess::{resize_for_sd, ResizeMode};
+use diffusers::schedulers;
 use diffusers::transformers::clip;
 use tch::{nn::Module, Device, Kind, Tensor};
 
@@ -124,7 +126,7 @@ fn image_preprocess<T: AsRef<std::path::Path>>(path: T) -> anyhow::Result<Tensor
     let (_num_channels, height, width) = image.size3()?;
     let height = height - height % 32;
     let width = width - width % 32;
-    let image = tch::vision::image::resize(&image, width, height)?;
+    let image = resize_for_sd(&image, width, height, ResizeMode::Stretch);
     Ok((image / 255. * 2. - 1.).unsqueeze(0))
 }
 
@@ -166,6 +168,7 @@ fn run(args: Args) -> anyhow::Result<()> {
     let clip_device = device_setup.get("clip");
     let vae_device = device_setup.get("vae");
     let unet_device = device_setup.get("unet");
+    schedulers::check_inference_steps(n_steps, sd_config.scheduler.train_timesteps)?;
     let scheduler = sd_config.build_scheduler(n_steps);
 
     let tokenizer = clip::Tokenizer::create(vocab_file, &sd_config.clip)?;
@@ -181,9 +184,9 @@ fn run(args: Args) -> anyhow::Result<()> {
 
     println!("Building the Clip transformer.");
     let text_model = sd_config.build_clip_transformer(&clip_weights, clip_device)?;
-    let text_embeddings = text_model.forward(&tokens);
-    let uncond_embeddings = text_model.forward(&uncond_tokens);
-    let text_embeddings = Tensor::cat(&[uncond_embeddings, text_embeddings], 0).to(unet_device);
+    // Both prompts are padded to the clip context length so they can be encoded in a single pass.
+    let tokens = Tensor::cat(&[uncond_tokens, tokens], 0);
+    let text_embeddings = text_model.forward(&tokens).to(unet_device);
 
     println!("Building the autoencoder.");
     let vae = sd_config.build_vae(&vae_weights, vae_device)?;
@@ -16,6 +16,7 @@
 // https://raw.githubusercontent.com/CompVis/latent-diffusion/main/data/inpainting_examples/overture-creations-5sI6fQgYIuo_mask.png
 use clap::Parser;
 use diffusers::pipelines::stable_diffusion_turbo as stable_diffusion;
+use diffusers::schedulers;
 use diffusers::transformers::clip;
 use tch::{nn::Module, Device, Kind, Tensor};
 
@@ -33,6 +34,15 @@ struct Args {
 				#[arg(long, value_name = "FILE")]
 				mask_image: String,
 
+				/// The standard deviation in pixels of the gaussian blur applied to the mask edges so that the
+				/// inpainted region blends smoothly with the rest of the image, 0 disables the blur.
+				#[arg(long, default_value_t = 0.)]
+				mask_blur: f64,
+
+				/// The filter used to resize the mask to the latent resolution.
+				#[arg(long, value_enum, default_value = "nearest")]
+				mask_downsampling: MaskDownsampling,
+
 				/// The prompt to be used for image generation.
 				#[arg(long, default_value = "Face of a yellow cat, high resolution, sitting on a park bench")]
 				prompt: String,
@@ -96,6 +106,23 @@ enum StableDiffusionVersion {
 				V2_1,
 }
 
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum MaskDownsampling {
+				Nearest,
+				Bilinear,
+				Area,
+}
+
+impl MaskDownsampling {
+				fn filter(&self) -> stable_diffusion::MaskDownsampling {
+								match self {
+												Self::Nearest => stable_diffusion::MaskDownsampling::Nearest,
+												Self::Bilinear => stable_diffusion::MaskDownsampling::Bilinear,
+												Self::Area => stable_diffusion::MaskDownsampling::Area,
+								}
+				}
+}
+
 fn file(file: &'static str) -> String { assert!(std::path::Path::new(file).exists(), "{file}"); file.to_string() }
 
 impl Args {
@@ -133,15 +160,11 @@ impl Args {
 fn prepare_mask_and_masked_image<T: AsRef<std::path::Path>>(
 				path_input: T,
 				path_mask: T,
+				mask_blur: f64,
 ) -> anyhow::Result<(Tensor, Tensor)> {
 				let image = tch::vision::image::load(path_input)?;
-				let image = image / 255. * 2. - 1.;
-
 				let mask = tch::vision::image::load(path_mask)?;
-				let mask = mask.mean_dim(Some([0].as_slice()), true, Kind::Float);
-				let mask = mask.ge(122.5).totype(Kind::Float);
-				let masked_image: Tensor = image * (1 - &mask);
-				Ok((mask.unsqueeze(0), masked_image.unsqueeze(0)))
+				Ok(diffusers::preprocess::prepare_mask_and_masked_image(&image, &mask, mask_blur))
 }
 
 fn run(args: Args) -> anyhow::Result<()> {
@@ -160,6 +183,8 @@ fn run(args: Args) -> anyhow::Result<()> {
 								num_samples,
 								input_image,
 								mask_image,
+								mask_blur,
+								mask_downsampling,
 								vocab_file,
 								sd_version,
 								..
@@ -177,12 +202,13 @@ fn run(args: Args) -> anyhow::Result<()> {
 												width,
 								),
 				};
-				let (mask, masked_image) = prepare_mask_and_masked_image(input_image, mask_image)?;
+				let (mask, masked_image) = prepare_mask_and_masked_image(input_image, mask_image, mask_blur)?;
 				println!("Loaded input image and mask, {:?} {:?}.", masked_image.size(), mask.size());
 				let device_setup = diffusers::utils::DeviceSetup::new(cpu);
 				let clip_device = device_setup.get("clip");
 				let vae_device = device_setup.get("vae");
 				let unet_device = device_setup.get("unet");
+				schedulers::check_inference_steps(n_steps, sd_config.scheduler.train_timesteps)?;
 				let scheduler = sd_config.build_scheduler(n_steps);
 
 				let tokenizer = clip::Tokenizer::create(vocab_file, &sd_config.clip)?;
@@ -198,9 +224,9 @@ fn run(args: Args) -> anyhow::Result<()> {
 
 				println!("Building the Clip transformer.");
 				let text_model = sd_config.build_clip_transformer(&clip_weights, clip_device)?;
-				let text_embeddings = text_model.forward(&tokens);
-				let uncond_embeddings = text_model.forward(&uncond_tokens);
-				let text_embeddings = Tensor::cat(&[uncond_embeddings, text_embeddings], 0).to(unet_device);
+				// Both prompts are padded to the clip context length so they can be encoded in a single pass.
+				let tokens = Tensor::cat(&[uncond_tokens, tokens], 0);
+				let text_embeddings = text_model.forward(&tokens).to(unet_device);
 
 				println!("Building the autoencoder.");
 				let vae = sd_config.build_vae(&vae_weights, vae_device)?;
@@ -208,7 +234,7 @@ fn run(args: Args) -> anyhow::Result<()> {
 				let unet = sd_config.build_unet(&unet_weights, unet_device, 9)?;
 
 				dbg!();
-				let mask = mask.upsample_nearest2d([sd_config.height / 8, sd_config.width / 8], None, None);
+				let mask = mask_downsampling.filter().apply(&mask, sd_config.height / 8, sd_config.width / 8);
 				let mask = Tensor::cat(&[&mask, &mask], 0).to_device(unet_device);
 				let masked_image_dist = vae.encode(&masked_image.to_device(vae_device));
 
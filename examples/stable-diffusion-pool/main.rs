@@ -0,0 +1,187 @@
+// Serving multiple prompts concurrently from a single set of loaded weights.
+//
+// The pipeline is loaded once, wrapped in an `Arc` and shared between worker threads, each
+// thread generating the image for one of the prompts. See the stable-diffusion example for
+// how to get the weight files.
+//
+// cargo run --example stable-diffusion-pool --features clap -- \
+//   --prompts "A rusty robot holding a fire torch" "A fantasy landscape, trending on artstation"
+use clap::Parser;
+use diffusers::pipelines::stable_diffusion;
+use std::sync::Arc;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The prompts to be generated concurrently, one thread is spawned per prompt.
+    #[arg(
+        long,
+        num_args = 1..,
+        default_values = [
+            "A very realistic photo of a rusty robot walking on a sandy beach",
+            "A fantasy landscape, trending on artstation",
+        ]
+    )]
+    prompts: Vec<String>,
+
+    /// When set, use the CPU for the listed devices, can be 'all', 'unet', 'clip', etc.
+    /// Multiple values can be set.
+    #[arg(long)]
+    cpu: Vec<String>,
+
+    /// The number of threads used by libtorch for the CPU operations, the libtorch default
+    /// when not set.
+    #[arg(long)]
+    num_threads: Option<usize>,
+
+    /// The height in pixels of the generated image.
+    #[arg(long)]
+    height: Option<i64>,
+
+    /// The width in pixels of the generated image.
+    #[arg(long)]
+    width: Option<i64>,
+
+    /// The UNet weight file, in .ot or .safetensors format.
+    #[arg(long, value_name = "FILE")]
+    unet_weights: Option<String>,
+
+    /// The CLIP weight file, in .ot or .safetensors format.
+    #[arg(long, value_name = "FILE")]
+    clip_weights: Option<String>,
+
+    /// The VAE weight file, in .ot or .safetensors format.
+    #[arg(long, value_name = "FILE")]
+    vae_weights: Option<String>,
+
+    #[arg(long, value_name = "FILE", default_value = "data/bpe_simple_vocab_16e6.txt")]
+    /// The file specifying the vocabulary to used for tokenization.
+    vocab_file: String,
+
+    /// The size of the sliced attention or 0 for automatic slicing (disabled by default)
+    #[arg(long)]
+    sliced_attention_size: Option<i64>,
+
+    /// The number of steps to run the diffusion for.
+    #[arg(long, default_value_t = 30)]
+    n_steps: usize,
+
+    /// The random seed to be used for the generation.
+    #[arg(long, default_value_t = 32)]
+    seed: i64,
+
+    /// Use autocast (disabled by default as it may use more memory in some cases).
+    #[arg(long, action)]
+    autocast: bool,
+
+    /// Run the vae without autocast when using `--autocast`, this avoids the black images
+    /// caused by overflows in the half precision vae decoder.
+    #[arg(long, action)]
+    no_vae_autocast: bool,
+
+    #[arg(long, value_enum, default_value = "v2-1")]
+    sd_version: StableDiffusionVersion,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum StableDiffusionVersion {
+    V1_5,
+    V2_1,
+}
+
+impl Args {
+    fn clip_weights(&self) -> String {
+        match &self.clip_weights {
+            Some(w) => w.clone(),
+            None => match self.sd_version {
+                StableDiffusionVersion::V1_5 => "data/pytorch_model.safetensors".to_string(),
+                StableDiffusionVersion::V2_1 => "data/clip_v2.1.safetensors".to_string(),
+            },
+        }
+    }
+
+    fn vae_weights(&self) -> String {
+        match &self.vae_weights {
+            Some(w) => w.clone(),
+            None => match self.sd_version {
+                StableDiffusionVersion::V1_5 => "data/vae.safetensors".to_string(),
+                StableDiffusionVersion::V2_1 => "data/vae_v2.1.safetensors".to_string(),
+            },
+        }
+    }
+
+    fn unet_weights(&self) -> String {
+        match &self.unet_weights {
+            Some(w) => w.clone(),
+            None => match self.sd_version {
+                StableDiffusionVersion::V1_5 => "data/unet.safetensors".to_string(),
+                StableDiffusionVersion::V2_1 => "data/unet_v2.1.safetensors".to_string(),
+            },
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    tch::maybe_init_cuda();
+    let sd_config = match args.sd_version {
+        StableDiffusionVersion::V1_5 => stable_diffusion::StableDiffusionConfig::v1_5(
+            args.sliced_attention_size,
+            args.height,
+            args.width,
+        ),
+        StableDiffusionVersion::V2_1 => stable_diffusion::StableDiffusionConfig::v2_1(
+            args.sliced_attention_size,
+            args.height,
+            args.width,
+        ),
+    };
+    let device_setup = diffusers::utils::DeviceSetup::new(args.cpu.clone());
+    println!("Loading the pipeline.");
+    let pipeline = stable_diffusion::StableDiffusionPipeline::new(
+        sd_config,
+        &args.vocab_file,
+        &args.clip_weights(),
+        &args.vae_weights(),
+        &args.unet_weights(),
+        4,
+        &device_setup,
+    )?;
+    let pipeline = match args.num_threads {
+        None => pipeline,
+        Some(num_threads) => pipeline.with_num_threads(num_threads),
+    };
+    let pipeline = pipeline.with_vae_autocast(!args.no_vae_autocast);
+    let pipeline = Arc::new(pipeline);
+    println!("Warming up the pipeline.");
+    tch::autocast(args.autocast, || pipeline.warmup())?;
+
+    let workers: Vec<_> = args
+        .prompts
+        .iter()
+        .enumerate()
+        .map(|(idx, prompt)| {
+            let pipeline = pipeline.clone();
+            let prompt = prompt.clone();
+            let sampling_config = stable_diffusion::SamplingConfig {
+                n_steps: args.n_steps,
+                seed: args.seed,
+                ..Default::default()
+            };
+            let autocast = args.autocast;
+            std::thread::spawn(move || -> anyhow::Result<()> {
+                // Autocast is a thread-local setting so it has to be enabled in each worker.
+                let image =
+                    tch::autocast(autocast, || pipeline.txt2img(&prompt, "", &sampling_config))?;
+                let filename = format!("sd_pool.{idx}.png");
+                tch::vision::image::save(&image, &filename)?;
+                println!("Generated {filename} for prompt \"{prompt}\".");
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().expect("worker thread panicked")?;
+    }
+    Ok(())
+}
@@ -57,9 +57,11 @@
 //   model = torch.load("./unet.bin")
 //   save_file(dict(model), './unet.safetensors')
 use clap::Parser;
+use diffusers::pipelines::stable_diffusion::{save_images, NamingOptions};
 use diffusers::pipelines::stable_diffusion_turbo as stable_diffusion;
+use diffusers::schedulers;
 use diffusers::transformers::clip;
-use tch::{nn::Module, Device, Kind, Tensor};
+use tch::{Device, Kind, Tensor};
 
 const GUIDANCE_SCALE: f64 = 7.5;
 
@@ -172,33 +174,6 @@ impl Args {
     }
 }
 
-fn output_filename(
-    basename: &str,
-    sample_idx: i64,
-    num_samples: i64,
-    timestep_idx: Option<usize>,
-) -> String {
-    let filename = if num_samples > 1 {
-        match basename.rsplit_once('.') {
-            None => format!("{basename}.{sample_idx}.png"),
-            Some((filename_no_extension, extension)) => {
-                format!("{filename_no_extension}.{sample_idx}.{extension}")
-            }
-        }
-    } else {
-        basename.to_string()
-    };
-    match timestep_idx {
-        None => filename,
-        Some(timestep_idx) => match filename.rsplit_once('.') {
-            None => format!("{filename}-{timestep_idx}.png"),
-            Some((filename_no_extension, extension)) => {
-                format!("{filename_no_extension}-{timestep_idx}.{extension}")
-            }
-        },
-    }
-}
-
 fn run(args: Args) -> anyhow::Result<()> {
     let clip_weights = args.clip_weights();
     let vae_weights = args.vae_weights();
@@ -235,24 +210,17 @@ fn run(args: Args) -> anyhow::Result<()> {
     let clip_device = device_setup.get("clip");
     let vae_device = device_setup.get("vae");
     let unet_device = device_setup.get("unet");
+    schedulers::check_inference_steps(n_steps, sd_config.scheduler.train_timesteps)?;
     let scheduler = sd_config.build_scheduler(n_steps);
 
     let tokenizer = clip::Tokenizer::create(vocab_file, &sd_config.clip)?;
-    println!("Running with prompt \"{prompt}\".");
-    let tokens = tokenizer.encode(&prompt)?;
-    let tokens: Vec<i64> = tokens.into_iter().map(|x| x as i64).collect();
-    let tokens = Tensor::from_slice(&tokens).view((1, -1)).to(clip_device);
-    let uncond_tokens = tokenizer.encode("")?;
-    let uncond_tokens: Vec<i64> = uncond_tokens.into_iter().map(|x| x as i64).collect();
-    let uncond_tokens = Tensor::from_slice(&uncond_tokens).view((1, -1)).to(clip_device);
-
     let no_grad_guard = tch::no_grad_guard();
 
     println!("Building the Clip transformer.");
     let text_model = sd_config.build_clip_transformer(&clip_weights, clip_device)?;
-    let text_embeddings = text_model.forward(&tokens);
-    let uncond_embeddings = text_model.forward(&uncond_tokens);
-    let text_embeddings = Tensor::cat(&[uncond_embeddings, text_embeddings], 0).to(unet_device);
+    let text_encoder = clip::TextEncoder::new(tokenizer, text_model, clip_device);
+    println!("Running with prompt \"{prompt}\".");
+    let text_embeddings = text_encoder.encode_prompts(&["", &prompt])?.to(unet_device);
 
     println!("Building the autoencoder.");
     let vae = sd_config.build_vae(&vae_weights, vae_device)?;
@@ -287,9 +255,13 @@ fn run(args: Args) -> anyhow::Result<()> {
                 let image = vae.decode(&(&latents / 0.18215));
                 let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
                 let image = (image * 255.).to_kind(Kind::Uint8);
-                let final_image =
-                    output_filename(&final_image, idx + 1, num_samples, Some(timestep_index + 1));
-                tch::vision::image::save(&image, final_image)?;
+                let naming = NamingOptions {
+                    first_index: (idx + 1) as usize,
+                    count: Some(num_samples as usize),
+                    step: Some(timestep_index + 1),
+                    ..Default::default()
+                };
+                save_images(&[image], &final_image, &naming)?;
             }
         }
 
@@ -298,8 +270,12 @@ fn run(args: Args) -> anyhow::Result<()> {
         let image = vae.decode(&(&latents / 0.18215));
         let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
         let image = (image * 255.).to_kind(Kind::Uint8);
-        let final_image = output_filename(&final_image, idx + 1, num_samples, None);
-        tch::vision::image::save(&image, final_image)?;
+        let naming = NamingOptions {
+            first_index: (idx + 1) as usize,
+            count: Some(num_samples as usize),
+            ..Default::default()
+        };
+        save_images(&[image], &final_image, &naming)?;
     }
 
     drop(no_grad_guard);
@@ -57,9 +57,11 @@
 //   model = torch.load("./unet.bin")
 //   save_file(dict(model), './unet.safetensors')
 use clap::Parser;
+use diffusers::models::attention::{AttentionBackend, AttentionSlicing};
 use diffusers::pipelines::stable_diffusion;
+use diffusers::schedulers;
 use diffusers::transformers::clip;
-use tch::{nn::Module, Device, Kind, Tensor};
+use tch::{Device, Kind, Tensor};
 
 const GUIDANCE_SCALE: f64 = 7.5;
 
@@ -74,10 +76,16 @@ struct Args {
     prompt: String,
 
     /// When set, use the CPU for the listed devices, can be 'all', 'unet', 'clip', etc.
-    /// Multiple values can be set.
+    /// Multiple values can be set, 'clip-token-embedding' only keeps the clip token embedding
+    /// table on the CPU.
     #[arg(long)]
     cpu: Vec<String>,
 
+    /// The device used for the models that are not on the CPU, by default MPS when available
+    /// and otherwise cuda when available.
+    #[arg(long, value_enum)]
+    device: Option<DeviceArg>,
+
     /// The height in pixels of the generated image.
     #[arg(long)]
     height: Option<i64>,
@@ -98,6 +106,11 @@ struct Args {
     #[arg(long, value_name = "FILE")]
     vae_weights: Option<String>,
 
+    /// The weight file of the OpenAI consistency decoder, used in place of the VAE decoder when
+    /// set. This only works with the v1.5 and v2.1 latents.
+    #[arg(long, value_name = "FILE")]
+    consistency_decoder_weights: Option<String>,
+
     #[arg(long, value_name = "FILE", default_value = "data/bpe_simple_vocab_16e6.txt")]
     /// The file specifying the vocabulary to used for tokenization.
     vocab_file: String,
@@ -106,6 +119,21 @@ struct Args {
     #[arg(long)]
     sliced_attention_size: Option<i64>,
 
+    /// Automatically slice the attention so that each slice of scores fits in this many
+    /// megabytes, this takes precedence over --sliced-attention-size.
+    #[arg(long)]
+    attention_budget_mb: Option<i64>,
+
+    /// Only slice the attention of this many unet blocks, starting from the highest resolution
+    /// ones where the attention uses most of the memory, rather than of all the blocks.
+    #[arg(long)]
+    sliced_attention_blocks: Option<usize>,
+
+    /// The attention implementation, sdpa uses the fused libtorch kernel and ignores the
+    /// attention slicing settings, auto uses sdpa when supported and standard otherwise.
+    #[arg(long, value_enum, default_value = "standard")]
+    attention_backend: AttentionBackendArg,
+
     /// The number of steps to run the diffusion for.
     #[arg(long, default_value_t = 30)]
     n_steps: usize,
@@ -129,9 +157,28 @@ struct Args {
     #[arg(long, value_enum, default_value = "v2-1")]
     sd_version: StableDiffusionVersion,
 
+    /// The precision used for the vae, bf16 avoids the overflows of f16 but requires an Ampere
+    /// or more recent GPU.
+    #[arg(long, value_enum, default_value = "f32")]
+    vae_dtype: Dtype,
+
+    /// The precision used for the clip text encoder, the embeddings are converted to single
+    /// precision for the unet.
+    #[arg(long, value_enum, default_value = "f32")]
+    clip_dtype: Dtype,
+
     /// Generate intermediary images at each step.
     #[arg(long, action)]
     intermediary_images: bool,
+
+    /// Make the generation bit-reproducible on CUDA devices, this disables cuDNN and is slower.
+    #[arg(long, action)]
+    deterministic: bool,
+
+    /// Also save the final latents of each sample to this file, in .npz, .safetensors, or .ot
+    /// format depending on the extension, e.g. to decode them later with another decoder.
+    #[arg(long, value_name = "FILE")]
+    save_latents: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy, clap::ValueEnum)]
@@ -140,6 +187,47 @@ enum StableDiffusionVersion {
     V2_1,
 }
 
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum DeviceArg {
+    Cpu,
+    Cuda,
+    Mps,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum AttentionBackendArg {
+    Standard,
+    Sdpa,
+    Auto,
+}
+
+impl AttentionBackendArg {
+    fn backend(&self) -> AttentionBackend {
+        match self {
+            Self::Standard => AttentionBackend::Standard,
+            Self::Sdpa => AttentionBackend::ScaledDotProduct,
+            Self::Auto => AttentionBackend::Auto,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Dtype {
+    F32,
+    Bf16,
+    F16,
+}
+
+impl Dtype {
+    fn kind(&self) -> Kind {
+        match self {
+            Self::F32 => Kind::Float,
+            Self::Bf16 => Kind::BFloat16,
+            Self::F16 => Kind::Half,
+        }
+    }
+}
+
 impl Args {
     fn clip_weights(&self) -> String {
         match &self.clip_weights {
@@ -172,33 +260,6 @@ impl Args {
     }
 }
 
-fn output_filename(
-    basename: &str,
-    sample_idx: i64,
-    num_samples: i64,
-    timestep_idx: Option<usize>,
-) -> String {
-    let filename = if num_samples > 1 {
-        match basename.rsplit_once('.') {
-            None => format!("{basename}.{sample_idx}.png"),
-            Some((filename_no_extension, extension)) => {
-                format!("{filename_no_extension}.{sample_idx}.{extension}")
-            }
-        }
-    } else {
-        basename.to_string()
-    };
-    match timestep_idx {
-        None => filename,
-        Some(timestep_idx) => match filename.rsplit_once('.') {
-            None => format!("{filename}-{timestep_idx}.png"),
-            Some((filename_no_extension, extension)) => {
-                format!("{filename_no_extension}-{timestep_idx}.{extension}")
-            }
-        },
-    }
-}
-
 fn run(args: Args) -> anyhow::Result<()> {
     let clip_weights = args.clip_weights();
     let vae_weights = args.vae_weights();
@@ -206,6 +267,7 @@ fn run(args: Args) -> anyhow::Result<()> {
     let Args {
         prompt,
         cpu,
+        device,
         height,
         width,
         n_steps,
@@ -213,6 +275,8 @@ fn run(args: Args) -> anyhow::Result<()> {
         vocab_file,
         final_image,
         sliced_attention_size,
+        attention_budget_mb,
+        attention_backend,
         num_samples,
         sd_version,
         ..
@@ -221,8 +285,11 @@ fn run(args: Args) -> anyhow::Result<()> {
     println!("Cuda available: {}", tch::Cuda::is_available());
     println!("Cudnn available: {}", tch::Cuda::cudnn_is_available());
     println!("MPS available: {}", tch::utils::has_mps());
+    if args.deterministic {
+        diffusers::utils::set_deterministic(true);
+    }
 
-    let sd_config = match sd_version {
+    let mut sd_config = match sd_version {
         StableDiffusionVersion::V1_5 => {
             stable_diffusion::StableDiffusionConfig::v1_5(sliced_attention_size, height, width)
         }
@@ -230,34 +297,66 @@ fn run(args: Args) -> anyhow::Result<()> {
             stable_diffusion::StableDiffusionConfig::v2_1(sliced_attention_size, height, width)
         }
     };
+    if let Some(budget_mb) = attention_budget_mb {
+        sd_config.unet.attention_slicing =
+            AttentionSlicing::Auto { max_bytes: budget_mb * 1024 * 1024 };
+    }
+    if let Some(n_blocks) = args.sliced_attention_blocks {
+        sd_config.unet.block_attention_slicing =
+            Some(vec![sd_config.unet.attention_slicing; n_blocks]);
+    }
+    sd_config.unet.attention_backend = attention_backend.backend();
 
-    let device_setup = diffusers::utils::DeviceSetup::new(cpu);
+    let device_setup = match device {
+        None => diffusers::utils::DeviceSetup::new(cpu),
+        Some(DeviceArg::Cpu) => diffusers::utils::DeviceSetup::with_accelerator(Device::Cpu, cpu)?,
+        Some(DeviceArg::Cuda) => {
+            diffusers::utils::DeviceSetup::with_accelerator(Device::Cuda(0), cpu)?
+        }
+        Some(DeviceArg::Mps) => diffusers::utils::DeviceSetup::with_accelerator(Device::Mps, cpu)?,
+    };
     let clip_device = device_setup.get("clip");
+    let token_embedding_device = match device_setup.get("clip-token-embedding") {
+        Device::Cpu => Device::Cpu,
+        _ => clip_device,
+    };
     let vae_device = device_setup.get("vae");
     let unet_device = device_setup.get("unet");
+    schedulers::check_inference_steps(n_steps, sd_config.scheduler.train_timesteps)?;
     let scheduler = sd_config.build_scheduler(n_steps);
 
     let tokenizer = clip::Tokenizer::create(vocab_file, &sd_config.clip)?;
-    println!("Running with prompt \"{prompt}\".");
-    let tokens = tokenizer.encode(&prompt)?;
-    let tokens: Vec<i64> = tokens.into_iter().map(|x| x as i64).collect();
-    let tokens = Tensor::from_slice(&tokens).view((1, -1)).to(clip_device);
-    let uncond_tokens = tokenizer.encode("")?;
-    let uncond_tokens: Vec<i64> = uncond_tokens.into_iter().map(|x| x as i64).collect();
-    let uncond_tokens = Tensor::from_slice(&uncond_tokens).view((1, -1)).to(clip_device);
-
     let no_grad_guard = tch::no_grad_guard();
 
     println!("Building the Clip transformer.");
-    let text_model = sd_config.build_clip_transformer(&clip_weights, clip_device)?;
-    let text_embeddings = text_model.forward(&tokens);
-    let uncond_embeddings = text_model.forward(&uncond_tokens);
-    let text_embeddings = Tensor::cat(&[uncond_embeddings, text_embeddings], 0).to(unet_device);
+    let text_model = sd_config.build_clip_transformer_with_token_embedding(
+        &clip_weights,
+        clip_device,
+        token_embedding_device,
+        args.clip_dtype.kind(),
+    )?;
+    let text_encoder = clip::TextEncoder::new(tokenizer, text_model, clip_device);
+    println!("Running with prompt \"{prompt}\".");
+    // The unconditional embeddings come first, see `stable_diffusion::CfgOrder`.
+    let text_embeddings =
+        text_encoder.encode_prompts(&["", &prompt])?.to_device(unet_device).to_kind(Kind::Float);
 
     println!("Building the autoencoder.");
-    let vae = sd_config.build_vae(&vae_weights, vae_device)?;
+    let vae = sd_config.build_vae_with_kind(&vae_weights, vae_device, args.vae_dtype.kind())?;
+    let consistency_decoder = match &args.consistency_decoder_weights {
+        Some(weights) => {
+            println!("Building the consistency decoder.");
+            Some(sd_config.build_consistency_decoder(weights, vae_device, args.vae_dtype.kind())?)
+        }
+        None => None,
+    };
+    let decode = |latents: &Tensor| match &consistency_decoder {
+        Some(decoder) => decoder.decode(&(latents / 0.18215)),
+        None => vae.decode(&(latents / 0.18215)),
+    };
     println!("Building the unet.");
     let unet = sd_config.build_unet(&unet_weights, unet_device, 4)?;
+    let model_hash = diffusers::models::weight_hash(&unet_weights)?;
 
     let bsize = 1;
     for idx in 0..num_samples {
@@ -280,26 +379,51 @@ fn run(args: Args) -> anyhow::Result<()> {
             let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
             let noise_pred =
                 noise_pred_uncond + (noise_pred_text - noise_pred_uncond) * GUIDANCE_SCALE;
-            latents = scheduler.step(&noise_pred, timestep, &latents);
+            latents = scheduler.step_by_index(&noise_pred, timestep_index, &latents);
 
             if args.intermediary_images {
-                let latents = latents.to(vae_device);
-                let image = vae.decode(&(&latents / 0.18215));
+                let latents = latents.to(vae_device).to_kind(vae.kind());
+                let image = decode(&latents).to_kind(Kind::Float);
                 let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
                 let image = (image * 255.).to_kind(Kind::Uint8);
-                let final_image =
-                    output_filename(&final_image, idx + 1, num_samples, Some(timestep_index + 1));
-                tch::vision::image::save(&image, final_image)?;
+                let naming = stable_diffusion::NamingOptions {
+                    first_index: (idx + 1) as usize,
+                    count: Some(num_samples as usize),
+                    step: Some(timestep_index + 1),
+                    ..Default::default()
+                };
+                stable_diffusion::save_images(&[image], &final_image, &naming)?;
             }
         }
 
+        if let Some(save_latents) = &args.save_latents {
+            let naming = stable_diffusion::NamingOptions::default();
+            let filename = naming.filename(save_latents, (idx + 1) as usize, num_samples as usize);
+            stable_diffusion::save_latents(&latents, filename)?;
+        }
         println!("Generating the final image for sample {}/{}.", idx + 1, num_samples);
-        let latents = latents.to(vae_device);
-        let image = vae.decode(&(&latents / 0.18215));
+        let latents = latents.to(vae_device).to_kind(vae.kind());
+        let image = decode(&latents).to_kind(Kind::Float);
         let image = (image / 2 + 0.5).clamp(0., 1.).to_device(Device::Cpu);
         let image = (image * 255.).to_kind(Kind::Uint8);
-        let final_image = output_filename(&final_image, idx + 1, num_samples, None);
-        tch::vision::image::save(&image, final_image)?;
+        let parameters = diffusers::utils::GenerationParameters {
+            prompt: prompt.clone(),
+            negative_prompt: String::new(),
+            seed: seed + idx,
+            steps: n_steps,
+            guidance_scale: GUIDANCE_SCALE,
+            scheduler: "DDIM".to_string(),
+            width: sd_config.width,
+            height: sd_config.height,
+            model_hash: Some(model_hash.clone()),
+        };
+        let naming = stable_diffusion::NamingOptions {
+            first_index: (idx + 1) as usize,
+            count: Some(num_samples as usize),
+            metadata: vec![parameters.to_string()],
+            ..Default::default()
+        };
+        stable_diffusion::save_images(&[image], &final_image, &naming)?;
     }
 
     drop(no_grad_guard);
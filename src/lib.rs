@@ -9,9 +9,13 @@
 //!
 //! The models can used pre-trained weights adapted from the Python
 //! implementation.
+//!
+//! The library does not print anything, the model loading and the denoising progress are
+//! reported through the [log](https://docs.rs/log) facade at the info and debug levels.
 
 pub mod models;
 pub mod pipelines;
+pub mod preprocess;
 pub mod schedulers;
 pub mod transformers;
 pub mod utils;
@@ -1,4 +1,5 @@
 //! Attention Based Building Blocks
+use std::sync::Arc;
 use tch::{nn, nn::Module, IndexOp, Kind, Tensor};
 #[derive(Debug)]
 struct GeGlu {
@@ -47,15 +48,163 @@ impl Module for FeedForward {
     }
 }
 
+/// How the attention score matrices are split to bound memory usage.
+///
+/// The slices are taken over the batch times heads dimension, a slice size of `n` meaning that
+/// the scores for `n` heads are materialized at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttentionSlicing {
+    /// The scores for all the heads are computed in one go.
+    #[default]
+    Disabled,
+    /// Process a fixed number of heads per slice.
+    SliceSize(i64),
+    /// Pick the slice size from the sequence lengths so that the score matrix of each slice
+    /// stays under `max_bytes`, larger resolutions resulting in more slices.
+    Auto { max_bytes: i64 },
+}
+
+impl AttentionSlicing {
+    /// The default memory budget for `Auto`, 512MB per score matrix slice.
+    pub const DEFAULT_MAX_BYTES: i64 = 512 * 1024 * 1024;
+
+    /// Automatic slicing using the default memory budget.
+    pub fn auto() -> Self {
+        Self::Auto { max_bytes: Self::DEFAULT_MAX_BYTES }
+    }
+
+    /// Returns the number of heads to process per slice for the given attention shapes, `None`
+    /// meaning that no slicing is needed.
+    pub fn slice_size(&self, batch_heads: i64, query_len: i64, key_len: i64) -> Option<i64> {
+        let slice_size = match *self {
+            Self::Disabled => return None,
+            Self::SliceSize(slice_size) => slice_size,
+            Self::Auto { max_bytes } => {
+                // The softmax is always computed in single precision.
+                let bytes_per_head = query_len * key_len * Kind::Float.elt_size_in_bytes() as i64;
+                i64::max(max_bytes / bytes_per_head, 1)
+            }
+        };
+        (slice_size < batch_heads).then_some(slice_size)
+    }
+}
+
+impl From<Option<i64>> for AttentionSlicing {
+    fn from(slice_size: Option<i64>) -> Self {
+        match slice_size {
+            None => Self::Disabled,
+            Some(slice_size) => Self::SliceSize(slice_size),
+        }
+    }
+}
+
+/// The implementation used to compute the attention scores of a `CrossAttention` layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttentionBackend {
+    /// The matmul and softmax based implementation, sliced according to the layer
+    /// `AttentionSlicing` setting.
+    #[default]
+    Standard,
+    /// The fused `scaled_dot_product_attention` kernel from libtorch, this ignores the slicing
+    /// setting as the memory efficient kernels do not materialize the score matrix.
+    ScaledDotProduct,
+    /// Uses `ScaledDotProduct` when libtorch supports it for the inputs, e.g. the device and
+    /// kind in use, and falls back to `Standard` otherwise. Once libtorch has reported that no
+    /// fused kernel is available for a device and kind, the standard implementation is used
+    /// for these for the rest of the process. Other errors of the kernel result in a panic, as
+    /// with `ScaledDotProduct`.
+    Auto,
+}
+
+/// The devices and kinds for which the fused attention kernel is not available, as found by
+/// `AttentionBackend::Auto`.
+static SCALED_DOT_PRODUCT_UNAVAILABLE: std::sync::Mutex<Vec<(tch::Device, Kind)>> =
+    std::sync::Mutex::new(Vec::new());
+
+/// Whether an error of the fused attention kernel means that it does not support the inputs,
+/// rather than e.g. running out of memory.
+fn is_kernel_unavailable(err: &tch::TchError) -> bool {
+    let err = err.to_string();
+    ["No available kernel", "not implemented", "not currently implemented"]
+        .iter()
+        .any(|pattern| err.contains(pattern))
+}
+
+/// Computes the output of a `CrossAttention` layer, the default being
+/// `DefaultAttentionProcessor`. Custom processors can be installed on some or all the attention
+/// layers of a model, e.g. via `UNet2DConditionModel::set_attention_processor`, to modify the
+/// queries, keys and values or to inject some additional cross-attention without having to
+/// fork the model code.
+pub trait AttentionProcessor: std::fmt::Debug + Send + Sync {
+    /// Returns the attention output for the hidden states `xs`, `context` is the
+    /// `encoder_hidden_states` for cross-attention layers and `None` for self-attention ones.
+    fn forward(&self, attn: &CrossAttention, xs: &Tensor, context: Option<&Tensor>) -> Tensor;
+}
+
+/// The standard scaled dot-product attention, using the slicing setting of the layer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultAttentionProcessor;
+
+impl AttentionProcessor for DefaultAttentionProcessor {
+    fn forward(&self, attn: &CrossAttention, xs: &Tensor, context: Option<&Tensor>) -> Tensor {
+        let context = context.unwrap_or(xs);
+        let query = attn.reshape_heads_to_batch_dim(&xs.apply(&attn.to_q));
+        let key = attn.reshape_heads_to_batch_dim(&context.apply(&attn.to_k));
+        let value = attn.reshape_heads_to_batch_dim(&context.apply(&attn.to_v));
+        attn.attention(&query, &key, &value).apply(&attn.to_out)
+    }
+}
+
+thread_local! {
+    static PERTURB_SELF_ATTENTION: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Runs `f` with the self-attention of the layers using a `PerturbedAttentionProcessor`
+/// replaced by an identity attention map. This is a thread-local setting, similar to
+/// `tch::no_grad`, so that the same model can be used for the regular and perturbed forward
+/// passes.
+pub fn with_perturbed_attention<T, F: FnOnce() -> T>(f: F) -> T {
+    // Restores the previous setting on drop, so that a panic in `f` caught by the caller does
+    // not leave the thread with the perturbed attention enabled.
+    struct Guard(bool);
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            PERTURB_SELF_ATTENTION.with(|p| p.set(self.0))
+        }
+    }
+    let _guard = Guard(PERTURB_SELF_ATTENTION.with(|p| p.replace(true)));
+    f()
+}
+
+/// The processor used for Perturbed-Attention Guidance (PAG). Inside
+/// `with_perturbed_attention`, self-attention layers return the projected values as if each
+/// token only attended to itself, otherwise this delegates to the wrapped processor.
 #[derive(Debug)]
-struct CrossAttention {
-    to_q: nn::Linear,
-    to_k: nn::Linear,
-    to_v: nn::Linear,
-    to_out: nn::Linear,
-    heads: i64,
-    scale: f64,
-    slice_size: Option<i64>,
+pub struct PerturbedAttentionProcessor {
+    pub inner: Arc<dyn AttentionProcessor>,
+}
+
+impl AttentionProcessor for PerturbedAttentionProcessor {
+    fn forward(&self, attn: &CrossAttention, xs: &Tensor, context: Option<&Tensor>) -> Tensor {
+        if context.is_none() && PERTURB_SELF_ATTENTION.with(|p| p.get()) {
+            xs.apply(&attn.to_v).apply(&attn.to_out)
+        } else {
+            self.inner.forward(attn, xs, context)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CrossAttention {
+    pub to_q: nn::Linear,
+    pub to_k: nn::Linear,
+    pub to_v: nn::Linear,
+    pub to_out: nn::Linear,
+    pub heads: i64,
+    pub scale: f64,
+    pub slicing: AttentionSlicing,
+    pub backend: AttentionBackend,
+    processor: Arc<dyn AttentionProcessor>,
 }
 
 impl CrossAttention {
@@ -66,7 +215,7 @@ impl CrossAttention {
         context_dim: Option<i64>,
         heads: i64,
         dim_head: i64,
-        slice_size: Option<i64>,
+        slicing: AttentionSlicing,
     ) -> Self {
         let no_bias = nn::LinearConfig { bias: false, ..Default::default() };
         let inner_dim = dim_head * heads;
@@ -76,17 +225,42 @@ impl CrossAttention {
         let to_k = nn::linear(&vs / "to_k", context_dim, inner_dim, no_bias);
         let to_v = nn::linear(&vs / "to_v", context_dim, inner_dim, no_bias);
         let to_out = nn::linear(&vs / "to_out" / 0, inner_dim, query_dim, Default::default());
-        Self { to_q, to_k, to_v, to_out, heads, scale, slice_size }
+        let processor = Arc::new(DefaultAttentionProcessor);
+        let backend = AttentionBackend::Standard;
+        Self { to_q, to_k, to_v, to_out, heads, scale, slicing, backend, processor }
+    }
+
+    pub fn processor(&self) -> &Arc<dyn AttentionProcessor> {
+        &self.processor
+    }
+
+    pub fn set_processor(&mut self, processor: Arc<dyn AttentionProcessor>) {
+        self.processor = processor
     }
 
-    fn reshape_heads_to_batch_dim(&self, xs: &Tensor) -> Tensor {
+    /// The standard softmax scale of the layer, `1 / sqrt(dim_head)`.
+    pub fn default_scale(&self) -> f64 {
+        let dim_head = self.to_q.ws.size()[0] / self.heads;
+        1.0 / f64::sqrt(dim_head as f64)
+    }
+
+    /// Replaces the softmax scale of the layer, `None` restoring the standard one. Larger
+    /// values sharpen the attention maps and smaller ones flatten them.
+    pub fn set_scale_override(&mut self, scale: Option<f64>) {
+        self.scale = scale.unwrap_or_else(|| self.default_scale())
+    }
+
+    /// Converts a `(batch, seq_len, heads * dim_head)` tensor to
+    /// `(batch * heads, seq_len, dim_head)`.
+    pub fn reshape_heads_to_batch_dim(&self, xs: &Tensor) -> Tensor {
         let (batch_size, seq_len, dim) = xs.size3().unwrap();
         xs.reshape([batch_size, seq_len, self.heads, dim / self.heads])
             .permute([0, 2, 1, 3])
             .reshape([batch_size * self.heads, seq_len, dim / self.heads])
     }
 
-    fn reshape_batch_dim_to_heads(&self, xs: &Tensor) -> Tensor {
+    /// The inverse of `reshape_heads_to_batch_dim`.
+    pub fn reshape_batch_dim_to_heads(&self, xs: &Tensor) -> Tensor {
         let (batch_size, seq_len, dim) = xs.size3().unwrap();
         xs.reshape([batch_size / self.heads, self.heads, seq_len, dim])
             .permute([0, 2, 1, 3])
@@ -98,19 +272,17 @@ impl CrossAttention {
         query: &Tensor,
         key: &Tensor,
         value: &Tensor,
-        sequence_length: i64,
-        dim: i64,
         slice_size: i64,
     ) -> Tensor {
-        let batch_size_attention = query.size()[0];
+        let (batch_size_attention, sequence_length, _) = query.size3().unwrap();
+        let dim_head = value.size()[2];
         let mut hidden_states = Tensor::zeros(
-            [batch_size_attention, sequence_length, dim / self.heads],
+            [batch_size_attention, sequence_length, dim_head],
             (query.kind(), query.device()),
         );
 
-        for i in 0..batch_size_attention / slice_size {
-            let start_idx = i * slice_size;
-            let end_idx = (i + 1) * slice_size;
+        for start_idx in (0..batch_size_attention).step_by(slice_size as usize) {
+            let end_idx = i64::min(start_idx + slice_size, batch_size_attention);
 
             let xs = query
                 .i(start_idx..end_idx)
@@ -125,35 +297,76 @@ impl CrossAttention {
         self.reshape_batch_dim_to_heads(&hidden_states)
     }
 
-    fn attention(&self, query: &Tensor, key: &Tensor, value: &Tensor) -> Tensor {
-        let xs = query
-            .matmul(&(key.transpose(-1, -2) * self.scale))
-            .softmax(-1, Kind::Float)
-            .matmul(value);
-        self.reshape_batch_dim_to_heads(&xs)
-    }
-
-    fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Tensor {
-        let sequence_length = xs.size()[1];
-        let query = xs.apply(&self.to_q);
-        let dim = *query.size().last().unwrap();
-        let context = context.unwrap_or(xs);
-        let key = context.apply(&self.to_k);
-        let value = context.apply(&self.to_v);
-        let query = self.reshape_heads_to_batch_dim(&query);
-        let key = self.reshape_heads_to_batch_dim(&key);
-        let value = self.reshape_heads_to_batch_dim(&value);
-        match self.slice_size {
-            None => self.attention(&query, &key, &value).apply(&self.to_out),
-            Some(slice_size) => {
-                if query.size()[0] / slice_size <= 1 {
-                    self.attention(&query, &key, &value).apply(&self.to_out)
-                } else {
-                    self.sliced_attention(&query, &key, &value, sequence_length, dim, slice_size)
-                        .apply(&self.to_out)
+    /// Computes the attention for queries, keys and values of shape
+    /// `(batch * heads, seq_len, dim_head)` using the backend and slicing setting of the layer,
+    /// and returns the result with shape `(batch, seq_len, heads * dim_head)` before the output
+    /// projection.
+    pub fn attention(&self, query: &Tensor, key: &Tensor, value: &Tensor) -> Tensor {
+        // The kernel always uses a 1/sqrt(dim_head) scale, the queries are rescaled when the
+        // scale of the layer has been overridden.
+        let kernel_query = || {
+            let default_scale = self.default_scale();
+            if self.scale == default_scale {
+                query.shallow_clone()
+            } else {
+                query * (self.scale / default_scale)
+            }
+        };
+        match self.backend {
+            AttentionBackend::Standard => {}
+            AttentionBackend::ScaledDotProduct => {
+                let xs = Tensor::scaled_dot_product_attention(
+                    &kernel_query(),
+                    key,
+                    value,
+                    None::<Tensor>,
+                    0.,
+                    false,
+                );
+                return self.reshape_batch_dim_to_heads(&xs);
+            }
+            AttentionBackend::Auto => {
+                let inputs = (query.device(), query.kind());
+                let unavailable =
+                    || SCALED_DOT_PRODUCT_UNAVAILABLE.lock().unwrap_or_else(|e| e.into_inner());
+                if !unavailable().contains(&inputs) {
+                    match Tensor::f_scaled_dot_product_attention(
+                        &kernel_query(),
+                        key,
+                        value,
+                        None::<Tensor>,
+                        0.,
+                        false,
+                    ) {
+                        Ok(xs) => return self.reshape_batch_dim_to_heads(&xs),
+                        Err(err) if is_kernel_unavailable(&err) => {
+                            log::info!(
+                                "no fused attention kernel for {inputs:?}, using the standard \
+                                 attention: {err}"
+                            );
+                            unavailable().push(inputs)
+                        }
+                        Err(err) => panic!("scaled dot product attention failed: {err}"),
+                    }
                 }
             }
         }
+        let (batch_size_attention, sequence_length, _) = query.size3().unwrap();
+        let key_length = key.size()[1];
+        match self.slicing.slice_size(batch_size_attention, sequence_length, key_length) {
+            None => {
+                let xs = query
+                    .matmul(&(key.transpose(-1, -2) * self.scale))
+                    .softmax(-1, Kind::Float)
+                    .matmul(value);
+                self.reshape_batch_dim_to_heads(&xs)
+            }
+            Some(slice_size) => self.sliced_attention(query, key, value, slice_size),
+        }
+    }
+
+    pub fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Tensor {
+        self.processor.forward(self, xs, context)
     }
 }
 
@@ -175,10 +388,10 @@ impl BasicTransformerBlock {
         n_heads: i64,
         d_head: i64,
         context_dim: Option<i64>,
-        sliced_attention_size: Option<i64>,
+        attention_slicing: AttentionSlicing,
     ) -> Self {
         let attn1 =
-            CrossAttention::new(&vs / "attn1", dim, None, n_heads, d_head, sliced_attention_size);
+            CrossAttention::new(&vs / "attn1", dim, None, n_heads, d_head, attention_slicing);
         let ff = FeedForward::new(&vs / "ff", dim, None, 4);
         let attn2 = CrossAttention::new(
             &vs / "attn2",
@@ -186,7 +399,7 @@ impl BasicTransformerBlock {
             context_dim,
             n_heads,
             d_head,
-            sliced_attention_size,
+            attention_slicing,
         );
         let norm1 = nn::layer_norm(&vs / "norm1", vec![dim], Default::default());
         let norm2 = nn::layer_norm(&vs / "norm2", vec![dim], Default::default());
@@ -194,6 +407,15 @@ impl BasicTransformerBlock {
         Self { attn1, ff, attn2, norm1, norm2, norm3 }
     }
 
+    fn for_each_attention_mut(
+        &mut self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &mut CrossAttention),
+    ) {
+        f(&format!("{prefix}.attn1"), &mut self.attn1);
+        f(&format!("{prefix}.attn2"), &mut self.attn2);
+    }
+
     fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Tensor {
         let xs = self.attn1.forward(&xs.apply(&self.norm1), None) + xs;
         let xs = self.attn2.forward(&xs.apply(&self.norm2), context) + xs;
@@ -206,7 +428,7 @@ pub struct SpatialTransformerConfig {
     pub depth: i64,
     pub num_groups: i64,
     pub context_dim: Option<i64>,
-    pub sliced_attention_size: Option<i64>,
+    pub attention_slicing: AttentionSlicing,
     pub use_linear_projection: bool,
 }
 
@@ -216,12 +438,26 @@ impl Default for SpatialTransformerConfig {
             depth: 1,
             num_groups: 32,
             context_dim: None,
-            sliced_attention_size: None,
+            attention_slicing: AttentionSlicing::Disabled,
             use_linear_projection: false,
         }
     }
 }
 
+/// The number of parameters of a transformer with a single block, as used by the stable
+/// diffusion unets, for the given channels and cross-attention dimension.
+pub(crate) fn spatial_transformer_num_parameters(channels: i64, context_dim: i64) -> i64 {
+    let c = channels;
+    // The query, key, value, and output projections, only the output one having a bias.
+    let attn1 = 4 * c * c + c;
+    let attn2 = 2 * c * c + 2 * context_dim * c + c;
+    // The GEGLU projection to twice the inner dimension followed by the output projection.
+    let ff = c * 8 * c + 8 * c + 4 * c * c + c;
+    let norms = 2 * c + 3 * 2 * c;
+    let projs = 2 * (c * c + c);
+    attn1 + attn2 + ff + norms + projs
+}
+
 #[derive(Debug)]
 enum Proj {
     Conv2D(nn::Conv2D),
@@ -264,7 +500,7 @@ impl SpatialTransformer {
                 n_heads,
                 d_head,
                 config.context_dim,
-                config.sliced_attention_size,
+                config.attention_slicing,
             );
             transformer_blocks.push(tb)
         }
@@ -276,6 +512,19 @@ impl SpatialTransformer {
         Self { norm, proj_in, transformer_blocks, proj_out, config }
     }
 
+    /// Calls `f` on each attention layer, together with its name relative to `prefix` using the
+    /// python diffusers naming, e.g. `{prefix}.transformer_blocks.0.attn2` for the first
+    /// cross-attention layer.
+    pub fn for_each_attention_mut(
+        &mut self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &mut CrossAttention),
+    ) {
+        for (index, block) in self.transformer_blocks.iter_mut().enumerate() {
+            block.for_each_attention_mut(&format!("{prefix}.transformer_blocks.{index}"), f)
+        }
+    }
+
     pub fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Tensor {
         let (batch, _channel, height, weight) = xs.size4().unwrap();
         let residual = xs;
@@ -309,6 +558,16 @@ impl SpatialTransformer {
     }
 }
 
+/// The names of the `AttentionBlock` projection weights.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AttentionBlockWeightNames {
+    /// `query`, `key`, `value`, and `proj_attn`, as in the stable diffusion vae weights.
+    #[default]
+    Legacy,
+    /// `to_q`, `to_k`, `to_v`, and `to_out.0`, as saved by recent versions of python diffusers.
+    Diffusers,
+}
+
 /// Configuration for an attention block.
 #[derive(Debug, Clone, Copy)]
 pub struct AttentionBlockConfig {
@@ -316,11 +575,18 @@ pub struct AttentionBlockConfig {
     pub num_groups: i64,
     pub rescale_output_factor: f64,
     pub eps: f64,
+    pub weight_names: AttentionBlockWeightNames,
 }
 
 impl Default for AttentionBlockConfig {
     fn default() -> Self {
-        Self { num_head_channels: None, num_groups: 32, rescale_output_factor: 1., eps: 1e-5 }
+        Self {
+            num_head_channels: None,
+            num_groups: 32,
+            rescale_output_factor: 1.,
+            eps: 1e-5,
+            weight_names: AttentionBlockWeightNames::Legacy,
+        }
     }
 }
 
@@ -342,10 +608,18 @@ impl AttentionBlock {
         let num_heads = channels / num_head_channels;
         let group_cfg = nn::GroupNormConfig { eps: config.eps, affine: true, ..Default::default() };
         let group_norm = nn::group_norm(&vs / "group_norm", config.num_groups, channels, group_cfg);
-        let query = nn::linear(&vs / "query", channels, channels, Default::default());
-        let key = nn::linear(&vs / "key", channels, channels, Default::default());
-        let value = nn::linear(&vs / "value", channels, channels, Default::default());
-        let proj_attn = nn::linear(&vs / "proj_attn", channels, channels, Default::default());
+        let (vs_query, vs_key, vs_value, vs_proj_attn) = match config.weight_names {
+            AttentionBlockWeightNames::Legacy => {
+                (&vs / "query", &vs / "key", &vs / "value", &vs / "proj_attn")
+            }
+            AttentionBlockWeightNames::Diffusers => {
+                (&vs / "to_q", &vs / "to_k", &vs / "to_v", &vs / "to_out" / 0)
+            }
+        };
+        let query = nn::linear(vs_query, channels, channels, Default::default());
+        let key = nn::linear(vs_key, channels, channels, Default::default());
+        let value = nn::linear(vs_value, channels, channels, Default::default());
+        let proj_attn = nn::linear(vs_proj_attn, channels, channels, Default::default());
         Self { group_norm, query, key, value, proj_attn, channels, num_heads, config }
     }
 
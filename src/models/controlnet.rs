@@ -1,5 +1,6 @@
 // https://github.com/huggingface/diffusers/blob/main/src/diffusers/models/controlnet.py
 use super::unet_2d::{BlockConfig, UNetDownBlock};
+use crate::models::attention::AttentionSlicing;
 use crate::models::embeddings::{TimestepEmbedding, Timesteps};
 use crate::models::unet_2d_blocks::*;
 use tch::{nn, nn::Module, Kind, Tensor};
@@ -148,7 +149,7 @@ impl ControlNet {
                         downblock: db_cfg,
                         attn_num_head_channels: attention_head_dim,
                         cross_attention_dim: config.cross_attention_dim,
-                        sliced_attention_size: None,
+                        attention_slicing: AttentionSlicing::Disabled,
                         use_linear_projection: config.use_linear_projection,
                     };
                     let block = CrossAttnDownBlock2D::new(
@@ -9,3 +9,23 @@ pub mod resnet;
 pub mod unet_2d;
 pub mod unet_2d_blocks;
 pub mod vae;
+
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+/// Returns a short hash of a weight file: the first 10 hexadecimal characters of the sha256 of
+/// the whole file, as displayed by the AUTOMATIC1111 web UI.
+pub fn weight_hash<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<String> {
+    let mut file = crate::utils::file_open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    let hash: String = hasher.finalize().iter().map(|b| format!("{b:02x}")).collect();
+    Ok(hash[..10].to_string())
+}
@@ -6,6 +6,27 @@
 //! https://arxiv.org/abs/1512.03385
 use tch::{nn, Tensor};
 
+/// How the time embedding is combined with the hidden states.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeEmbeddingNorm {
+    /// The projected embedding is added before the second group normalization.
+    #[default]
+    Default,
+    /// The projected embedding is split in a scale and a shift applied after the second group
+    /// normalization, this is the `scale_shift` mode of diffusers.
+    ScaleShift,
+}
+
+/// Resampling applied by a block after the first normalization, on both the hidden states and
+/// the skip connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resample {
+    /// Nearest neighbor upsampling by a factor 2.
+    Up,
+    /// Average pooling by a factor 2.
+    Down,
+}
+
 /// Configuration for a ResNet block.
 #[derive(Debug, Clone, Copy)]
 pub struct ResnetBlock2DConfig {
@@ -24,6 +45,9 @@ pub struct ResnetBlock2DConfig {
     // non_linearity: silu
     /// The final output is scaled by dividing by this value.
     pub output_scale_factor: f64,
+    pub time_embedding_norm: TimeEmbeddingNorm,
+    /// Resample the input, the output then has a different spatial size.
+    pub resample: Option<Resample>,
 }
 
 impl Default for ResnetBlock2DConfig {
@@ -36,10 +60,26 @@ impl Default for ResnetBlock2DConfig {
             eps: 1e-6,
             use_in_shortcut: None,
             output_scale_factor: 1.,
+            time_embedding_norm: TimeEmbeddingNorm::Default,
+            resample: None,
         }
     }
 }
 
+/// The number of parameters of a block with the default config, the shortcut convolution being
+/// used when the input and output channels differ.
+pub(crate) fn num_parameters(
+    in_channels: i64,
+    out_channels: i64,
+    temb_channels: Option<i64>,
+) -> i64 {
+    let norms = 2 * in_channels + 2 * out_channels;
+    let convs = 9 * in_channels * out_channels + 9 * out_channels * out_channels + 2 * out_channels;
+    let time_emb_proj = temb_channels.map_or(0, |temb_channels| (temb_channels + 1) * out_channels);
+    let shortcut = if in_channels != out_channels { (in_channels + 1) * out_channels } else { 0 };
+    norms + convs + time_emb_proj + shortcut
+}
+
 #[derive(Debug)]
 pub struct ResnetBlock2D {
     norm1: nn::GroupNorm,
@@ -68,25 +108,49 @@ impl ResnetBlock2D {
         } else {
             None
         };
+        let temb_out_channels = match config.time_embedding_norm {
+            TimeEmbeddingNorm::Default => out_channels,
+            TimeEmbeddingNorm::ScaleShift => 2 * out_channels,
+        };
         let time_emb_proj = config.temb_channels.map(|temb_channels| {
-            nn::linear(&vs / "time_emb_proj", temb_channels, out_channels, Default::default())
+            nn::linear(&vs / "time_emb_proj", temb_channels, temb_out_channels, Default::default())
         });
         Self { norm1, conv1, norm2, conv2, time_emb_proj, config, conv_shortcut }
     }
 
+    fn resample(&self, xs: &Tensor) -> Tensor {
+        match self.config.resample {
+            None => xs.shallow_clone(),
+            Some(Resample::Up) => {
+                let (_, _, h, w) = xs.size4().unwrap();
+                xs.upsample_nearest2d([2 * h, 2 * w], Some(2.), Some(2.))
+            }
+            Some(Resample::Down) => xs.avg_pool2d([2, 2], [2, 2], [0, 0], false, true, None),
+        }
+    }
+
     pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>) -> Tensor {
+        let shortcut_xs = self.resample(xs);
         let shortcut_xs = match &self.conv_shortcut {
-            Some(conv_shortcut) => xs.apply(conv_shortcut),
-            None => xs.shallow_clone(),
+            Some(conv_shortcut) => shortcut_xs.apply(conv_shortcut),
+            None => shortcut_xs,
         };
-        let xs = xs.apply(&self.norm1).silu().apply(&self.conv1);
-        let xs = match (temb, &self.time_emb_proj) {
+        let xs = self.resample(&xs.apply(&self.norm1).silu()).apply(&self.conv1);
+        let temb = match (temb, &self.time_emb_proj) {
             (Some(temb), Some(time_emb_proj)) => {
-                temb.silu().apply(time_emb_proj).unsqueeze(-1).unsqueeze(-1) + xs
+                Some(temb.silu().apply(time_emb_proj).unsqueeze(-1).unsqueeze(-1))
+            }
+            _ => None,
+        };
+        let xs = match (temb, self.config.time_embedding_norm) {
+            (Some(temb), TimeEmbeddingNorm::Default) => (temb + xs).apply(&self.norm2),
+            (Some(temb), TimeEmbeddingNorm::ScaleShift) => {
+                let scale_shift = temb.chunk(2, 1);
+                xs.apply(&self.norm2) * (1 + &scale_shift[0]) + &scale_shift[1]
             }
-            _ => xs,
+            (None, _) => xs.apply(&self.norm2),
         };
-        let xs = xs.apply(&self.norm2).silu().apply(&self.conv2);
+        let xs = xs.silu().apply(&self.conv2);
         (shortcut_xs + xs) / self.config.output_scale_factor
     }
 }
@@ -2,8 +2,13 @@
 //!
 //! The 2D Unet models take as input a noisy sample and the current diffusion
 //! timestep and return a denoised version of the input.
+use crate::models::attention::{
+    AttentionBackend, AttentionProcessor, AttentionSlicing, CrossAttention,
+    PerturbedAttentionProcessor,
+};
 use crate::models::embeddings::{TimestepEmbedding, Timesteps};
 use crate::models::unet_2d_blocks::*;
+use std::sync::Arc;
 use tch::{nn, Kind, Tensor};
 
 #[derive(Debug, Clone, Copy)]
@@ -25,8 +30,17 @@ pub struct UNet2DConditionModelConfig {
     pub norm_num_groups: i64,
     pub norm_eps: f64,
     pub cross_attention_dim: i64,
-    pub sliced_attention_size: Option<i64>,
+    pub attention_slicing: AttentionSlicing,
+    /// The slicing of each block, from the highest resolution one, overriding
+    /// `attention_slicing`. The down and up blocks at the same resolution share a setting and
+    /// the blocks past the end of the list are not sliced, e.g. `vec![slicing]` only slices the
+    /// highest resolution blocks where the attention scores use most of the memory.
+    pub block_attention_slicing: Option<Vec<AttentionSlicing>>,
+    pub attention_backend: AttentionBackend,
     pub use_linear_projection: bool,
+    /// When set, the softmax scale used by all the attention layers instead of the standard
+    /// `1 / sqrt(dim_head)`, e.g. to experiment with the attention temperature.
+    pub attention_scale_override: Option<f64>,
 }
 
 impl Default for UNet2DConditionModelConfig {
@@ -47,12 +61,118 @@ impl Default for UNet2DConditionModelConfig {
             norm_num_groups: 32,
             norm_eps: 1e-5,
             cross_attention_dim: 1280,
-            sliced_attention_size: None,
+            attention_slicing: AttentionSlicing::Disabled,
+            block_attention_slicing: None,
+            attention_backend: AttentionBackend::Standard,
             use_linear_projection: false,
+            attention_scale_override: None,
         }
     }
 }
 
+impl UNet2DConditionModelConfig {
+    // https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/unet/config.json
+    pub fn v1_5() -> Self {
+        let bc = |out_channels, use_cross_attn| BlockConfig {
+            out_channels,
+            use_cross_attn,
+            attention_head_dim: 8,
+        };
+        Self {
+            blocks: vec![bc(320, true), bc(640, true), bc(1280, true), bc(1280, false)],
+            cross_attention_dim: 768,
+            ..Default::default()
+        }
+    }
+
+    // https://huggingface.co/stabilityai/stable-diffusion-2-1/blob/main/unet/config.json
+    pub fn v2_1() -> Self {
+        let bc = |out_channels, use_cross_attn, attention_head_dim| BlockConfig {
+            out_channels,
+            use_cross_attn,
+            attention_head_dim,
+        };
+        Self {
+            blocks: vec![
+                bc(320, true, 5),
+                bc(640, true, 10),
+                bc(1280, true, 20),
+                bc(1280, false, 20),
+            ],
+            cross_attention_dim: 1024,
+            use_linear_projection: true,
+            ..Default::default()
+        }
+    }
+
+    /// The attention slicing used by the down and up blocks at the resolution of the down block
+    /// `index`, a slice size of 0 selecting a slice size based on the number of attention
+    /// heads.
+    pub fn attention_slicing_for_block(&self, index: usize) -> AttentionSlicing {
+        let attention_slicing = match &self.block_attention_slicing {
+            None => self.attention_slicing,
+            Some(slicing) => slicing.get(index).copied().unwrap_or(AttentionSlicing::Disabled),
+        };
+        match attention_slicing {
+            AttentionSlicing::SliceSize(0) => {
+                AttentionSlicing::SliceSize(self.blocks[index].attention_head_dim / 2)
+            }
+            attention_slicing => attention_slicing,
+        }
+    }
+
+    /// The number of parameters of a unet with this config, e.g. to estimate the memory used by
+    /// the weights before loading them.
+    pub fn num_parameters(&self, in_channels: i64, out_channels: i64) -> i64 {
+        use super::{attention::spatial_transformer_num_parameters, resnet};
+        let b_channels = self.blocks[0].out_channels;
+        let bl_channels = self.blocks.last().unwrap().out_channels;
+        let temb = Some(4 * b_channels);
+        let transformer =
+            |channels| spatial_transformer_num_parameters(channels, self.cross_attention_dim);
+        let sample_conv = |channels| 9 * channels * channels + channels;
+        let mut n = (9 * in_channels + 1) * b_channels
+            + 2 * b_channels
+            + (9 * b_channels + 1) * out_channels;
+        // The time embedding.
+        n += (b_channels + 1) * 4 * b_channels + (4 * b_channels + 1) * 4 * b_channels;
+        // The channels of the residual connections from the down blocks to the up blocks.
+        let mut res_channels = vec![b_channels];
+        let mut prev_channels = b_channels;
+        for (i, block) in self.blocks.iter().enumerate() {
+            let channels = block.out_channels;
+            for _ in 0..self.layers_per_block {
+                n += resnet::num_parameters(prev_channels, channels, temb);
+                if block.use_cross_attn {
+                    n += transformer(channels)
+                }
+                res_channels.push(channels);
+                prev_channels = channels;
+            }
+            if i < self.blocks.len() - 1 {
+                n += sample_conv(channels);
+                res_channels.push(channels)
+            }
+        }
+        n += 2 * resnet::num_parameters(bl_channels, bl_channels, temb) + transformer(bl_channels);
+        for (i, block) in self.blocks.iter().rev().enumerate() {
+            let channels = block.out_channels;
+            for _ in 0..self.layers_per_block + 1 {
+                let res_channels = res_channels.pop().unwrap();
+                n += resnet::num_parameters(prev_channels + res_channels, channels, temb);
+                if block.use_cross_attn {
+                    n += transformer(channels)
+                }
+                prev_channels = channels;
+            }
+            if i < self.blocks.len() - 1 {
+                n += sample_conv(channels)
+            }
+        }
+        n
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum UNetDownBlock {
     Basic(DownBlock2D),
@@ -103,12 +223,7 @@ impl UNet2DConditionModel {
             .map(|i| {
                 let BlockConfig { out_channels, use_cross_attn, attention_head_dim } =
                     config.blocks[i];
-
-                // Enable automatic attention slicing if the config sliced_attention_size is set to 0.
-                let sliced_attention_size = match config.sliced_attention_size {
-                    Some(0) => Some(attention_head_dim / 2),
-                    _ => config.sliced_attention_size,
-                };
+                let attention_slicing = config.attention_slicing_for_block(i);
 
                 let in_channels =
                     if i > 0 { config.blocks[i - 1].out_channels } else { b_channels };
@@ -125,7 +240,7 @@ impl UNet2DConditionModel {
                         downblock: db_cfg,
                         attn_num_head_channels: attention_head_dim,
                         cross_attention_dim: config.cross_attention_dim,
-                        sliced_attention_size,
+                        attention_slicing,
                         use_linear_projection: config.use_linear_projection,
                     };
                     let block = CrossAttnDownBlock2D::new(
@@ -170,12 +285,7 @@ impl UNet2DConditionModel {
             .map(|i| {
                 let BlockConfig { out_channels, use_cross_attn, attention_head_dim } =
                     config.blocks[n_blocks - 1 - i];
-
-                // Enable automatic attention slicing if the config sliced_attention_size is set to 0.
-                let sliced_attention_size = match config.sliced_attention_size {
-                    Some(0) => Some(attention_head_dim / 2),
-                    _ => config.sliced_attention_size,
-                };
+                let attention_slicing = config.attention_slicing_for_block(n_blocks - 1 - i);
 
                 let prev_out_channels =
                     if i > 0 { config.blocks[n_blocks - i].out_channels } else { bl_channels };
@@ -195,7 +305,7 @@ impl UNet2DConditionModel {
                         upblock: ub_cfg,
                         attn_num_head_channels: attention_head_dim,
                         cross_attention_dim: config.cross_attention_dim,
-                        sliced_attention_size,
+                        attention_slicing,
                         use_linear_projection: config.use_linear_projection,
                     };
                     let block = CrossAttnUpBlock2D::new(
@@ -225,7 +335,9 @@ impl UNet2DConditionModel {
         let conv_norm_out =
             nn::group_norm(&vs / "conv_norm_out", config.norm_num_groups, b_channels, group_cfg);
         let conv_out = nn::conv2d(&vs / "conv_out", b_channels, out_channels, 3, conv_cfg);
-        Self {
+        let attention_backend = config.attention_backend;
+        let attention_scale_override = config.attention_scale_override;
+        let mut unet = Self {
             conv_in,
             time_proj,
             time_embedding,
@@ -235,11 +347,82 @@ impl UNet2DConditionModel {
             conv_norm_out,
             conv_out,
             config,
+        };
+        unet.set_attention_backend(attention_backend);
+        if attention_scale_override.is_some() {
+            unet.set_attention_scale_override(attention_scale_override)
         }
+        unet
     }
 }
 
 impl UNet2DConditionModel {
+    /// The kind of the model weights, inputs should be converted to it before calling `forward`
+    /// when not using autocast.
+    pub fn kind(&self) -> Kind {
+        self.conv_in.ws.kind()
+    }
+
+    /// The number of channels expected for the input sample, e.g. 4 for the standard models and
+    /// 9 for the inpainting ones.
+    pub fn in_channels(&self) -> i64 {
+        self.conv_in.ws.size()[1]
+    }
+
+    /// The number of channels of the predicted noise, i.e. of the latents.
+    pub fn out_channels(&self) -> i64 {
+        self.conv_out.ws.size()[0]
+    }
+
+    /// Calls `f` on each attention layer of the model together with its name, e.g.
+    /// `down_blocks.0.attentions.1.transformer_blocks.0.attn2`, using the same naming as the
+    /// weights. The `attn1` layers are self-attention ones and the `attn2` layers attend to the
+    /// encoder hidden states.
+    pub fn for_each_attention_mut(&mut self, f: &mut dyn FnMut(&str, &mut CrossAttention)) {
+        for (index, block) in self.down_blocks.iter_mut().enumerate() {
+            if let UNetDownBlock::CrossAttn(block) = block {
+                block.for_each_attention_mut(&format!("down_blocks.{index}"), f)
+            }
+        }
+        self.mid_block.for_each_attention_mut("mid_block", f);
+        for (index, block) in self.up_blocks.iter_mut().enumerate() {
+            if let UNetUpBlock::CrossAttn(block) = block {
+                block.for_each_attention_mut(&format!("up_blocks.{index}"), f)
+            }
+        }
+    }
+
+    /// Installs `processor` on all the attention layers, use `for_each_attention_mut` to only
+    /// target some of them.
+    pub fn set_attention_processor(&mut self, processor: Arc<dyn AttentionProcessor>) {
+        self.for_each_attention_mut(&mut |_name, attn| attn.set_processor(processor.clone()))
+    }
+
+    /// Wraps the processor of the self-attention layers whose name starts with one of
+    /// `prefixes`, e.g. `mid_block`, in a `PerturbedAttentionProcessor` so that they can be
+    /// perturbed for Perturbed-Attention Guidance.
+    pub fn enable_perturbed_attention(&mut self, prefixes: &[&str]) {
+        self.for_each_attention_mut(&mut |name, attn| {
+            if name.ends_with(".attn1") && prefixes.iter().any(|p| name.starts_with(p)) {
+                let inner = attn.processor().clone();
+                attn.set_processor(Arc::new(PerturbedAttentionProcessor { inner }))
+            }
+        })
+    }
+
+    /// Selects the attention implementation used by all the attention layers.
+    pub fn set_attention_backend(&mut self, backend: AttentionBackend) {
+        self.config.attention_backend = backend;
+        self.for_each_attention_mut(&mut |_name, attn| attn.backend = backend)
+    }
+
+    /// Overrides the softmax scale of all the attention layers, `None` restoring the standard
+    /// `1 / sqrt(dim_head)` scale, see `CrossAttention::set_scale_override`.
+    pub fn set_attention_scale_override(&mut self, scale: Option<f64>) {
+        self.config.attention_scale_override = scale;
+        self.for_each_attention_mut(&mut |_name, attn| attn.set_scale_override(scale))
+    }
+
     pub fn forward(&self, xs: &Tensor, timestep: f64, encoder_hidden_states: &Tensor) -> Tensor {
         self.forward_with_additional_residuals(xs, timestep, encoder_hidden_states, None, None)
     }
@@ -252,7 +435,86 @@ impl UNet2DConditionModel {
         down_block_additional_residuals: Option<&[Tensor]>,
         mid_block_additional_residual: Option<&Tensor>,
     ) -> Tensor {
-        let (bsize, _channels, height, width) = xs.size4().unwrap();
+        let bsize = xs.size()[0];
+        let timesteps = Tensor::ones([bsize], (Kind::Float, xs.device())) * timestep;
+        self.forward_impl(
+            xs,
+            &timesteps,
+            encoder_hidden_states,
+            down_block_additional_residuals,
+            mid_block_additional_residual,
+        )
+    }
+
+    /// Same as `forward` with a timestep per batch element, e.g. when the elements of a batch
+    /// are denoised at different noise levels. `timesteps` has shape `(batch,)`, or is a scalar
+    /// tensor which is then used for the whole batch.
+    pub fn forward_with_timesteps(
+        &self,
+        xs: &Tensor,
+        timesteps: &Tensor,
+        encoder_hidden_states: &Tensor,
+    ) -> Tensor {
+        let bsize = xs.size()[0];
+        let timesteps = match timesteps.dim() {
+            0 => timesteps.expand([bsize], false),
+            _ => timesteps.shallow_clone(),
+        };
+        assert_eq!(
+            timesteps.size(),
+            [bsize],
+            "expected one timestep per batch element for a batch of size {bsize}"
+        );
+        self.forward_impl(xs, &timesteps, encoder_hidden_states, None, None)
+    }
+
+    /// Traces the model and saves it as a standalone TorchScript module that can be loaded with
+    /// `tch::CModule::load`, or `torch.jit.load` in python, without the model code. The
+    /// module forward method takes as input the sample, a float tensor with the timestep of
+    /// each batch element, and the encoder hidden states, and returns the predicted noise.
+    ///
+    /// Tracing records the operations run on the example inputs, so the saved module is
+    /// specialized for their shapes, kinds, and device, as well as for the attention slicing
+    /// and processors in use.
+    pub fn save_jit<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        example_sample: &Tensor,
+        example_encoder_hidden_states: &Tensor,
+    ) -> anyhow::Result<()> {
+        let _no_grad_guard = tch::no_grad_guard();
+        let bsize = example_sample.size()[0];
+        let timesteps = Tensor::ones([bsize], (Kind::Float, example_sample.device()));
+        let inputs = [
+            example_sample.shallow_clone(),
+            timesteps,
+            example_encoder_hidden_states.shallow_clone(),
+        ];
+        let module = tch::CModule::create_by_tracing(
+            "UNet2DConditionModel",
+            "forward",
+            &inputs,
+            &mut |inputs| vec![self.forward_impl(&inputs[0], &inputs[1], &inputs[2], None, None)],
+        )?;
+        module.save(path)?;
+        Ok(())
+    }
+
+    fn forward_impl(
+        &self,
+        xs: &Tensor,
+        timesteps: &Tensor,
+        encoder_hidden_states: &Tensor,
+        down_block_additional_residuals: Option<&[Tensor]>,
+        mid_block_additional_residual: Option<&Tensor>,
+    ) -> Tensor {
+        let (_, channels, height, width) = xs.size4().unwrap();
+        assert_eq!(
+            channels,
+            self.in_channels(),
+            "the unet expects {} input channels but got a sample with {channels}",
+            self.in_channels()
+        );
         let device = xs.device();
         let n_blocks = self.config.blocks.len();
         let num_upsamplers = n_blocks - 1;
@@ -262,8 +524,13 @@ impl UNet2DConditionModel {
         // 0. center input if necessary
         let xs = if self.config.center_input_sample { xs * 2.0 - 1.0 } else { xs.shallow_clone() };
         // 1. time
-        let emb = (Tensor::ones([bsize], (Kind::Float, device)) * timestep)
+        // The conversion happens before moving to the device as MPS does not support double
+        // precision timesteps.
+        let emb = timesteps
+            .to_kind(Kind::Float)
+            .to_device(device)
             .apply(&self.time_proj)
+            .to_kind(self.kind())
             .apply(&self.time_embedding);
         // 2. pre-process
         let xs = xs.apply(&self.conv_in);
@@ -325,3 +592,17 @@ impl UNet2DConditionModel {
         xs.apply(&self.conv_norm_out).silu().apply(&self.conv_out)
     }
 }
+
+/// Reads the number of input channels of a unet from the `conv_in.weight` tensor of a
+/// `.safetensors` weight file, only the file header is read. Returns `None` if the weight file
+/// uses a different format.
+pub fn in_channels_from_weights<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Option<i64>> {
+    let shape = crate::utils::safetensors_shape(path.as_ref(), "conv_in.weight")?;
+    match shape {
+        None => Ok(None),
+        Some(shape) if shape.len() == 4 => Ok(Some(shape[1])),
+        Some(shape) => {
+            anyhow::bail!("unexpected conv_in.weight shape {shape:?} in {:?}", path.as_ref())
+        }
+    }
+}
@@ -1,9 +1,10 @@
 //! 2D UNet Building Blocks
 //!
 use crate::models::attention::{
-    AttentionBlock, AttentionBlockConfig, SpatialTransformer, SpatialTransformerConfig,
+    AttentionBlock, AttentionBlockConfig, AttentionBlockWeightNames, AttentionSlicing,
+    CrossAttention, SpatialTransformer, SpatialTransformerConfig,
 };
-use crate::models::resnet::{ResnetBlock2D, ResnetBlock2DConfig};
+use crate::models::resnet::{Resample, ResnetBlock2D, ResnetBlock2DConfig, TimeEmbeddingNorm};
 use tch::{nn, nn::Module, Tensor};
 
 #[derive(Debug)]
@@ -242,6 +243,7 @@ pub struct UNetMidBlock2DConfig {
     pub attn_num_head_channels: Option<i64>,
     // attention_type "default"
     pub output_scale_factor: f64,
+    pub attn_weight_names: AttentionBlockWeightNames,
 }
 
 impl Default for UNetMidBlock2DConfig {
@@ -252,6 +254,7 @@ impl Default for UNetMidBlock2DConfig {
             resnet_groups: Some(32),
             attn_num_head_channels: Some(1),
             output_scale_factor: 1.,
+            attn_weight_names: AttentionBlockWeightNames::Legacy,
         }
     }
 }
@@ -286,6 +289,7 @@ impl UNetMidBlock2D {
             num_groups: resnet_groups,
             rescale_output_factor: config.output_scale_factor,
             eps: config.resnet_eps,
+            weight_names: config.attn_weight_names,
         };
         let mut attn_resnets = vec![];
         for index in 0..config.num_layers {
@@ -314,7 +318,7 @@ pub struct UNetMidBlock2DCrossAttnConfig {
     // attention_type "default"
     pub output_scale_factor: f64,
     pub cross_attn_dim: i64,
-    pub sliced_attention_size: Option<i64>,
+    pub attention_slicing: AttentionSlicing,
     pub use_linear_projection: bool,
 }
 
@@ -327,7 +331,7 @@ impl Default for UNetMidBlock2DCrossAttnConfig {
             attn_num_head_channels: 1,
             output_scale_factor: 1.,
             cross_attn_dim: 1280,
-            sliced_attention_size: None, // Sliced attention disabled
+            attention_slicing: AttentionSlicing::Disabled,
             use_linear_projection: false,
         }
     }
@@ -363,7 +367,7 @@ impl UNetMidBlock2DCrossAttn {
             depth: 1,
             num_groups: resnet_groups,
             context_dim: Some(config.cross_attn_dim),
-            sliced_attention_size: config.sliced_attention_size,
+            attention_slicing: config.attention_slicing,
             use_linear_projection: config.use_linear_projection,
         };
         let mut attn_resnets = vec![];
@@ -381,6 +385,17 @@ impl UNetMidBlock2DCrossAttn {
         Self { resnet, attn_resnets, config }
     }
 
+    /// Calls `f` on each attention layer, see `SpatialTransformer::for_each_attention_mut`.
+    pub fn for_each_attention_mut(
+        &mut self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &mut CrossAttention),
+    ) {
+        for (index, attn) in self.attn_resnets.iter_mut().map(|(attn, _)| attn).enumerate() {
+            attn.for_each_attention_mut(&format!("{prefix}.attentions.{index}"), f)
+        }
+    }
+
     pub fn forward(
         &self,
         xs: &Tensor,
@@ -439,6 +454,7 @@ impl DownBlock2D {
         let resnet_cfg = ResnetBlock2DConfig {
             out_channels: Some(out_channels),
             eps: config.resnet_eps,
+            groups: config.resnet_groups,
             output_scale_factor: config.output_scale_factor,
             temb_channels,
             ..Default::default()
@@ -489,7 +505,7 @@ pub struct CrossAttnDownBlock2DConfig {
     pub attn_num_head_channels: i64,
     pub cross_attention_dim: i64,
     // attention_type: "default"
-    pub sliced_attention_size: Option<i64>,
+    pub attention_slicing: AttentionSlicing,
     pub use_linear_projection: bool,
 }
 
@@ -499,7 +515,7 @@ impl Default for CrossAttnDownBlock2DConfig {
             downblock: Default::default(),
             attn_num_head_channels: 1,
             cross_attention_dim: 1280,
-            sliced_attention_size: None,
+            attention_slicing: AttentionSlicing::Disabled,
             use_linear_projection: false,
         }
     }
@@ -532,7 +548,7 @@ impl CrossAttnDownBlock2D {
             depth: 1,
             context_dim: Some(config.cross_attention_dim),
             num_groups: config.downblock.resnet_groups,
-            sliced_attention_size: config.sliced_attention_size,
+            attention_slicing: config.attention_slicing,
             use_linear_projection: config.use_linear_projection,
         };
         let vs_attn = &vs / "attentions";
@@ -550,6 +566,17 @@ impl CrossAttnDownBlock2D {
         Self { downblock, attentions, config }
     }
 
+    /// Calls `f` on each attention layer, see `SpatialTransformer::for_each_attention_mut`.
+    pub fn for_each_attention_mut(
+        &mut self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &mut CrossAttention),
+    ) {
+        for (index, attn) in self.attentions.iter_mut().enumerate() {
+            attn.for_each_attention_mut(&format!("{prefix}.attentions.{index}"), f)
+        }
+    }
+
     pub fn forward(
         &self,
         xs: &Tensor,
@@ -619,6 +646,7 @@ impl UpBlock2D {
             out_channels: Some(out_channels),
             temb_channels,
             eps: config.resnet_eps,
+            groups: config.resnet_groups,
             output_scale_factor: config.output_scale_factor,
             ..Default::default()
         };
@@ -665,7 +693,7 @@ pub struct CrossAttnUpBlock2DConfig {
     pub attn_num_head_channels: i64,
     pub cross_attention_dim: i64,
     // attention_type: "default"
-    pub sliced_attention_size: Option<i64>,
+    pub attention_slicing: AttentionSlicing,
     pub use_linear_projection: bool,
 }
 
@@ -675,7 +703,7 @@ impl Default for CrossAttnUpBlock2DConfig {
             upblock: Default::default(),
             attn_num_head_channels: 1,
             cross_attention_dim: 1280,
-            sliced_attention_size: None,
+            attention_slicing: AttentionSlicing::Disabled,
             use_linear_projection: false,
         }
     }
@@ -710,7 +738,7 @@ impl CrossAttnUpBlock2D {
             depth: 1,
             context_dim: Some(config.cross_attention_dim),
             num_groups: config.upblock.resnet_groups,
-            sliced_attention_size: config.sliced_attention_size,
+            attention_slicing: config.attention_slicing,
             use_linear_projection: config.use_linear_projection,
         };
         let vs_attn = &vs / "attentions";
@@ -728,6 +756,17 @@ impl CrossAttnUpBlock2D {
         Self { upblock, attentions, config }
     }
 
+    /// Calls `f` on each attention layer, see `SpatialTransformer::for_each_attention_mut`.
+    pub fn for_each_attention_mut(
+        &mut self,
+        prefix: &str,
+        f: &mut dyn FnMut(&str, &mut CrossAttention),
+    ) {
+        for (index, attn) in self.attentions.iter_mut().enumerate() {
+            attn.for_each_attention_mut(&format!("{prefix}.attentions.{index}"), f)
+        }
+    }
+
     pub fn forward(
         &self,
         xs: &Tensor,
@@ -748,3 +787,283 @@ impl CrossAttnUpBlock2D {
         }
     }
 }
+
+/// Shared by `AttnDownBlock2D` and `AttnUpBlock2D`.
+#[derive(Debug, Clone, Copy)]
+pub struct AttnBlock2DConfig {
+    pub attn_num_head_channels: Option<i64>,
+    pub attn_weight_names: AttentionBlockWeightNames,
+}
+
+impl Default for AttnBlock2DConfig {
+    fn default() -> Self {
+        Self { attn_num_head_channels: None, attn_weight_names: AttentionBlockWeightNames::Legacy }
+    }
+}
+
+impl AttnBlock2DConfig {
+    fn attentions(
+        &self,
+        vs: nn::Path,
+        channels: i64,
+        num_layers: i64,
+        resnet_eps: f64,
+        resnet_groups: i64,
+        output_scale_factor: f64,
+    ) -> Vec<AttentionBlock> {
+        let attn_cfg = AttentionBlockConfig {
+            num_head_channels: self.attn_num_head_channels,
+            num_groups: resnet_groups,
+            rescale_output_factor: output_scale_factor,
+            eps: resnet_eps,
+            weight_names: self.attn_weight_names,
+        };
+        let vs_attn = &vs / "attentions";
+        (0..num_layers).map(|i| AttentionBlock::new(&vs_attn / i, channels, attn_cfg)).collect()
+    }
+}
+
+/// Same as `DownBlock2D` with a self-attention layer after each resnet block.
+#[derive(Debug)]
+pub struct AttnDownBlock2D {
+    downblock: DownBlock2D,
+    attentions: Vec<AttentionBlock>,
+    pub config: AttnBlock2DConfig,
+}
+
+impl AttnDownBlock2D {
+    pub fn new(
+        vs: nn::Path,
+        in_channels: i64,
+        out_channels: i64,
+        temb_channels: Option<i64>,
+        downblock: DownBlock2DConfig,
+        config: AttnBlock2DConfig,
+    ) -> Self {
+        let attentions = config.attentions(
+            vs.clone(),
+            out_channels,
+            downblock.num_layers,
+            downblock.resnet_eps,
+            downblock.resnet_groups,
+            downblock.output_scale_factor,
+        );
+        let downblock = DownBlock2D::new(vs, in_channels, out_channels, temb_channels, downblock);
+        Self { downblock, attentions, config }
+    }
+
+    pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>) -> (Tensor, Vec<Tensor>) {
+        let mut output_states = vec![];
+        let mut xs = xs.shallow_clone();
+        for (resnet, attn) in self.downblock.resnets.iter().zip(self.attentions.iter()) {
+            xs = resnet.forward(&xs, temb).apply(attn);
+            output_states.push(xs.shallow_clone());
+        }
+        let xs = match &self.downblock.downsampler {
+            Some(downsampler) => {
+                let xs = xs.apply(downsampler);
+                output_states.push(xs.shallow_clone());
+                xs
+            }
+            None => xs,
+        };
+        (xs, output_states)
+    }
+}
+
+/// Same as `UpBlock2D` with a self-attention layer after each resnet block.
+#[derive(Debug)]
+pub struct AttnUpBlock2D {
+    pub upblock: UpBlock2D,
+    attentions: Vec<AttentionBlock>,
+    pub config: AttnBlock2DConfig,
+}
+
+impl AttnUpBlock2D {
+    pub fn new(
+        vs: nn::Path,
+        in_channels: i64,
+        prev_output_channels: i64,
+        out_channels: i64,
+        temb_channels: Option<i64>,
+        upblock: UpBlock2DConfig,
+        config: AttnBlock2DConfig,
+    ) -> Self {
+        let attentions = config.attentions(
+            vs.clone(),
+            out_channels,
+            upblock.num_layers,
+            upblock.resnet_eps,
+            upblock.resnet_groups,
+            upblock.output_scale_factor,
+        );
+        let upblock = UpBlock2D::new(
+            vs,
+            in_channels,
+            prev_output_channels,
+            out_channels,
+            temb_channels,
+            upblock,
+        );
+        Self { upblock, attentions, config }
+    }
+
+    pub fn forward(
+        &self,
+        xs: &Tensor,
+        res_xs: &[Tensor],
+        temb: Option<&Tensor>,
+        upsample_size: Option<(i64, i64)>,
+    ) -> Tensor {
+        let mut xs = xs.shallow_clone();
+        for (index, resnet) in self.upblock.resnets.iter().enumerate() {
+            xs = Tensor::cat(&[&xs, &res_xs[res_xs.len() - index - 1]], 1);
+            xs = resnet.forward(&xs, temb).apply(&self.attentions[index]);
+        }
+        match &self.upblock.upsampler {
+            Some(upsampler) => upsampler.forward(&xs, upsample_size),
+            None => xs,
+        }
+    }
+}
+
+/// Shared by `ResnetDownsampleBlock2D` and `ResnetUpsampleBlock2D`.
+#[derive(Debug, Clone, Copy)]
+pub struct ResnetResampleBlock2DConfig {
+    pub num_layers: i64,
+    pub resnet_eps: f64,
+    // resnet_act_fn: "swish"
+    pub resnet_groups: i64,
+    pub output_scale_factor: f64,
+    pub time_embedding_norm: TimeEmbeddingNorm,
+    /// Whether a final resnet block resamples the output.
+    pub add_resample: bool,
+}
+
+impl Default for ResnetResampleBlock2DConfig {
+    fn default() -> Self {
+        Self {
+            num_layers: 1,
+            resnet_eps: 1e-6,
+            resnet_groups: 32,
+            output_scale_factor: 1.,
+            time_embedding_norm: TimeEmbeddingNorm::Default,
+            add_resample: true,
+        }
+    }
+}
+
+impl ResnetResampleBlock2DConfig {
+    fn resnet_config(
+        &self,
+        out_channels: i64,
+        temb_channels: Option<i64>,
+        resample: Option<Resample>,
+    ) -> ResnetBlock2DConfig {
+        ResnetBlock2DConfig {
+            out_channels: Some(out_channels),
+            temb_channels,
+            groups: self.resnet_groups,
+            eps: self.resnet_eps,
+            output_scale_factor: self.output_scale_factor,
+            time_embedding_norm: self.time_embedding_norm,
+            resample,
+            ..Default::default()
+        }
+    }
+}
+
+/// Same as `DownBlock2D` but the downsampling is done by a resnet block.
+#[derive(Debug)]
+pub struct ResnetDownsampleBlock2D {
+    resnets: Vec<ResnetBlock2D>,
+    downsampler: Option<ResnetBlock2D>,
+    pub config: ResnetResampleBlock2DConfig,
+}
+
+impl ResnetDownsampleBlock2D {
+    pub fn new(
+        vs: nn::Path,
+        in_channels: i64,
+        out_channels: i64,
+        temb_channels: Option<i64>,
+        config: ResnetResampleBlock2DConfig,
+    ) -> Self {
+        let vs_resnets = &vs / "resnets";
+        let resnet_cfg = config.resnet_config(out_channels, temb_channels, None);
+        let resnets = (0..config.num_layers)
+            .map(|i| {
+                let in_channels = if i == 0 { in_channels } else { out_channels };
+                ResnetBlock2D::new(&vs_resnets / i, in_channels, resnet_cfg)
+            })
+            .collect();
+        let downsampler = config.add_resample.then(|| {
+            let resnet_cfg =
+                config.resnet_config(out_channels, temb_channels, Some(Resample::Down));
+            ResnetBlock2D::new(&vs / "downsamplers" / 0, out_channels, resnet_cfg)
+        });
+        Self { resnets, downsampler, config }
+    }
+
+    pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>) -> (Tensor, Vec<Tensor>) {
+        let mut xs = xs.shallow_clone();
+        let mut output_states = vec![];
+        for resnet in self.resnets.iter() {
+            xs = resnet.forward(&xs, temb);
+            output_states.push(xs.shallow_clone());
+        }
+        if let Some(downsampler) = &self.downsampler {
+            xs = downsampler.forward(&xs, temb);
+            output_states.push(xs.shallow_clone());
+        }
+        (xs, output_states)
+    }
+}
+
+/// Same as `UpBlock2D` but the upsampling is done by a resnet block.
+#[derive(Debug)]
+pub struct ResnetUpsampleBlock2D {
+    pub resnets: Vec<ResnetBlock2D>,
+    upsampler: Option<ResnetBlock2D>,
+    pub config: ResnetResampleBlock2DConfig,
+}
+
+impl ResnetUpsampleBlock2D {
+    pub fn new(
+        vs: nn::Path,
+        in_channels: i64,
+        prev_output_channels: i64,
+        out_channels: i64,
+        temb_channels: Option<i64>,
+        config: ResnetResampleBlock2DConfig,
+    ) -> Self {
+        let vs_resnets = &vs / "resnets";
+        let resnet_cfg = config.resnet_config(out_channels, temb_channels, None);
+        let resnets = (0..config.num_layers)
+            .map(|i| {
+                let res_skip_channels =
+                    if i == config.num_layers - 1 { in_channels } else { out_channels };
+                let resnet_in_channels = if i == 0 { prev_output_channels } else { out_channels };
+                let in_channels = resnet_in_channels + res_skip_channels;
+                ResnetBlock2D::new(&vs_resnets / i, in_channels, resnet_cfg)
+            })
+            .collect();
+        let upsampler = config.add_resample.then(|| {
+            let resnet_cfg = config.resnet_config(out_channels, temb_channels, Some(Resample::Up));
+            ResnetBlock2D::new(&vs / "upsamplers" / 0, out_channels, resnet_cfg)
+        });
+        Self { resnets, upsampler, config }
+    }
+
+    pub fn forward(&self, xs: &Tensor, res_xs: &[Tensor], temb: Option<&Tensor>) -> Tensor {
+        let mut xs = xs.shallow_clone();
+        for (index, resnet) in self.resnets.iter().enumerate() {
+            xs = Tensor::cat(&[&xs, &res_xs[res_xs.len() - index - 1]], 1);
+            xs = resnet.forward(&xs, temb);
+        }
+        match &self.upsampler {
+            Some(upsampler) => upsampler.forward(&xs, temb),
+            None => xs,
+        }
+    }
+}
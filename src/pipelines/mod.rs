@@ -1,3 +1,4 @@
 //! # Pipelines
 
+pub mod stable_diffusion;
 pub mod stable_diffusion_turbo;
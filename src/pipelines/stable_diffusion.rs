@@ -1,17 +1,31 @@
+use crate::models::attention;
 use crate::models::{unet_2d, vae};
 use crate::schedulers::ddim;
-use crate::schedulers::PredictionType;
+use crate::schedulers::{
+    check_inference_steps, Generator, PredictionType, SchedulerOutput, SeedPolicy,
+};
 use crate::transformers::clip;
-use tch::{nn, Device};
+use crate::utils::DeviceSetup;
+use tch::{nn, nn::Module, Device, Kind, Tensor};
 
 #[derive(Clone, Debug)]
 pub struct StableDiffusionConfig {
     pub width: i64,
     pub height: i64,
     pub clip: clip::Config,
-    autoencoder: vae::AutoEncoderKLConfig,
-    unet: unet_2d::UNet2DConditionModelConfig,
-    scheduler: ddim::DDIMSchedulerConfig,
+    /// The vae architecture used by `build_vae`, this can be changed to load the weights of a
+    /// different vae, e.g. `AutoEncoderKLConfig::asymmetric_x1_5`.
+    pub autoencoder: vae::AutoEncoderKLConfig,
+    pub unet: unet_2d::UNet2DConditionModelConfig,
+    pub scheduler: ddim::DDIMSchedulerConfig,
+    /// The kind used for the vae weights and computations, `Kind::Float` by default. The vae
+    /// decoder is prone to overflows in half precision resulting in NaN, i.e. black, images.
+    /// `Kind::BFloat16` has the same range as single precision while being faster, it requires
+    /// an Ampere or more recent GPU.
+    pub vae_kind: Kind,
+    /// The kind used for the clip text encoder weights, the embeddings are converted to the
+    /// unet kind before being used.
+    pub clip_kind: Kind,
 }
 
 impl StableDiffusionConfig {
@@ -20,32 +34,11 @@ impl StableDiffusionConfig {
         height: Option<i64>,
         width: Option<i64>,
     ) -> Self {
-        let bc = |out_channels, use_cross_attn, attention_head_dim| unet_2d::BlockConfig {
-            out_channels,
-            use_cross_attn,
-            attention_head_dim,
-        };
-        // https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/unet/config.json
         let unet = unet_2d::UNet2DConditionModelConfig {
-            blocks: vec![bc(320, true, 8), bc(640, true, 8), bc(1280, true, 8), bc(1280, false, 8)],
-            center_input_sample: false,
-            cross_attention_dim: 768,
-            downsample_padding: 1,
-            flip_sin_to_cos: true,
-            freq_shift: 0.,
-            layers_per_block: 2,
-            mid_block_scale_factor: 1.,
-            norm_eps: 1e-5,
-            norm_num_groups: 32,
-            sliced_attention_size,
-            use_linear_projection: false,
-        };
-        let autoencoder = vae::AutoEncoderKLConfig {
-            block_out_channels: vec![128, 256, 512, 512],
-            layers_per_block: 2,
-            latent_channels: 4,
-            norm_num_groups: 32,
+            attention_slicing: sliced_attention_size.into(),
+            ..unet_2d::UNet2DConditionModelConfig::v1_5()
         };
+        let autoencoder = vae::AutoEncoderKLConfig::v1_5();
         let height = if let Some(height) = height {
             assert_eq!(height % 8, 0, "heigh has to be divisible by 8");
             height
@@ -67,6 +60,8 @@ impl StableDiffusionConfig {
             autoencoder,
             scheduler: Default::default(),
             unet,
+            vae_kind: Kind::Float,
+            clip_kind: Kind::Float,
         }
     }
 
@@ -76,38 +71,12 @@ impl StableDiffusionConfig {
         width: Option<i64>,
         prediction_type: PredictionType,
     ) -> Self {
-        let bc = |out_channels, use_cross_attn, attention_head_dim| unet_2d::BlockConfig {
-            out_channels,
-            use_cross_attn,
-            attention_head_dim,
-        };
-        // https://huggingface.co/stabilityai/stable-diffusion-2-1/blob/main/unet/config.json
         let unet = unet_2d::UNet2DConditionModelConfig {
-            blocks: vec![
-                bc(320, true, 5),
-                bc(640, true, 10),
-                bc(1280, true, 20),
-                bc(1280, false, 20),
-            ],
-            center_input_sample: false,
-            cross_attention_dim: 1024,
-            downsample_padding: 1,
-            flip_sin_to_cos: true,
-            freq_shift: 0.,
-            layers_per_block: 2,
-            mid_block_scale_factor: 1.,
-            norm_eps: 1e-5,
-            norm_num_groups: 32,
-            sliced_attention_size,
-            use_linear_projection: true,
+            attention_slicing: sliced_attention_size.into(),
+            ..unet_2d::UNet2DConditionModelConfig::v2_1()
         };
         // https://huggingface.co/stabilityai/stable-diffusion-2-1/blob/main/vae/config.json
-        let autoencoder = vae::AutoEncoderKLConfig {
-            block_out_channels: vec![128, 256, 512, 512],
-            layers_per_block: 2,
-            latent_channels: 4,
-            norm_num_groups: 32,
-        };
+        let autoencoder = vae::AutoEncoderKLConfig::v1_5();
         let scheduler = ddim::DDIMSchedulerConfig { prediction_type, ..Default::default() };
 
         let height = if let Some(height) = height {
@@ -124,7 +93,16 @@ impl StableDiffusionConfig {
             768
         };
 
-        Self { width, height, clip: clip::Config::v2_1(), autoencoder, scheduler, unet }
+        Self {
+            width,
+            height,
+            clip: clip::Config::v2_1(),
+            autoencoder,
+            scheduler,
+            unet,
+            vae_kind: Kind::Float,
+            clip_kind: Kind::Float,
+        }
     }
 
     pub fn v2_1(
@@ -152,26 +130,102 @@ impl StableDiffusionConfig {
         vae_weights: &str,
         device: Device,
     ) -> anyhow::Result<vae::AutoEncoderKL> {
+        self.build_vae_with_kind(vae_weights, device, Kind::Float)
+    }
+
+    /// Same as `build_vae` but converts the weights to `kind` after loading them, the inputs
+    /// then have to be converted to this kind too, see `AutoEncoderKL::kind`.
+    pub fn build_vae_with_kind(
+        &self,
+        vae_weights: &str,
+        device: Device,
+        kind: Kind,
+    ) -> anyhow::Result<vae::AutoEncoderKL> {
+        let latent_channels = self.autoencoder.latent_channels;
+        let shape = crate::utils::safetensors_shape(vae_weights, "post_quant_conv.weight")?;
+        if let Some(shape) = shape {
+            if shape.first() != Some(&latent_channels) {
+                anyhow::bail!(
+                    "{vae_weights} is for a vae with {shape:?} post_quant_conv weights, expected \
+                     {latent_channels} latent channels"
+                )
+            }
+        }
+        let start = std::time::Instant::now();
         let mut vs_ae = nn::VarStore::new(device);
         // https://huggingface.co/runwayml/stable-diffusion-v1-5/blob/main/vae/config.json
         let autoencoder = vae::AutoEncoderKL::new(vs_ae.root(), 3, 3, self.autoencoder.clone());
         vs_ae.load(vae_weights)?;
+        vs_ae.set_kind(kind);
+        log::info!("loaded the vae from {vae_weights} on {device:?} in {:.2?}", start.elapsed());
         Ok(autoencoder)
     }
 
+    /// Builds the consistency decoder from the `openai/consistency-decoder` weights, this only
+    /// applies to the latents of the stable diffusion v1 vae, e.g. v1.5 and v2.1.
+    pub fn build_consistency_decoder(
+        &self,
+        decoder_weights: &str,
+        device: Device,
+        kind: Kind,
+    ) -> anyhow::Result<vae::ConsistencyDecoder> {
+        let start = std::time::Instant::now();
+        let mut vs = nn::VarStore::new(device);
+        let decoder = vae::ConsistencyDecoder::new(vs.root() / "decoder_unet", Default::default());
+        vs.load(decoder_weights)?;
+        vs.set_kind(kind);
+        log::info!(
+            "loaded the consistency decoder from {decoder_weights} on {device:?} in {:.2?}",
+            start.elapsed()
+        );
+        Ok(decoder)
+    }
+
+    /// Builds the LayerDiffuse transparent vae decoder, used by
+    /// `StableDiffusionPipeline::decode_rgba`.
+    pub fn build_transparent_decoder(
+        &self,
+        decoder_weights: &str,
+        device: Device,
+        kind: Kind,
+    ) -> anyhow::Result<vae::TransparentDecoder> {
+        let start = std::time::Instant::now();
+        let mut vs = nn::VarStore::new(device);
+        let decoder = vae::TransparentDecoder::new(vs.root(), Default::default());
+        vs.load(decoder_weights)?;
+        vs.set_kind(kind);
+        log::info!(
+            "loaded the transparent decoder from {decoder_weights} on {device:?} in {:.2?}",
+            start.elapsed()
+        );
+        Ok(decoder)
+    }
+
     pub fn build_unet(
         &self,
         unet_weights: &str,
         device: Device,
         in_channels: i64,
     ) -> anyhow::Result<unet_2d::UNet2DConditionModel> {
+        if let Some(weight_in_channels) = unet_2d::in_channels_from_weights(unet_weights)? {
+            if weight_in_channels != in_channels {
+                anyhow::bail!(
+                    "{unet_weights} is for a unet with {weight_in_channels} input channels, \
+                     expected {in_channels}"
+                )
+            }
+        }
+        let start = std::time::Instant::now();
         let mut vs_unet = nn::VarStore::new(device);
         let unet =
             unet_2d::UNet2DConditionModel::new(vs_unet.root(), in_channels, 4, self.unet.clone());
         vs_unet.load(unet_weights)?;
+        log::info!("loaded the unet from {unet_weights} on {device:?} in {:.2?}", start.elapsed());
         Ok(unet)
     }
 
+    /// Panics if `n_steps` is not between 1 and the scheduler `train_timesteps`, this can be
+    /// checked beforehand with `schedulers::check_inference_steps`.
     pub fn build_scheduler(&self, n_steps: usize) -> ddim::DDIMScheduler {
         ddim::DDIMScheduler::new(n_steps, self.scheduler)
     }
@@ -181,9 +235,2514 @@ impl StableDiffusionConfig {
         clip_weights: &str,
         device: tch::Device,
     ) -> anyhow::Result<clip::ClipTextTransformer> {
+        self.build_clip_transformer_with_kind(clip_weights, device, Kind::Float)
+    }
+
+    /// Same as `build_clip_transformer` but converts the weights to `kind` after loading them,
+    /// independently of the kind used for the other models. The resulting embeddings have this
+    /// kind too and should be converted to the unet kind, see
+    /// `StableDiffusionPipeline::to_unet_input`.
+    pub fn build_clip_transformer_with_kind(
+        &self,
+        clip_weights: &str,
+        device: tch::Device,
+        kind: Kind,
+    ) -> anyhow::Result<clip::ClipTextTransformer> {
+        let start = std::time::Instant::now();
+        let mut vs = tch::nn::VarStore::new(device);
+        let mut text_model = clip::ClipTextTransformer::new(vs.root(), &self.clip);
+        if has_text_projection(clip_weights) {
+            text_model = text_model.with_text_projection(vs.root(), &self.clip);
+        }
+        vs.load(clip_weights)?;
+        vs.set_kind(kind);
+        log::info!("loaded clip from {clip_weights} on {device:?} in {:.2?}", start.elapsed());
+        Ok(text_model)
+    }
+
+    /// Same as `build_clip_transformer_with_kind` but with the token embedding table stored on
+    /// `token_embedding_device`, typically the CPU to save some memory on the accelerator. The
+    /// embedding lookup happens once per prompt so the extra transfer is negligible.
+    pub fn build_clip_transformer_with_token_embedding(
+        &self,
+        clip_weights: &str,
+        device: tch::Device,
+        token_embedding_device: tch::Device,
+        kind: Kind,
+    ) -> anyhow::Result<clip::ClipTextTransformer> {
+        if token_embedding_device == device {
+            return self.build_clip_transformer_with_kind(clip_weights, device, kind);
+        }
+        let start = std::time::Instant::now();
         let mut vs = tch::nn::VarStore::new(device);
-        let text_model = clip::ClipTextTransformer::new(vs.root(), &self.clip);
+        let mut vs_token_embedding = tch::nn::VarStore::new(token_embedding_device);
+        let mut text_model = clip::ClipTextTransformer::new_with_token_embedding(
+            vs.root(),
+            vs_token_embedding.root(),
+            &self.clip,
+        );
+        if has_text_projection(clip_weights) {
+            text_model = text_model.with_text_projection(vs.root(), &self.clip);
+        }
         vs.load(clip_weights)?;
+        vs_token_embedding.load(clip_weights)?;
+        vs.set_kind(kind);
+        vs_token_embedding.set_kind(kind);
+        log::info!(
+            "loaded clip from {clip_weights} on {device:?}, with the token embedding on \
+             {token_embedding_device:?}, in {:.2?}",
+            start.elapsed()
+        );
         Ok(text_model)
     }
+
+    /// Same as `build_clip_transformer` but returns a boxed text encoder, so that it can be
+    /// used interchangeably with other encoders.
+    pub fn build_text_encoder(
+        &self,
+        clip_weights: &str,
+        device: tch::Device,
+    ) -> anyhow::Result<Box<dyn Module>> {
+        Ok(Box::new(self.build_clip_transformer(clip_weights, device)?))
+    }
+
+    /// Builds a text encoder concatenating the embeddings of multiple CLIP models, each given by
+    /// its config and weight file. When the concatenated dimension differs from the unet
+    /// cross-attention dimension, a linear projection is loaded from `projection_weights`, the
+    /// `projection.weight` and `projection.bias` tensors are used.
+    pub fn build_combined_text_encoder(
+        &self,
+        encoders: &[(clip::Config, &str)],
+        projection_weights: Option<&str>,
+        device: tch::Device,
+    ) -> anyhow::Result<clip::CombinedTextEncoder> {
+        let mut text_models: Vec<Box<dyn Module>> = vec![];
+        for (config, clip_weights) in encoders.iter() {
+            let mut vs = nn::VarStore::new(device);
+            let text_model = clip::ClipTextTransformer::new(vs.root(), config);
+            vs.load(clip_weights)?;
+            text_models.push(Box::new(text_model))
+        }
+        let embed_dim: i64 = encoders.iter().map(|(config, _)| config.embed_dim()).sum();
+        let cross_attention_dim = self.unet.cross_attention_dim;
+        let projection = match projection_weights {
+            Some(projection_weights) => {
+                let mut vs = nn::VarStore::new(device);
+                let projection = nn::linear(
+                    vs.root() / "projection",
+                    embed_dim,
+                    cross_attention_dim,
+                    Default::default(),
+                );
+                vs.load(projection_weights)?;
+                Some(projection)
+            }
+            None if embed_dim == cross_attention_dim => None,
+            None => anyhow::bail!(
+                "combined embedding dim {embed_dim} differs from the cross-attention dim \
+                 {cross_attention_dim}, a projection is required"
+            ),
+        };
+        Ok(clip::CombinedTextEncoder::new(text_models, projection))
+    }
+}
+
+enum PromptPart<'a> {
+    Text(&'a str),
+    Edit { from: &'a str, to: &'a str, step: usize },
+}
+
+/// Parses the prompt editing syntax of the AUTOMATIC1111 web UI, `[from:to:when]` replacing
+/// `from` with `to` after `when` steps, or after this fraction of the `n_steps` steps when
+/// `when` is below 1. `[to:when]` adds `to` after `when` and `[from::when]` removes `from`.
+/// Returns the step at which each prompt starts being used and the prompt with the editing
+/// syntax expanded, the first one starting at step 0. Brackets that do not follow this syntax,
+/// e.g. because `when` is not a number, are kept as is, nested edits are not supported.
+pub fn parse_prompt_schedule(prompt: &str, n_steps: usize) -> Vec<(usize, String)> {
+    let mut parts = vec![];
+    let mut rest = prompt;
+    while let Some(open) = rest.find('[') {
+        let edit = rest[open + 1..].find(']').and_then(|close| {
+            let inner = &rest[open + 1..open + 1 + close];
+            if inner.contains('[') {
+                return None;
+            }
+            let fields: Vec<&str> = inner.split(':').collect();
+            let (from, to, when) = match fields.as_slice() {
+                [to, when] => ("", *to, when),
+                [from, to, when] => (*from, *to, when),
+                _ => return None,
+            };
+            let when: f64 = when.trim().parse().ok()?;
+            let step = if when < 1. { when * n_steps as f64 } else { when };
+            Some((PromptPart::Edit { from, to, step: step.max(0.) as usize }, close))
+        });
+        match edit {
+            Some((edit, close)) => {
+                parts.push(PromptPart::Text(&rest[..open]));
+                parts.push(edit);
+                rest = &rest[open + close + 2..];
+            }
+            None => {
+                parts.push(PromptPart::Text(&rest[..open + 1]));
+                rest = &rest[open + 1..];
+            }
+        }
+    }
+    parts.push(PromptPart::Text(rest));
+    let mut steps: Vec<usize> = parts
+        .iter()
+        .filter_map(|part| match part {
+            PromptPart::Edit { step, .. } if *step > 0 && *step < n_steps => Some(*step),
+            _ => None,
+        })
+        .collect();
+    steps.sort_unstable();
+    steps.dedup();
+    std::iter::once(0)
+        .chain(steps)
+        .map(|start| {
+            let prompt: String = parts
+                .iter()
+                .map(|part| match part {
+                    PromptPart::Text(text) => *text,
+                    PromptPart::Edit { from, to, step } => {
+                        if start < *step {
+                            from
+                        } else {
+                            to
+                        }
+                    }
+                })
+                .collect();
+            (start, prompt)
+        })
+        .collect()
+}
+
+/// Returns the prompt weights divided by their sum.
+fn normalized_weights(prompts: &[(&str, f64)]) -> anyhow::Result<Vec<f64>> {
+    if prompts.is_empty() {
+        anyhow::bail!("at least one prompt is required for blending")
+    }
+    let total: f64 = prompts.iter().map(|(_, weight)| weight).sum();
+    if total.abs() < 1e-6 {
+        anyhow::bail!("the prompt weights sum to zero: {prompts:?}")
+    }
+    Ok(prompts.iter().map(|(_, weight)| weight / total).collect())
+}
+
+/// Computes the weighted sum of some `(n, seq_len, dim)` embeddings over the first dimension.
+fn blend_embeddings(embeddings: &Tensor, weights: &[f64]) -> Tensor {
+    let weights = Tensor::from_slice(weights).to_kind(Kind::Float).to_device(embeddings.device());
+    let embeddings = embeddings.to_kind(Kind::Float) * weights.view([-1, 1, 1]);
+    embeddings.sum_dim_intlist(0, true, Kind::Float)
+}
+
+/// Converts the vae output with values in `[-1, 1]` to an image in the given format on the CPU.
+fn postprocess_image(image: &Tensor, format: OutputFormat) -> Tensor {
+    let image = (image.to_kind(Kind::Float) / 2 + 0.5).to_device(Device::Cpu);
+    match format {
+        OutputFormat::U8 => (image.clamp(0., 1.) * 255.).to_kind(Kind::Uint8),
+        OutputFormat::U16 => (image.clamp(0., 1.) * 65535.).round().to_kind(Kind::Int),
+        OutputFormat::F32 => image,
+    }
+}
+
+fn check_vae_for_unet(
+    vae: &vae::AutoEncoderKL,
+    unet: &unet_2d::UNet2DConditionModel,
+) -> anyhow::Result<()> {
+    let (vae_channels, unet_channels) = (vae.config.latent_channels, unet.out_channels());
+    if vae_channels != unet_channels {
+        anyhow::bail!(
+            "the vae uses {vae_channels} latent channels but the unet denoises latents with \
+             {unet_channels} channels"
+        )
+    }
+    Ok(())
+}
+
+/// The order of the unconditional and conditional halves along the batch dimension of the
+/// classifier-free guidance batches, i.e. of the text embeddings returned by
+/// `StableDiffusionPipeline::encode_prompt` and expected by the `denoise` methods, and of the
+/// corresponding unet predictions. Mixing up the two conventions silently inverts the guidance,
+/// pushing the samples towards the negative prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CfgOrder {
+    /// `[uncond, cond]`, as in python diffusers.
+    #[default]
+    UncondFirst,
+    /// `[cond, uncond]`, as used by some other implementations.
+    CondFirst,
+}
+
+impl CfgOrder {
+    /// Concatenates the unconditional and conditional halves along the batch dimension.
+    pub fn cat(&self, uncond: &Tensor, cond: &Tensor) -> Tensor {
+        match self {
+            Self::UncondFirst => Tensor::cat(&[uncond, cond], 0),
+            Self::CondFirst => Tensor::cat(&[cond, uncond], 0),
+        }
+    }
+
+    /// Splits a guidance batch into its `(uncond, cond)` halves, this panics if the batch size
+    /// is odd.
+    pub fn split(&self, xs: &Tensor) -> (Tensor, Tensor) {
+        let bsize = xs.size()[0];
+        assert!(bsize % 2 == 0, "guidance batches have an even size, got a batch of {bsize}");
+        let (first, second) = (xs.narrow(0, 0, bsize / 2), xs.narrow(0, bsize / 2, bsize / 2));
+        match self {
+            Self::UncondFirst => (first, second),
+            Self::CondFirst => (second, first),
+        }
+    }
+}
+
+/// Selects the text embeddings the unet is run with from the concatenated negative and positive
+/// embeddings. Classifier-free guidance is only applied for a guidance scale above 1, otherwise
+/// a single unet pass is run per step: with the negative embeddings for a scale of 0, which
+/// samples from the unconditional model, and with the positive embeddings alone otherwise.
+fn guidance_embeddings(text_embeddings: &Tensor, guidance_scale: f64, order: CfgOrder) -> Tensor {
+    if guidance_scale > 1. {
+        text_embeddings.shallow_clone()
+    } else {
+        let (uncond, cond) = order.split(text_embeddings);
+        if guidance_scale == 0. {
+            uncond
+        } else {
+            cond
+        }
+    }
+}
+
+/// Whether a clip weight file includes the `text_projection` layer applied to the pooled output,
+/// only `.safetensors` files are inspected. Unreadable files are reported as not including it,
+/// the error being returned when loading the weights.
+fn has_text_projection(clip_weights: &str) -> bool {
+    matches!(crate::utils::safetensors_shape(clip_weights, "text_projection.weight"), Ok(Some(_)))
+}
+
+/// Checks that the text embeddings have the dimension expected by the unet cross-attention
+/// layers, e.g. to catch a clip config for v1.5 being used with a v2.1 unet.
+fn check_clip_for_unet(config: &StableDiffusionConfig) -> anyhow::Result<()> {
+    let (embed_dim, cross_attention_dim) =
+        (config.clip.embed_dim(), config.unet.cross_attention_dim);
+    if embed_dim != cross_attention_dim {
+        anyhow::bail!(
+            "the text encoder returns embeddings of dimension {embed_dim} but the unet uses a \
+             cross-attention dimension of {cross_attention_dim}"
+        )
+    }
+    Ok(())
+}
+
+/// An approximation of the memory needed to generate images, in bytes, see `estimate_memory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    pub clip_weights: u64,
+    pub unet_weights: u64,
+    pub vae_weights: u64,
+    /// The peak memory used by the intermediate tensors of a unet forward pass, classifier free
+    /// guidance doubling the batch size.
+    pub unet_activations: u64,
+    /// The peak memory used by the intermediate tensors of the vae decoder.
+    pub vae_activations: u64,
+}
+
+impl MemoryEstimate {
+    pub fn weights(&self) -> u64 {
+        self.clip_weights + self.unet_weights + self.vae_weights
+    }
+
+    /// The peak memory when all the models are on the same device, the unet and the vae
+    /// decoder never running at the same time.
+    pub fn total(&self) -> u64 {
+        self.weights() + u64::max(self.unet_activations, self.vae_activations)
+    }
+}
+
+/// Estimates the memory needed to generate `batch_size` images of the configured size at a
+/// time, with the unet weights converted to `unet_kind`, without loading or allocating
+/// anything. This can be used to lower the resolution or to enable attention slicing before
+/// running out of memory on a small GPU.
+///
+/// The weight sizes are computed from the number of parameters of each model. The activations
+/// are approximated from the largest tensors alive at the same time, the residual connections
+/// of the unet and the attention scores at the highest resolution, which gives the right order
+/// of magnitude but does not account for the allocator overhead.
+pub fn estimate_memory(
+    config: &StableDiffusionConfig,
+    batch_size: i64,
+    unet_kind: Kind,
+) -> MemoryEstimate {
+    let bytes = |numel: i64, kind: Kind| (numel * kind.elt_size_in_bytes() as i64) as u64;
+    let unet_config = &config.unet;
+    let (height, width) = (config.height / 8, config.width / 8);
+    let n_blocks = unet_config.blocks.len();
+
+    let cfg_batch_size = 2 * batch_size;
+    let b_channels = unet_config.blocks[0].out_channels;
+    let mut residual_numel = b_channels * height * width;
+    let mut peak_numel = 0;
+    let mut peak_scores_bytes = 0;
+    for (i, block) in unet_config.blocks.iter().enumerate() {
+        let channels = block.out_channels;
+        let hw = (height >> i) * (width >> i);
+        residual_numel += unet_config.layers_per_block * channels * hw;
+        if i < n_blocks - 1 {
+            residual_numel += channels * (height >> (i + 1)) * (width >> (i + 1))
+        }
+        // The concatenated up block inputs and the GEGLU feed-forward intermediate tensors.
+        let numel = 16 * channels * hw;
+        peak_numel = peak_numel.max(numel);
+        let uses_scores =
+            matches!(unet_config.attention_backend, attention::AttentionBackend::Standard);
+        if block.use_cross_attn && uses_scores {
+            let batch_heads = cfg_batch_size * block.attention_head_dim;
+            let slicing = unet_config.attention_slicing_for_block(i);
+            let heads = slicing.slice_size(batch_heads, hw, hw).unwrap_or(batch_heads);
+            // The scores and the softmax output, both in single precision.
+            peak_scores_bytes = peak_scores_bytes.max(2 * bytes(heads * hw * hw, Kind::Float));
+        }
+    }
+    let unet_activations =
+        bytes(cfg_batch_size * (residual_numel + peak_numel), unet_kind) + peak_scores_bytes;
+
+    let vae_config = &config.autoencoder;
+    let up_channels =
+        vae_config.up_block_out_channels.as_ref().unwrap_or(&vae_config.block_out_channels);
+    let first = up_channels[0];
+    let prev = up_channels.get(1).copied().unwrap_or(first);
+    // The upsampled output of the previous block and the resnet intermediate tensors at the full
+    // resolution, or the single head attention scores of the mid block.
+    let full_resolution =
+        bytes(batch_size * (2 * prev + 2 * first) * config.height * config.width, config.vae_kind);
+    let hw = height * width;
+    let mid_scores = 2 * bytes(batch_size * hw * hw, Kind::Float);
+
+    MemoryEstimate {
+        clip_weights: bytes(config.clip.num_parameters(), config.clip_kind),
+        unet_weights: bytes(unet_config.num_parameters(4, 4), unet_kind),
+        vae_weights: bytes(vae_config.num_parameters(), config.vae_kind),
+        unet_activations,
+        vae_activations: full_resolution.max(mid_scores),
+    }
+}
+
+/// A least recently used cache of prompt embeddings as computed by the text encoder, see
+/// `StableDiffusionPipeline::with_embedding_cache`. The entries are keyed by the prompt tokens
+/// so that prompts only differing in case or spacing share the same embeddings. The cached
+/// embeddings are only valid for a given text encoder configuration, the cache is cleared when
+/// used with a different one.
+#[derive(Debug)]
+pub struct EmbeddingCache {
+    capacity: usize,
+    clip_config: Option<clip::Config>,
+    // Ordered from the least to the most recently used.
+    entries: Vec<(Vec<Vec<usize>>, Tensor)>,
+}
+
+impl EmbeddingCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, clip_config: None, entries: Vec::with_capacity(capacity) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear()
+    }
+
+    fn check_config(&mut self, clip_config: &clip::Config) {
+        if self.clip_config.as_ref() != Some(clip_config) {
+            self.entries.clear();
+            self.clip_config = Some(clip_config.clone())
+        }
+    }
+
+    fn get(&mut self, tokens: &[Vec<usize>]) -> Option<Tensor> {
+        let index = self.entries.iter().position(|(t, _)| t == tokens)?;
+        let entry = self.entries.remove(index);
+        // A deep copy so that in-place operations by the caller cannot modify the cached value,
+        // the cache being shared by all the threads using the pipeline.
+        let embeddings = entry.1.copy();
+        self.entries.push(entry);
+        Some(embeddings)
+    }
+
+    fn insert(&mut self, tokens: &[Vec<usize>], embeddings: &Tensor) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.retain(|(t, _)| t != tokens);
+        if self.entries.len() >= self.capacity {
+            let _ = self.entries.remove(0);
+        }
+        self.entries.push((tokens.to_vec(), embeddings.copy()))
+    }
+}
+
+static SEED_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Runs a scheduler step drawing its noise using `generator`. With `SeedPolicy::Streaming` the
+/// noise comes from the global random generator, so `SEED_LOCK` is held during the step.
+fn scheduler_step(
+    scheduler: &ddim::DDIMScheduler,
+    model_output: &Tensor,
+    timestep: usize,
+    sample: &Tensor,
+    generator: &Generator,
+) -> SchedulerOutput {
+    match generator.seed_policy {
+        SeedPolicy::Streaming => {
+            let _lock = SEED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            scheduler.step_with_generator(model_output, timestep, sample, generator)
+        }
+        SeedPolicy::PerStepDerived { .. } => {
+            scheduler.step_with_generator(model_output, timestep, sample, generator)
+        }
+    }
+}
+
+/// Parameters controlling a single generation with [`StableDiffusionPipeline`].
+#[derive(Debug, Clone)]
+pub struct SamplingConfig {
+    /// The number of steps to run the diffusion for.
+    pub n_steps: usize,
+    /// The classifier free guidance scale. Guidance is only applied above 1, which requires
+    /// two unet passes per step, 0 ignores the prompt and samples from the unconditional model.
+    pub guidance_scale: f64,
+    /// The random seed used to generate the initial latents, this is the structure seed when
+    /// `detail_seed` is set.
+    pub seed: i64,
+    /// The source of the initial latents noise, and of the noise added at each step by the
+    /// DDIM scheduler when its `eta` is positive. With `SeedPolicy::Streaming` the step noise
+    /// continues drawing from the global random generator, holding the pipeline seed lock for
+    /// each draw, so the concurrent generations of a shared pipeline can interleave their draws.
+    /// `SeedPolicy::PerStepDerived` makes the step noise independent of the other generations.
+    pub generator: Generator,
+    /// When set, a fraction in `[0, 1)` of the denoising process that has already been run on
+    /// the input latents, the timesteps before this point are skipped. This is used by a refiner
+    /// to resume from the latents returned by a base model run with `denoising_end`.
+    pub denoising_start: Option<f64>,
+    /// When set, a fraction in `(0, 1]` of the denoising process after which the loop stops,
+    /// returning partially denoised latents.
+    pub denoising_end: Option<f64>,
+    /// The Perturbed-Attention Guidance scale, 0 to disable it. When positive, an additional
+    /// unet forward pass is run at each step with the self-attention of the mid block replaced
+    /// by an identity map, and the prediction is pushed away from this perturbed one.
+    pub pag_scale: f64,
+    /// The filter used to resize the mask to the latent resolution when inpainting.
+    pub mask_downsampling: MaskDownsampling,
+    /// When set, the initial latents are interpolated towards the noise generated with this
+    /// seed, producing variations of the image generated with `seed` alone.
+    pub variation_seed: Option<i64>,
+    /// How far the initial latents are moved towards the `variation_seed` noise, from 0 for the
+    /// original image to 1 for the image that `variation_seed` would generate.
+    pub variation_strength: f64,
+    /// When set, the noise added by the scheduler from step `detail_start` onwards is drawn from
+    /// this seed, see `SeedPolicy::PerStepDerived`, so that it varies the details while the
+    /// composition established by the first steps is kept. This only has an effect with a
+    /// stochastic scheduler, e.g. DDIM with a positive `eta`.
+    pub detail_seed: Option<i64>,
+    /// The index, among the steps being run, of the first step using `detail_seed`.
+    pub detail_start: usize,
+    /// When set, `inpaint` only denoises the bounding box of the mask extended by this number
+    /// of pixels on each side, the result being pasted back into the full image. This speeds up
+    /// small edits on large images, the padding giving the models some context around the
+    /// repainted region.
+    pub inpaint_crop_padding: Option<i64>,
+    /// Whether the unet predictions are checked for NaN or infinite values at each step, see
+    /// `NanCheck`.
+    pub nan_check: NanCheck,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            n_steps: 30,
+            guidance_scale: 7.5,
+            seed: 32,
+            generator: Generator::default(),
+            denoising_start: None,
+            denoising_end: None,
+            pag_scale: 0.,
+            mask_downsampling: MaskDownsampling::Nearest,
+            variation_seed: None,
+            variation_strength: 0.,
+            detail_seed: None,
+            detail_start: 0,
+            inpaint_crop_padding: None,
+            nan_check: NanCheck::Disabled,
+        }
+    }
+}
+
+impl SamplingConfig {
+    /// Returns the range of timestep indexes to run based on `denoising_start` and
+    /// `denoising_end`, using the same cutoff timesteps as the Python diffusers SDXL pipelines.
+    /// Returns an error when one of these fractions is out of range.
+    pub fn timestep_range(
+        &self,
+        timesteps: &[usize],
+        train_timesteps: usize,
+    ) -> anyhow::Result<std::ops::Range<usize>> {
+        let cutoff = |fraction: f64| {
+            (train_timesteps as f64 - fraction * train_timesteps as f64).round() as usize
+        };
+        let start = match self.denoising_start {
+            None => 0,
+            Some(start) => {
+                if !(0. ..1.).contains(&start) {
+                    anyhow::bail!("denoising_start must be in [0, 1), got {start}")
+                }
+                let cutoff = cutoff(start);
+                timesteps.iter().take_while(|&&t| t >= cutoff).count()
+            }
+        };
+        let end = match self.denoising_end {
+            None => timesteps.len(),
+            Some(end) => {
+                if !(end > 0. && end <= 1.) {
+                    anyhow::bail!("denoising_end must be in (0, 1], got {end}")
+                }
+                let cutoff = cutoff(end);
+                timesteps.iter().take_while(|&&t| t >= cutoff).count()
+            }
+        };
+        Ok(start..end.max(start))
+    }
+
+    /// The generator for the noise added by the scheduler at `step`, the index among the steps
+    /// being run, this uses `detail_seed` from `detail_start` onwards.
+    fn step_generator(&self, step: usize) -> Generator {
+        match self.detail_seed {
+            Some(detail_seed) if step >= self.detail_start => Generator {
+                seed_policy: SeedPolicy::PerStepDerived { base_seed: detail_seed },
+                ..self.generator
+            },
+            _ => self.generator,
+        }
+    }
+
+    /// Draws the initial latents noise from `seed`, blended with the `variation_seed` noise
+    /// using a spherical interpolation. This seeds the global random generator so `SEED_LOCK`
+    /// has to be held.
+    fn initial_noise(&self, size: &[i64], device: Device) -> Tensor {
+        tch::manual_seed(self.seed);
+        let noise = self.generator.randn(size, (Kind::Float, device));
+        match self.variation_seed {
+            Some(variation_seed) if self.variation_strength > 0. => {
+                tch::manual_seed(variation_seed);
+                let variation = self.generator.randn(size, (Kind::Float, device));
+                crate::utils::slerp(&noise, &variation, self.variation_strength)
+            }
+            _ => noise,
+        }
+    }
+}
+
+/// What to do when the unet predicts NaN or infinite values, e.g. after an overflow in half
+/// precision, which would otherwise silently result in a black image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NanCheck {
+    #[default]
+    Disabled,
+    /// Fail with an error giving the step index and timestep.
+    Error,
+    /// Run the unet forward pass of the step again with autocast disabled, i.e. in single
+    /// precision for a unet with single precision weights run under autocast, and fail if the
+    /// prediction is still not finite. This also fails for unets with half precision weights.
+    RetryInFloat,
+}
+
+/// The format of the images returned by `StableDiffusionPipeline::decode_with_format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Values clamped to `[0, 255]` with kind `Uint8`, as expected by `tch::vision::image::save`.
+    #[default]
+    U8,
+    /// Values clamped to `[0, 65535]`, this uses kind `Int` as there is no 16 bits unsigned
+    /// kind. The image can be saved with `utils::save_image_u16`.
+    U16,
+    /// The raw decoder output with kind `Float`, nominally in `[0, 1]` but without clamping so
+    /// that out of range values are preserved, e.g. for tone mapping.
+    F32,
+}
+
+/// The state of the denoising loop at the end of a step, as passed to the callback of
+/// `StableDiffusionPipeline::denoise_with_callback`.
+#[derive(Debug)]
+pub struct StepInfo<'a> {
+    /// The index of the step among the ones being run.
+    pub step: usize,
+    pub timestep: usize,
+    /// The latents after this step, used as input for the next one.
+    pub latents: &'a Tensor,
+    /// The scheduler estimate of the fully denoised latents. Decoding these gives a much better
+    /// preview of the final image than decoding the noisy `latents`.
+    pub pred_original_sample: &'a Tensor,
+}
+
+/// A single image to generate with `StableDiffusionPipeline::txt2img_bucketed`.
+#[derive(Debug, Clone)]
+pub struct GenerationRequest {
+    pub prompt: String,
+    /// The negative prompt, an empty one being used when `None`.
+    pub negative_prompt: Option<String>,
+    /// The image height in pixels, has to be divisible by 8.
+    pub height: i64,
+    /// The image width in pixels, has to be divisible by 8.
+    pub width: i64,
+}
+
+/// The filter used to resize an inpainting mask to the latent resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MaskDownsampling {
+    /// Picks a single pixel per latent, this results in blocky mask boundaries.
+    #[default]
+    Nearest,
+    Bilinear,
+    /// Averages the pixels covered by each latent so that partially masked latents get an
+    /// intermediate value.
+    Area,
+}
+
+impl MaskDownsampling {
+    /// Resizes a `(batch, 1, height, width)` mask to `(batch, 1, latent_height, latent_width)`.
+    pub fn apply(&self, mask: &Tensor, latent_height: i64, latent_width: i64) -> Tensor {
+        let size = [latent_height, latent_width];
+        match self {
+            Self::Nearest => mask.upsample_nearest2d(size, None, None),
+            Self::Bilinear => mask.upsample_bilinear2d(size, false, None, None),
+            Self::Area => mask.adaptive_avg_pool2d(size),
+        }
+    }
+}
+
+/// How the latents of the first pass are upscaled in `StableDiffusionPipeline::txt2img_hires`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LatentUpscaleMethod {
+    Nearest,
+    #[default]
+    Bilinear,
+    Bicubic,
+    /// Decodes the latents, resizes the image with `preprocess::resize_for_sd` and encodes it
+    /// back. This is slower but avoids the blurriness of interpolating the latents directly.
+    Image,
+}
+
+/// The second pass of `StableDiffusionPipeline::txt2img_hires`.
+#[derive(Debug, Clone)]
+pub struct HiresFixConfig {
+    /// The width in pixels of the final image, divisible by 8.
+    pub width: i64,
+    /// The height in pixels of the final image, divisible by 8.
+    pub height: i64,
+    /// The fraction in `(0, 1]` of the denoising process run again on the upscaled latents.
+    pub strength: f64,
+    pub upscale_method: LatentUpscaleMethod,
+}
+
+impl Default for HiresFixConfig {
+    fn default() -> Self {
+        Self { width: 1024, height: 1024, strength: 0.7, upscale_method: Default::default() }
+    }
+}
+
+/// The tokenizer and the models needed to run Stable Diffusion, loaded once and reused for
+/// each generation.
+///
+/// The generation methods only read the model weights, so a pipeline can be put in an `Arc`
+/// and shared between threads serving concurrent requests, see the `stable-diffusion-pool`
+/// example. Note that libtorch gradient mode and autocast are thread-local: the methods below
+/// disable gradient tracking themselves, but autocast has to be enabled by each worker thread
+/// via `tch::autocast`.
+pub struct StableDiffusionPipeline {
+    pub config: StableDiffusionConfig,
+    pub text_encoder: clip::TextEncoder,
+    /// `None` for pipelines built with `new_latent_only`.
+    pub vae: Option<vae::AutoEncoderKL>,
+    /// Used by `decode` in place of the vae decoder when set, see `use_consistency_decoder`.
+    pub consistency_decoder: Option<vae::ConsistencyDecoder>,
+    /// Used by `decode_rgba`, see `use_transparent_decoder`.
+    pub transparent_decoder: Option<vae::TransparentDecoder>,
+    pub unet: unet_2d::UNet2DConditionModel,
+    pub vae_device: Device,
+    pub unet_device: Device,
+    unet_weights: String,
+    embedding_cache: Option<std::sync::Mutex<EmbeddingCache>>,
+    vae_autocast: bool,
+    latent_clamp: Option<f64>,
+    cfg_order: CfgOrder,
+}
+
+// SAFETY: `Tensor` is not `Sync` as tch cannot prevent in-place operations through shared
+// references, the pipeline fields holding tensors are only read concurrently:
+// - `text_encoder`, `vae`, `consistency_decoder`, `transparent_decoder`, and `unet` hold the
+//   model weights, the methods taking `&self` only use them as inputs of forward passes, which
+//   libtorch supports running concurrently on the same tensors, and never modify them in place.
+//   The attention processors installed on the unet are required to be `Send + Sync`.
+// - `embedding_cache` is behind a mutex, and both the tensors returned by the cache and the
+//   ones inserted in it are deep copies, so a tensor stored in the cache is never shared with
+//   a caller.
+// The other fields do not hold any tensor and are `Sync`.
+unsafe impl Sync for StableDiffusionPipeline {}
+
+impl StableDiffusionPipeline {
+    pub fn new(
+        config: StableDiffusionConfig,
+        vocab_file: &str,
+        clip_weights: &str,
+        vae_weights: &str,
+        unet_weights: &str,
+        unet_in_channels: i64,
+        device_setup: &DeviceSetup,
+    ) -> anyhow::Result<Self> {
+        Self::build(
+            config,
+            vocab_file,
+            clip_weights,
+            Some(vae_weights),
+            unet_weights,
+            unet_in_channels,
+            device_setup,
+        )
+    }
+
+    /// Builds a pipeline without loading the vae, e.g. to generate latents that get decoded
+    /// elsewhere or to upscale them in latent space. The methods that encode or decode images
+    /// return an error on such a pipeline, `txt2img_latents` and the other latent-only methods
+    /// are available, and a vae can be added later on with `replace_vae`.
+    pub fn new_latent_only(
+        config: StableDiffusionConfig,
+        vocab_file: &str,
+        clip_weights: &str,
+        unet_weights: &str,
+        unet_in_channels: i64,
+        device_setup: &DeviceSetup,
+    ) -> anyhow::Result<Self> {
+        Self::build(
+            config,
+            vocab_file,
+            clip_weights,
+            None,
+            unet_weights,
+            unet_in_channels,
+            device_setup,
+        )
+    }
+
+    fn build(
+        config: StableDiffusionConfig,
+        vocab_file: &str,
+        clip_weights: &str,
+        vae_weights: Option<&str>,
+        unet_weights: &str,
+        unet_in_channels: i64,
+        device_setup: &DeviceSetup,
+    ) -> anyhow::Result<Self> {
+        check_clip_for_unet(&config)?;
+        let clip_device = device_setup.get("clip");
+        let vae_device = device_setup.get("vae");
+        let unet_device = device_setup.get("unet");
+        let tokenizer = clip::Tokenizer::create(vocab_file, &config.clip)?;
+        // Only move the token embedding table to the cpu when explicitly requested.
+        let token_embedding_device = match device_setup.get("clip-token-embedding") {
+            Device::Cpu => Device::Cpu,
+            _ => clip_device,
+        };
+        let text_model = config.build_clip_transformer_with_token_embedding(
+            clip_weights,
+            clip_device,
+            token_embedding_device,
+            config.clip_kind,
+        )?;
+        let text_encoder = clip::TextEncoder::new(tokenizer, text_model, clip_device);
+        let vae = match vae_weights {
+            Some(vae_weights) => {
+                Some(config.build_vae_with_kind(vae_weights, vae_device, config.vae_kind)?)
+            }
+            None => None,
+        };
+        let mut unet = config.build_unet(unet_weights, unet_device, unet_in_channels)?;
+        if let Some(vae) = &vae {
+            check_vae_for_unet(vae, &unet)?;
+        }
+        // Only has an effect on the forward passes run with a positive `pag_scale`.
+        unet.enable_perturbed_attention(&["mid_block"]);
+        Ok(Self {
+            config,
+            text_encoder,
+            vae,
+            consistency_decoder: None,
+            transparent_decoder: None,
+            unet,
+            vae_device,
+            unet_device,
+            unet_weights: unet_weights.to_string(),
+            embedding_cache: None,
+            vae_autocast: true,
+            latent_clamp: None,
+            cfg_order: CfgOrder::UncondFirst,
+        })
+    }
+
+    /// Caches the text embeddings of the last `capacity` distinct prompts, including negative
+    /// prompts, so that repeated prompts do not have to go through the text encoder again. The
+    /// cache is cleared automatically when `config.clip` changes, `clear_embedding_cache` has to
+    /// be called after modifying the text encoder weights.
+    pub fn with_embedding_cache(mut self, capacity: usize) -> Self {
+        self.embedding_cache = Some(std::sync::Mutex::new(EmbeddingCache::new(capacity)));
+        self
+    }
+
+    /// Sets the number of threads used by libtorch for the operations run on the CPU, e.g. for
+    /// generations with `--cpu all`. More threads speed up each operation until the memory
+    /// bandwidth becomes the bottleneck, the number of physical cores being a good starting
+    /// point, while fewer threads leave some cores to other work such as concurrent
+    /// generations. This is a process wide libtorch setting that also applies to the other
+    /// pipelines.
+    pub fn with_num_threads(self, num_threads: usize) -> Self {
+        assert!(num_threads > 0, "the number of threads has to be positive");
+        tch::set_num_threads(num_threads as i32);
+        self
+    }
+
+    /// Makes two runs with the same settings produce bit-identical images on CUDA devices
+    /// rather than images differing slightly, e.g. for tests, at the cost of some speed, see
+    /// `utils::set_deterministic`. As for `with_num_threads`, this is a process wide setting.
+    pub fn with_deterministic(self, deterministic: bool) -> Self {
+        crate::utils::set_deterministic(deterministic);
+        self
+    }
+
+    /// Whether the vae encoder and decoder follow the autocast setting of the calling thread,
+    /// the default. When disabled, they always run with autocast disabled so that the unet can
+    /// still use autocast while the vae runs with the kind of its weights, `Kind::Float` by
+    /// default. Half precision vae passes tend to overflow, resulting in black images.
+    pub fn with_vae_autocast(mut self, vae_autocast: bool) -> Self {
+        self.vae_autocast = vae_autocast;
+        self
+    }
+
+    /// Clamps the latents to `[-latent_clamp, latent_clamp]` before decoding them, with the
+    /// NaN values replaced by 0, disabled by default. This is a safety valve for the scheduler
+    /// and guidance settings that occasionally make the latents diverge, the typical latents
+    /// magnitude being below 5 for the v1.5 and v2.1 models.
+    pub fn with_latent_clamp(mut self, latent_clamp: Option<f64>) -> Self {
+        self.latent_clamp = latent_clamp;
+        self
+    }
+
+    /// Sets the order of the negative and positive halves of the text embeddings produced and
+    /// consumed by the pipeline, `CfgOrder::UncondFirst` by default. This should match the
+    /// order of any precomputed embeddings passed to the `denoise` methods.
+    pub fn with_cfg_order(mut self, cfg_order: CfgOrder) -> Self {
+        self.cfg_order = cfg_order;
+        self
+    }
+
+    pub fn cfg_order(&self) -> CfgOrder {
+        self.cfg_order
+    }
+
+    /// Reorders a guidance batch built with the negative half first to `cfg_order`.
+    fn to_cfg_order(&self, uncond_first: &Tensor) -> Tensor {
+        let (uncond, cond) = CfgOrder::UncondFirst.split(uncond_first);
+        self.cfg_order.cat(&uncond, &cond)
+    }
+
+    /// Applies the `with_latent_clamp` setting to some latents about to be decoded.
+    fn clamp_latents(&self, latents: &Tensor) -> Tensor {
+        match self.latent_clamp {
+            None => latents.shallow_clone(),
+            Some(max) => latents.nan_to_num(0., max, -max).clamp(-max, max),
+        }
+    }
+
+    /// Runs the vae forward passes in `f` with the autocast setting chosen with
+    /// `with_vae_autocast`.
+    fn vae_stage<T>(&self, f: impl FnOnce() -> T) -> T {
+        if self.vae_autocast {
+            f()
+        } else {
+            tch::autocast(false, f)
+        }
+    }
+
+    pub fn clear_embedding_cache(&self) {
+        if let Some(cache) = &self.embedding_cache {
+            cache.lock().unwrap_or_else(|e| e.into_inner()).clear()
+        }
+    }
+
+    /// Same as `TextEncoder::encode_prompts_chunked` but going through the embedding cache
+    /// when enabled.
+    fn encode_prompts_chunked(&self, prompts: &[&str]) -> anyhow::Result<Tensor> {
+        let cache = match &self.embedding_cache {
+            None => return self.text_encoder.encode_prompts_chunked(prompts),
+            Some(cache) => cache,
+        };
+        let lock = || cache.lock().unwrap_or_else(|e| e.into_inner());
+        // The empty prompt is used to extend the embeddings of the shorter prompts.
+        let mut embeddings = Vec::with_capacity(prompts.len());
+        for &prompt in [""].iter().chain(prompts.iter()) {
+            let tokens = self.text_encoder.tokenizer.encode_chunks(prompt)?;
+            let cached = {
+                let mut cache = lock();
+                cache.check_config(&self.config.clip);
+                cache.get(&tokens)
+            };
+            let prompt_embeddings = match cached {
+                Some(prompt_embeddings) => prompt_embeddings,
+                None => {
+                    let prompt_embeddings = self.text_encoder.encode_token_chunks(&tokens)?;
+                    lock().insert(&tokens, &prompt_embeddings);
+                    prompt_embeddings
+                }
+            };
+            embeddings.push(prompt_embeddings)
+        }
+        let empty_embeddings = embeddings.remove(0);
+        let chunk_len = empty_embeddings.size()[1];
+        let seq_len = embeddings.iter().map(|e| e.size()[1]).max().unwrap_or(chunk_len);
+        let embeddings: Vec<Tensor> = embeddings
+            .into_iter()
+            .map(|e| {
+                let n_missing_chunks = (seq_len - e.size()[1]) / chunk_len;
+                if n_missing_chunks == 0 {
+                    e
+                } else {
+                    Tensor::cat(&[e, empty_embeddings.repeat([1, n_missing_chunks, 1])], 1)
+                }
+            })
+            .collect();
+        Ok(Tensor::cat(&embeddings, 0))
+    }
+
+    /// Replaces the vae with the one from `vae_weights`, e.g. a fine-tuned vae distributed
+    /// separately from the rest of the model. The new vae uses the same device and kind as the
+    /// current one and has to produce latents with the number of channels expected by the unet.
+    /// The vae architecture, including the latents scaling factor, is taken from
+    /// `config.autoencoder`. For a pipeline built with `new_latent_only`, the vae is added using
+    /// `config.vae_kind`.
+    pub fn replace_vae(&mut self, vae_weights: &str) -> anyhow::Result<()> {
+        let kind = self.vae.as_ref().map_or(self.config.vae_kind, |vae| vae.kind());
+        let vae = self.config.build_vae_with_kind(vae_weights, self.vae_device, kind)?;
+        check_vae_for_unet(&vae, &self.unet)?;
+        self.vae = Some(vae);
+        Ok(())
+    }
+
+    /// Decodes the latents with the consistency decoder from `decoder_weights` rather than with
+    /// the vae decoder, the vae is still used to encode images. The decoder runs on the vae
+    /// device with `config.vae_kind`, it is much larger and slower than the vae decoder as it
+    /// denoises the image in two unet steps, drawing some noise from the global generator.
+    pub fn use_consistency_decoder(&mut self, decoder_weights: &str) -> anyhow::Result<()> {
+        if self.unet.out_channels() != 4 {
+            anyhow::bail!("the consistency decoder only supports latents with 4 channels")
+        }
+        let decoder = self.config.build_consistency_decoder(
+            decoder_weights,
+            self.vae_device,
+            self.config.vae_kind,
+        )?;
+        self.consistency_decoder = Some(decoder);
+        Ok(())
+    }
+
+    /// Loads the LayerDiffuse transparent decoder from `decoder_weights` so that `decode_rgba`
+    /// can be used. It runs on the vae device with `config.vae_kind`.
+    pub fn use_transparent_decoder(&mut self, decoder_weights: &str) -> anyhow::Result<()> {
+        if self.unet.out_channels() != 4 {
+            anyhow::bail!("the transparent decoder only supports latents with 4 channels")
+        }
+        let decoder = self.config.build_transparent_decoder(
+            decoder_weights,
+            self.vae_device,
+            self.config.vae_kind,
+        )?;
+        self.transparent_decoder = Some(decoder);
+        Ok(())
+    }
+
+    /// The vae used to encode and decode images, this returns an error for pipelines built with
+    /// `new_latent_only`.
+    pub fn vae(&self) -> anyhow::Result<&vae::AutoEncoderKL> {
+        match &self.vae {
+            Some(vae) => Ok(vae),
+            None => anyhow::bail!("the pipeline was built with new_latent_only and has no vae"),
+        }
+    }
+
+    /// The hash of the unet weight file as returned by `models::weight_hash`, this is computed
+    /// on each call as this requires reading the whole file.
+    pub fn unet_hash(&self) -> anyhow::Result<String> {
+        crate::models::weight_hash(&self.unet_weights)
+    }
+
+    /// Runs a dummy forward pass through each of the models using the configured image size so
+    /// that the CUDA kernels get selected and the first actual generation does not have to pay
+    /// for it. This is meant to be called once at startup, with autocast set in the same way as
+    /// for the following generations.
+    pub fn warmup(&self) -> anyhow::Result<()> {
+        let text_embeddings = self.encode_prompt("", "")?;
+        let _no_grad_guard = tch::no_grad_guard();
+        let latents = Tensor::zeros(
+            [1, 4, self.config.height / 8, self.config.width / 8],
+            (Kind::Float, self.unet_device),
+        );
+        let latent_model_input = self.to_unet_input(&Tensor::cat(&[&latents, &latents], 0));
+        let _ = self.unet.forward(&latent_model_input, 1., &text_embeddings);
+        if self.vae.is_some() {
+            let _ = self.decode(&latents)?;
+        }
+        Ok(())
+    }
+
+    /// Moves a tensor to the unet device and converts it to the kind of the unet weights, this
+    /// applies to the latents, the text embeddings, and any additional conditioning.
+    pub fn to_unet_input(&self, xs: &Tensor) -> Tensor {
+        xs.to_device(self.unet_device).to_kind(self.unet.kind())
+    }
+
+    /// Moves a tensor to the vae device and converts it to the kind of the vae weights, or to
+    /// `Float` when the pipeline has no vae.
+    pub fn to_vae_input(&self, xs: &Tensor) -> Tensor {
+        let kind = self.vae.as_ref().map_or(Kind::Float, |vae| vae.kind());
+        xs.to_device(self.vae_device).to_kind(kind)
+    }
+
+    /// Returns the text embeddings for the negative prompt and the prompt, concatenated along
+    /// the batch dimension in the order given by `cfg_order`, on the unet device and with the
+    /// unet kind.
+    ///
+    /// Prompts that do not fit in the CLIP context, or that use the `BREAK` keyword, are split
+    /// in multiple chunks which embeddings are concatenated along the sequence dimension. The
+    /// prompt with the fewest chunks is extended with empty chunks so that both embeddings have
+    /// the same length.
+    pub fn encode_prompt(&self, prompt: &str, negative_prompt: &str) -> anyhow::Result<Tensor> {
+        let embeddings = self.encode_prompts_chunked(&[negative_prompt, prompt])?;
+        Ok(self.to_unet_input(&self.to_cfg_order(&embeddings)))
+    }
+
+    /// Same as `encode_prompt` for a batch of prompts, each with its own negative prompt, an
+    /// empty one being used when `None`. The result has shape `(2 * batch, seq_len, dim)`, each
+    /// half being in the same order as the prompts and the halves ordered by `cfg_order`, all
+    /// the sequences being extended to the chunk count of the longest one.
+    pub fn encode_prompt_pairs(
+        &self,
+        pairs: &[(String, Option<String>)],
+    ) -> anyhow::Result<Tensor> {
+        let negative_prompts = pairs.iter().map(|(_, negative)| negative.as_deref().unwrap_or(""));
+        let prompts = pairs.iter().map(|(prompt, _)| prompt.as_str());
+        let all_prompts: Vec<&str> = negative_prompts.chain(prompts).collect();
+        let embeddings = self.encode_prompts_chunked(&all_prompts)?;
+        Ok(self.to_unet_input(&self.to_cfg_order(&embeddings)))
+    }
+
+    /// Encodes each prompt and averages the embeddings using the given weights, e.g.
+    /// `[("a cat", 0.6), ("a dog", 0.4)]`. The weights are normalized to sum to 1 and the
+    /// prompts are extended to the same number of chunks, see
+    /// `TextEncoder::encode_prompts_chunked`. The result has shape `(1, seq_len, dim)`.
+    pub fn blend_prompts(&self, prompts: &[(&str, f64)]) -> anyhow::Result<Tensor> {
+        let weights = normalized_weights(prompts)?;
+        let texts: Vec<&str> = prompts.iter().map(|(prompt, _)| *prompt).collect();
+        let embeddings = self.encode_prompts_chunked(&texts)?;
+        Ok(self.to_unet_input(&blend_embeddings(&embeddings, &weights)))
+    }
+
+    /// Same as `encode_prompt` but the positive embeddings are a blend of the given prompts,
+    /// see `blend_prompts`.
+    pub fn encode_blended_prompt(
+        &self,
+        prompts: &[(&str, f64)],
+        negative_prompt: &str,
+    ) -> anyhow::Result<Tensor> {
+        let weights = normalized_weights(prompts)?;
+        let texts: Vec<&str> =
+            std::iter::once(negative_prompt).chain(prompts.iter().map(|(p, _)| *p)).collect();
+        let embeddings = self.encode_prompts_chunked(&texts)?;
+        let negative_embeddings = embeddings.narrow(0, 0, 1).to_kind(Kind::Float);
+        let embeddings = blend_embeddings(&embeddings.narrow(0, 1, prompts.len() as i64), &weights);
+        Ok(self.to_unet_input(&self.cfg_order.cat(&negative_embeddings, &embeddings)))
+    }
+
+    /// Panics with a message naming the tensor if `xs` is not on the unet device, rather than
+    /// letting libtorch fail later on with a device mismatch error.
+    fn check_unet_device(&self, name: &str, xs: &Tensor) {
+        assert_eq!(
+            xs.device(),
+            self.unet_device,
+            "{name} are on {:?} but the unet runs on {:?}",
+            xs.device(),
+            self.unet_device
+        )
+    }
+
+    /// Runs the denoising loop with classifier free guidance starting from `latents`, only the
+    /// timesteps selected by `denoising_start` and `denoising_end` are run. The scheduler
+    /// computations are done with the kind of the input latents whatever the unet kind. The
+    /// latents and conditioning have to be on the unet device, the text embeddings are moved
+    /// there if needed.
+    pub fn denoise(
+        &self,
+        latents: Tensor,
+        text_embeddings: &Tensor,
+        scheduler: &ddim::DDIMScheduler,
+        sampling_config: &SamplingConfig,
+    ) -> Tensor {
+        self.denoise_with_conditioning(latents, text_embeddings, scheduler, sampling_config, None)
+    }
+
+    /// Same as `denoise` but `conditioning` is concatenated to the scaled latents along the
+    /// channel dimension before each unet call, e.g. the mask and masked image latents for the
+    /// inpainting models. It should have a batch size of 1.
+    pub fn denoise_with_conditioning(
+        &self,
+        latents: Tensor,
+        text_embeddings: &Tensor,
+        scheduler: &ddim::DDIMScheduler,
+        sampling_config: &SamplingConfig,
+        conditioning: Option<&Tensor>,
+    ) -> Tensor {
+        self.denoise_with_callback(
+            latents,
+            text_embeddings,
+            scheduler,
+            sampling_config,
+            conditioning,
+            &mut |_| (),
+        )
+    }
+
+    /// Same as `denoise_with_conditioning` but `callback` is called at the end of each step,
+    /// e.g. to display a preview by decoding the predicted original sample.
+    ///
+    /// This panics if the `nan_check` of `sampling_config` fails, use
+    /// `try_denoise_with_callback` to get an error instead.
+    pub fn denoise_with_callback(
+        &self,
+        latents: Tensor,
+        text_embeddings: &Tensor,
+        scheduler: &ddim::DDIMScheduler,
+        sampling_config: &SamplingConfig,
+        conditioning: Option<&Tensor>,
+        callback: &mut dyn FnMut(&StepInfo),
+    ) -> Tensor {
+        self.try_denoise_with_callback(
+            latents,
+            text_embeddings,
+            scheduler,
+            sampling_config,
+            conditioning,
+            callback,
+        )
+        .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Same as `denoise_with_callback` but returns an error when the `nan_check` of
+    /// `sampling_config` fails.
+    pub fn try_denoise_with_callback(
+        &self,
+        latents: Tensor,
+        text_embeddings: &Tensor,
+        scheduler: &ddim::DDIMScheduler,
+        sampling_config: &SamplingConfig,
+        conditioning: Option<&Tensor>,
+        callback: &mut dyn FnMut(&StepInfo),
+    ) -> anyhow::Result<Tensor> {
+        self.try_denoise_with_embedding_schedule(
+            latents,
+            &[(0, text_embeddings.shallow_clone())],
+            scheduler,
+            sampling_config,
+            conditioning,
+            callback,
+        )
+    }
+
+    /// Same as `try_denoise_with_callback` with the text embeddings changing over the steps,
+    /// each embeddings being used from the given step index of the full schedule onwards, the
+    /// first ones having to start at step 0. See `parse_prompt_schedule`.
+    pub fn try_denoise_with_embedding_schedule(
+        &self,
+        mut latents: Tensor,
+        embedding_schedule: &[(usize, Tensor)],
+        scheduler: &ddim::DDIMScheduler,
+        sampling_config: &SamplingConfig,
+        conditioning: Option<&Tensor>,
+        callback: &mut dyn FnMut(&StepInfo),
+    ) -> anyhow::Result<Tensor> {
+        match embedding_schedule.first() {
+            Some((0, _)) => (),
+            _ => anyhow::bail!("the embedding schedule has to start at step 0"),
+        }
+        self.check_unet_device("latents", &latents);
+        if let Some(conditioning) = conditioning {
+            self.check_unet_device("conditioning latents", conditioning);
+        }
+        let _no_grad_guard = tch::no_grad_guard();
+        let guidance_scale = sampling_config.guidance_scale;
+        let do_cfg = guidance_scale > 1.;
+        let embedding_schedule: Vec<(usize, Tensor)> = embedding_schedule
+            .iter()
+            .map(|(start, text_embeddings)| {
+                (
+                    *start,
+                    self.to_unet_input(&guidance_embeddings(
+                        text_embeddings,
+                        guidance_scale,
+                        self.cfg_order,
+                    )),
+                )
+            })
+            .collect();
+        if do_cfg {
+            let bsize = latents.size()[0];
+            for (_, text_embeddings) in embedding_schedule.iter() {
+                assert_eq!(
+                    text_embeddings.size()[0],
+                    2 * bsize,
+                    "the text embeddings should have the negative and positive halves for each of \
+                     the {bsize} latents, in the order given by cfg_order"
+                )
+            }
+        }
+        let conditioning = conditioning.map(|c| {
+            let c = if do_cfg { Tensor::cat(&[c, c], 0) } else { c.shallow_clone() };
+            c.to_kind(latents.kind())
+        });
+        let timesteps = scheduler.timesteps();
+        let range = sampling_config.timestep_range(timesteps, scheduler.config.train_timesteps)?;
+        let (n_steps, first_step) = (range.len(), range.start);
+        let start = std::time::Instant::now();
+        for (step, &timestep) in timesteps[range].iter().enumerate() {
+            log::debug!("step {}/{n_steps}, timestep {timestep}", step + 1);
+            let (_, text_embeddings) = embedding_schedule
+                .iter()
+                .rev()
+                .find(|(start, _)| *start <= first_step + step)
+                .unwrap();
+            let latent_model_input = if do_cfg {
+                Tensor::cat(&[&latents, &latents], 0)
+            } else {
+                latents.shallow_clone()
+            };
+            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep);
+            let latent_model_input = match &conditioning {
+                None => latent_model_input,
+                Some(c) => Tensor::cat(&[&latent_model_input, c], 1),
+            };
+            let latent_model_input = self.to_unet_input(&latent_model_input);
+            let noise_pred =
+                self.unet.forward(&latent_model_input, timestep as f64, text_embeddings);
+            let noise_pred = self
+                .check_noise_pred(noise_pred, sampling_config.nan_check, step, timestep, || {
+                    self.unet.forward(&latent_model_input, timestep as f64, text_embeddings)
+                })?
+                .to_kind(latents.kind());
+            let (noise_pred_text, mut noise_pred) = if do_cfg {
+                let (noise_pred_uncond, noise_pred_text) = self.cfg_order.split(&noise_pred);
+                let guided =
+                    &noise_pred_uncond + (&noise_pred_text - &noise_pred_uncond) * guidance_scale;
+                (noise_pred_text, guided)
+            } else {
+                (noise_pred.shallow_clone(), noise_pred)
+            };
+            if sampling_config.pag_scale > 0. {
+                let cond_half = |xs: &Tensor| {
+                    if do_cfg {
+                        self.cfg_order.split(xs).1
+                    } else {
+                        xs.shallow_clone()
+                    }
+                };
+                let (latent_model_input, text_embeddings) =
+                    (cond_half(&latent_model_input), cond_half(text_embeddings));
+                let noise_pred_perturbed = attention::with_perturbed_attention(|| {
+                    self.unet.forward(&latent_model_input, timestep as f64, &text_embeddings)
+                })
+                .to_kind(latents.kind());
+                noise_pred += (noise_pred_text - noise_pred_perturbed) * sampling_config.pag_scale;
+            }
+            let output = scheduler_step(
+                scheduler,
+                &noise_pred,
+                timestep,
+                &latents,
+                &sampling_config.step_generator(step),
+            );
+            latents = output.prev_sample;
+            callback(&StepInfo {
+                step,
+                timestep,
+                latents: &latents,
+                pred_original_sample: &output.pred_original_sample,
+            });
+        }
+        log::info!("ran {n_steps} denoising steps in {:.2?}", start.elapsed());
+        Ok(latents)
+    }
+
+    /// Applies `nan_check` to the unet prediction of a step, `forward` running the unet again
+    /// for `NanCheck::RetryInFloat`.
+    fn check_noise_pred(
+        &self,
+        noise_pred: Tensor,
+        nan_check: NanCheck,
+        step: usize,
+        timestep: usize,
+        forward: impl FnOnce() -> Tensor,
+    ) -> anyhow::Result<Tensor> {
+        let is_finite = |xs: &Tensor| bool::try_from(xs.isfinite().all()).unwrap_or(false);
+        if nan_check == NanCheck::Disabled || is_finite(&noise_pred) {
+            return Ok(noise_pred);
+        }
+        let error = |details: &str| {
+            anyhow::anyhow!(
+                "the unet predicted NaN or infinite values at step {step}, timestep {timestep}, \
+                 this is usually caused by an overflow in half precision{details}"
+            )
+        };
+        if nan_check == NanCheck::Error {
+            return Err(error(""));
+        }
+        if self.unet.kind() != Kind::Float {
+            return Err(error(", retrying requires single precision unet weights"));
+        }
+        log::warn!("NaN or infinite unet prediction at step {step}, retrying in single precision");
+        let noise_pred = tch::autocast(false, forward);
+        if is_finite(&noise_pred) {
+            Ok(noise_pred)
+        } else {
+            Err(error(", the retry in single precision failed too"))
+        }
+    }
+
+    /// Same as `denoise` but using MultiDiffusion: at each step the unet is run over
+    /// overlapping windows of the latents and the noise predictions are averaged where the
+    /// windows overlap. This allows generating coherent images larger than the training
+    /// resolution, e.g. wide panoramas.
+    pub fn denoise_multi_diffusion(
+        &self,
+        mut latents: Tensor,
+        text_embeddings: &Tensor,
+        scheduler: &ddim::DDIMScheduler,
+        sampling_config: &SamplingConfig,
+        multi_diffusion_config: &MultiDiffusionConfig,
+    ) -> anyhow::Result<Tensor> {
+        self.check_unet_device("latents", &latents);
+        let _no_grad_guard = tch::no_grad_guard();
+        let guidance_scale = sampling_config.guidance_scale;
+        let do_cfg = guidance_scale > 1.;
+        let text_embeddings = self.to_unet_input(&guidance_embeddings(
+            text_embeddings,
+            guidance_scale,
+            self.cfg_order,
+        ));
+        let (_, _, height, width) = latents.size4().unwrap();
+        let MultiDiffusionConfig { window_size, stride } = *multi_diffusion_config;
+        assert!(
+            window_size > 0 && stride > 0,
+            "invalid multi-diffusion config {multi_diffusion_config:?}"
+        );
+        let (window_height, window_width) = (window_size.min(height), window_size.min(width));
+        let ys = window_offsets(height, window_height, stride);
+        let xs = window_offsets(width, window_width, stride);
+        let timesteps = scheduler.timesteps();
+        let range = sampling_config.timestep_range(timesteps, scheduler.config.train_timesteps)?;
+        for (step, &timestep) in timesteps[range].iter().enumerate() {
+            log::debug!("multi-diffusion step, timestep {timestep}");
+            let noise_sum = latents.zeros_like();
+            let count = Tensor::zeros([1, 1, height, width], (latents.kind(), latents.device()));
+            for &y in ys.iter() {
+                for &x in xs.iter() {
+                    let crop = latents.narrow(2, y, window_height).narrow(3, x, window_width);
+                    let latent_model_input =
+                        if do_cfg { Tensor::cat(&[&crop, &crop], 0) } else { crop };
+                    let latent_model_input =
+                        scheduler.scale_model_input(latent_model_input, timestep);
+                    let latent_model_input = self.to_unet_input(&latent_model_input);
+                    let noise_pred = self
+                        .unet
+                        .forward(&latent_model_input, timestep as f64, &text_embeddings)
+                        .to_kind(latents.kind());
+                    let noise_pred = if do_cfg {
+                        let (noise_pred_uncond, noise_pred_text) =
+                            self.cfg_order.split(&noise_pred);
+                        &noise_pred_uncond + (noise_pred_text - &noise_pred_uncond) * guidance_scale
+                    } else {
+                        noise_pred
+                    };
+                    let mut noise_view =
+                        noise_sum.narrow(2, y, window_height).narrow(3, x, window_width);
+                    noise_view += noise_pred;
+                    let mut count_view =
+                        count.narrow(2, y, window_height).narrow(3, x, window_width);
+                    count_view += 1.;
+                }
+            }
+            latents = scheduler_step(
+                scheduler,
+                &(noise_sum / count),
+                timestep,
+                &latents,
+                &sampling_config.step_generator(step),
+            )
+            .prev_sample;
+        }
+        Ok(latents)
+    }
+
+    /// Decodes some latents to an image tensor of kind `Uint8` on the CPU, this returns an error
+    /// if the pipeline has neither a vae nor a consistency decoder.
+    pub fn decode(&self, latents: &Tensor) -> anyhow::Result<Tensor> {
+        self.decode_with_format(latents, OutputFormat::U8)
+    }
+
+    /// Same as `decode` but returns the image in the given format, see `OutputFormat`.
+    pub fn decode_with_format(
+        &self,
+        latents: &Tensor,
+        format: OutputFormat,
+    ) -> anyhow::Result<Tensor> {
+        let _no_grad_guard = tch::no_grad_guard();
+        let image = match &self.consistency_decoder {
+            Some(decoder) => {
+                let latents = self.clamp_latents(latents) / decoder.config.scaling_factor;
+                let latents = latents.to_device(self.vae_device);
+                self.vae_stage(|| decoder.decode(&latents))
+            }
+            None => {
+                let vae = self.vae()?;
+                let latents = self.to_vae_input(&vae.unscale_latents(&self.clamp_latents(latents)));
+                self.vae_stage(|| vae.decode(&latents))
+            }
+        };
+        Ok(postprocess_image(&image, format))
+    }
+
+    /// Same as `decode` but returns an RGBA image of kind `Uint8` with shape
+    /// `(batch, 4, height, width)`, the alpha channel being estimated by the transparent decoder
+    /// from the vae decoder output, see `use_transparent_decoder`. The latents should come from
+    /// a unet trained for LayerDiffuse latent transparency, the alpha channel is meaningless
+    /// otherwise.
+    pub fn decode_rgba(&self, latents: &Tensor) -> anyhow::Result<Tensor> {
+        let vae = self.vae()?;
+        let decoder = match &self.transparent_decoder {
+            Some(decoder) => decoder,
+            None => anyhow::bail!("no transparent decoder, see use_transparent_decoder"),
+        };
+        let _no_grad_guard = tch::no_grad_guard();
+        let latents = self.to_vae_input(&vae.unscale_latents(&self.clamp_latents(latents)));
+        let alpha_rgb = self.vae_stage(|| decoder.decode(&vae.decode(&latents), &latents));
+        let rgba = Tensor::cat(&[alpha_rgb.narrow(1, 1, 3), alpha_rgb.narrow(1, 0, 1)], 1);
+        Ok((rgba.to_kind(Kind::Float).to_device(Device::Cpu) * 255.).round().to_kind(Kind::Uint8))
+    }
+
+    /// Same as `decode` for a vae with `mask_condition` set, e.g. the asymmetric autoencoder,
+    /// `image` and `mask` use the same format as for `inpaint`. The decoder uses the original
+    /// image outside of the mask which avoids color shifts at the mask boundary.
+    pub fn decode_with_mask(
+        &self,
+        latents: &Tensor,
+        image: &Tensor,
+        mask: &Tensor,
+    ) -> anyhow::Result<Tensor> {
+        let vae = self.vae()?;
+        let _no_grad_guard = tch::no_grad_guard();
+        let latents = self.to_vae_input(&vae.unscale_latents(&self.clamp_latents(latents)));
+        let image = self.to_vae_input(&(as_batch(image).to_kind(Kind::Float) / 255. * 2. - 1.));
+        let mask = self.to_vae_input(&as_batch(mask));
+        let image = self.vae_stage(|| vae.decode_with_mask(&latents, &image, &mask));
+        Ok(postprocess_image(&image, OutputFormat::U8))
+    }
+
+    /// Same as `decode` but writes the image to the PNG file `path` band by band so that the
+    /// full decoded image is never held in memory, e.g. for very large canvases. The latents
+    /// should have a batch size of 1 and are decoded in horizontal bands of `band_height`
+    /// latent rows. Each band is decoded with `overlap` additional latent rows above and below
+    /// which are then cropped, this hides most of the seams as the decoder output at a given
+    /// row depends on its neighbouring rows.
+    pub fn decode_to_png_streaming<P: AsRef<std::path::Path>>(
+        &self,
+        latents: &Tensor,
+        path: P,
+        band_height: i64,
+        overlap: i64,
+    ) -> anyhow::Result<()> {
+        let height = match latents.size().as_slice() {
+            &[1, _, height, _] => height,
+            shape => anyhow::bail!("expected latents with shape [1, c, h, w], got {shape:?}"),
+        };
+        if band_height <= 0 || overlap < 0 {
+            anyhow::bail!("invalid band height {band_height} or overlap {overlap}")
+        }
+        let mut png = None;
+        for start in (0..height).step_by(band_height as usize) {
+            let end = (start + band_height).min(height);
+            let (context_start, context_end) =
+                ((start - overlap).max(0), (end + overlap).min(height));
+            let band = latents.narrow(2, context_start, context_end - context_start);
+            let image = self.decode(&band)?.get(0);
+            let (_, image_height, image_width) = image.size3()?;
+            let scale = image_height / (context_end - context_start);
+            let rows = image.narrow(1, (start - context_start) * scale, (end - start) * scale);
+            if png.is_none() {
+                let file = std::io::BufWriter::new(std::fs::File::create(path.as_ref())?);
+                let writer =
+                    crate::utils::PngStreamWriter::new(file, image_width, height * scale, 3, 8)?;
+                png = Some(writer)
+            }
+            png.as_mut().unwrap().write_rows(&rows)?
+        }
+        if let Some(png) = png {
+            png.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Generates an image from a text prompt, the returned tensor has shape
+    /// `(1, 3, height, width)` and kind `Uint8`.
+    pub fn txt2img(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        let latents = self.txt2img_latents(prompt, negative_prompt, sampling_config)?;
+        self.decode(&latents)
+    }
+
+    /// Same as `txt2img` but returns the images as `image::RgbImage` values, see
+    /// `utils::to_rgb_images`.
+    #[cfg(feature = "image")]
+    pub fn txt2img_image(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Vec<image::RgbImage>> {
+        let images = self.txt2img(prompt, negative_prompt, sampling_config)?;
+        crate::utils::to_rgb_images(&images)
+    }
+
+    /// Same as `txt2img` but starts from the given noise rather than from noise drawn using
+    /// `seed`, e.g. to reproduce a generation from noise generated in python. The noise should
+    /// follow a standard normal distribution and have shape `(batch, 4, height / 8, width / 8)`
+    /// for the configured image size, one image is generated per batch element.
+    pub fn txt2img_with_noise(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+        noise: &Tensor,
+    ) -> anyhow::Result<Tensor> {
+        let (height, width) = (self.config.height / 8, self.config.width / 8);
+        let bsize = match noise.size().as_slice() {
+            &[bsize, 4, h, w] if h == height && w == width => bsize,
+            shape => anyhow::bail!(
+                "expected noise with shape [batch, 4, {height}, {width}], got {shape:?}"
+            ),
+        };
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self.encode_prompt(prompt, negative_prompt)?;
+        let text_embeddings = if bsize > 1 {
+            let (uncond, cond) = self.cfg_order.split(&text_embeddings);
+            self.cfg_order.cat(&uncond.repeat([bsize, 1, 1]), &cond.repeat([bsize, 1, 1]))
+        } else {
+            text_embeddings
+        };
+        let noise = noise.to_device(self.unet_device).to_kind(Kind::Float);
+        let latents = self.txt2img_latents_from_noise(
+            &text_embeddings,
+            &noise,
+            sampling_config,
+            &mut |_| (),
+        )?;
+        self.decode(&latents)
+    }
+
+    /// Same as `txt2img` but using precomputed text embeddings rather than prompts, e.g. cached
+    /// from a previous run or computed with a custom weighting of the tokens. The embeddings
+    /// have shape `(1, seq_len, cross_attention_dim)`, with the same `seq_len` for both. When
+    /// `negative_prompt_embeds` is `None`, the embeddings of the empty prompt are used, repeated
+    /// along the sequence dimension for chunked prompt embeddings.
+    pub fn txt2img_with_embeddings(
+        &self,
+        prompt_embeds: &Tensor,
+        negative_prompt_embeds: Option<&Tensor>,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        let seq_len = self.check_prompt_embeds("prompt_embeds", prompt_embeds)?;
+        let negative_prompt_embeds = match negative_prompt_embeds {
+            Some(negative_prompt_embeds) => negative_prompt_embeds.shallow_clone(),
+            None => {
+                let empty = self.encode_prompts_chunked(&[""])?;
+                let chunk_len = empty.size()[1];
+                if seq_len % chunk_len == 0 {
+                    empty.repeat([1, seq_len / chunk_len, 1])
+                } else {
+                    empty
+                }
+            }
+        };
+        let negative_seq_len =
+            self.check_prompt_embeds("negative_prompt_embeds", &negative_prompt_embeds)?;
+        if negative_seq_len != seq_len {
+            anyhow::bail!(
+                "prompt_embeds has a sequence length of {seq_len} but negative_prompt_embeds \
+                 has a sequence length of {negative_seq_len}"
+            )
+        }
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self
+            .cfg_order
+            .cat(&self.to_unet_input(&negative_prompt_embeds), &self.to_unet_input(prompt_embeds));
+        let latents =
+            self.txt2img_latents_from_embeddings(&text_embeddings, sampling_config, &mut |_| ())?;
+        self.decode(&latents)
+    }
+
+    /// Same as `txt2img` but using the text embeddings returned by `encode_prompt`, so that a
+    /// constant prompt only goes through the text encoder once when generating many images,
+    /// e.g. the frames of an animation which only differ by their `SamplingConfig` seeds.
+    pub fn txt2img_with_encoded_prompt(
+        &self,
+        text_embeddings: &Tensor,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        let cross_attention_dim = self.config.unet.cross_attention_dim;
+        match text_embeddings.size().as_slice() {
+            &[2, _, dim] if dim == cross_attention_dim => (),
+            shape => anyhow::bail!(
+                "expected text embeddings with shape [2, seq_len, {cross_attention_dim}], got \
+                 {shape:?}"
+            ),
+        }
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self.to_unet_input(text_embeddings);
+        let latents =
+            self.txt2img_latents_from_embeddings(&text_embeddings, sampling_config, &mut |_| ())?;
+        self.decode(&latents)
+    }
+
+    /// Checks that some precomputed text embeddings can be used as the unet encoder hidden
+    /// states and returns their sequence length.
+    fn check_prompt_embeds(&self, name: &str, xs: &Tensor) -> anyhow::Result<i64> {
+        let cross_attention_dim = self.config.unet.cross_attention_dim;
+        match xs.size().as_slice() {
+            &[1, seq_len, dim] if dim == cross_attention_dim => Ok(seq_len),
+            shape => anyhow::bail!(
+                "expected {name} with shape [1, seq_len, {cross_attention_dim}], got {shape:?}"
+            ),
+        }
+    }
+
+    /// Same as `txt2img` with the colors of the generated image biased towards the ones of
+    /// `color_reference`, an image with values in `[0, 255]` resized to the configured size if
+    /// needed. The reference is encoded and blurred so that only its low frequencies remain,
+    /// which are then added to the initial noise scaled by `strength`, values around 0.5 give a
+    /// noticeable palette shift while leaving the composition to the prompt.
+    pub fn txt2img_with_color_reference(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+        color_reference: &Tensor,
+        strength: f64,
+    ) -> anyhow::Result<Tensor> {
+        // The blur standard deviation, in latent pixels.
+        const COLOR_REFERENCE_BLUR: f64 = 4.;
+        let vae = self.vae()?;
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self.encode_prompt(prompt, negative_prompt)?;
+        let (height, width) = (self.config.height, self.config.width);
+        let reference = crate::preprocess::resize_for_sd(
+            &as_batch(color_reference).to_kind(Kind::Float),
+            width,
+            height,
+            crate::preprocess::ResizeMode::Stretch,
+        );
+        let reference = self.to_vae_input(&(reference / 255. * 2. - 1.));
+        let _no_grad_guard = tch::no_grad_guard();
+        let (noise, reference_latents) = {
+            let _lock = SEED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let noise =
+                sampling_config.initial_noise(&[1, 4, height / 8, width / 8], self.unet_device);
+            let reference_dist = self.vae_stage(|| vae.encode(&reference));
+            let reference_latents = vae.scale_latents(&reference_dist.sample());
+            (noise, reference_latents)
+        };
+        let reference_latents = reference_latents.to_device(self.unet_device).to_kind(Kind::Float);
+        let low_frequencies = crate::utils::gaussian_blur(&reference_latents, COLOR_REFERENCE_BLUR);
+        let noise = noise + low_frequencies * strength;
+        let latents = self.txt2img_latents_from_noise(
+            &text_embeddings,
+            &noise,
+            sampling_config,
+            &mut |_| (),
+        )?;
+        self.decode(&latents)
+    }
+
+    /// Same as `txt2img` with the prompt embeddings being a weighted blend of several prompts,
+    /// see `blend_prompts`.
+    pub fn txt2img_blend(
+        &self,
+        prompts: &[(&str, f64)],
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self.encode_blended_prompt(prompts, negative_prompt)?;
+        let latents =
+            self.txt2img_latents_from_embeddings(&text_embeddings, sampling_config, &mut |_| ())?;
+        self.decode(&latents)
+    }
+
+    /// Generates one image per (prompt, negative prompt) pair in a single batch, the returned
+    /// tensor has shape `(batch, 3, height, width)` and kind `Uint8`. The initial noise for the
+    /// whole batch is drawn from the configured seed.
+    pub fn txt2img_batch(
+        &self,
+        pairs: &[(String, Option<String>)],
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        let (height, width) = (self.config.height, self.config.width);
+        self.txt2img_batch_with_size(pairs, height, width, sampling_config)
+    }
+
+    fn txt2img_batch_with_size(
+        &self,
+        pairs: &[(String, Option<String>)],
+        height: i64,
+        width: i64,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        if pairs.is_empty() {
+            anyhow::bail!("txt2img_batch requires at least one prompt")
+        }
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self.encode_prompt_pairs(pairs)?;
+        let scheduler = self.config.build_scheduler(sampling_config.n_steps);
+        let latents = {
+            let _lock = SEED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            sampling_config
+                .initial_noise(&[pairs.len() as i64, 4, height / 8, width / 8], self.unet_device)
+        };
+        let latents = latents * scheduler.init_noise_sigma();
+        let latents = self.try_denoise_with_callback(
+            latents,
+            &text_embeddings,
+            &scheduler,
+            sampling_config,
+            None,
+            &mut |_| (),
+        )?;
+        self.decode(&latents)
+    }
+
+    /// Generates the images for requests with possibly different sizes. The requests are
+    /// grouped by size and each group is generated as a single batch with `txt2img_batch`, the
+    /// groups being run in order of first appearance. The images are returned in the order of
+    /// the requests, each with shape `(1, 3, height, width)` and kind `Uint8`.
+    ///
+    /// The initial noise of each group is drawn from the configured seed so an image depends
+    /// on the other requests of its group.
+    pub fn txt2img_bucketed(
+        &self,
+        requests: &[GenerationRequest],
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Vec<Tensor>> {
+        let mut buckets: Vec<((i64, i64), Vec<usize>)> = vec![];
+        for (index, request) in requests.iter().enumerate() {
+            let (height, width) = (request.height, request.width);
+            if height <= 0 || width <= 0 || height % 8 != 0 || width % 8 != 0 {
+                anyhow::bail!(
+                    "the image dimensions have to be positive multiples of 8, got {height}x{width}"
+                )
+            }
+            match buckets.iter_mut().find(|(size, _)| *size == (height, width)) {
+                Some((_, indexes)) => indexes.push(index),
+                None => buckets.push(((height, width), vec![index])),
+            }
+        }
+        let mut images: Vec<Option<Tensor>> = requests.iter().map(|_| None).collect();
+        for ((height, width), indexes) in buckets {
+            let pairs: Vec<_> = indexes
+                .iter()
+                .map(|&i| (requests[i].prompt.clone(), requests[i].negative_prompt.clone()))
+                .collect();
+            let batch = self.txt2img_batch_with_size(&pairs, height, width, sampling_config)?;
+            for (batch_index, image) in indexes.into_iter().zip(batch.split(1, 0)) {
+                images[batch_index] = Some(image)
+            }
+        }
+        Ok(images.into_iter().flatten().collect())
+    }
+
+    /// Same as `txt2img` but `callback` is called at the end of each denoising step, see
+    /// `StepInfo`.
+    pub fn txt2img_with_callback(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+        callback: &mut dyn FnMut(&StepInfo),
+    ) -> anyhow::Result<Tensor> {
+        let latents =
+            self.txt2img_latents_with_callback(prompt, negative_prompt, sampling_config, callback)?;
+        self.decode(&latents)
+    }
+
+    /// Same as `txt2img` but also returns the latents at the end of each of the steps listed in
+    /// `snapshot_steps`, as pairs of step index and latents ordered by step. Indexes past the
+    /// last step are ignored.
+    pub fn txt2img_with_snapshots(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+        snapshot_steps: &[usize],
+    ) -> anyhow::Result<(Tensor, Vec<(usize, Tensor)>)> {
+        let mut snapshots = vec![];
+        let image =
+            self.txt2img_with_callback(prompt, negative_prompt, sampling_config, &mut |info| {
+                if snapshot_steps.contains(&info.step) {
+                    snapshots.push((info.step, info.latents.copy()))
+                }
+            })?;
+        Ok((image, snapshots))
+    }
+
+    /// Same as `txt2img_with_snapshots` but the snapshots are decoded by the vae, returning the
+    /// image at the end of each of the steps listed in `frame_steps`, e.g. to make a timelapse of
+    /// the denoising process. The latents are kept on the CPU during the generation and decoded
+    /// one frame at a time afterwards to limit the memory usage.
+    pub fn txt2img_with_frames(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+        frame_steps: &[usize],
+    ) -> anyhow::Result<(Tensor, Vec<(usize, Tensor)>)> {
+        // Fail before running the denoising loop when there is nothing to decode with.
+        self.vae()?;
+        let mut snapshots = vec![];
+        let latents = self.txt2img_latents_with_callback(
+            prompt,
+            negative_prompt,
+            sampling_config,
+            &mut |info| {
+                if frame_steps.contains(&info.step) {
+                    snapshots.push((info.step, info.latents.to_device(Device::Cpu)))
+                }
+            },
+        )?;
+        let image = self.decode(&latents)?;
+        let frames = snapshots
+            .into_iter()
+            .map(|(step, latents)| Ok((step, self.decode(&latents)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok((image, frames))
+    }
+
+    /// Same as `txt2img` but also returns what each step changes in the estimate of the final
+    /// image, e.g. to study the dynamics of the denoising process. The `pred_original_sample`
+    /// latents of all the steps are kept on the CPU and decoded one at a time after the
+    /// generation, the delta of a step being the difference between its decoded estimate and
+    /// the one of the previous step. The deltas are paired with their step index, starting from
+    /// 1, and are `Float` images with values nominally in `[-1, 1]`, 0 meaning no change.
+    pub fn txt2img_with_deltas(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<(Tensor, Vec<(usize, Tensor)>)> {
+        self.vae()?;
+        let mut estimates = vec![];
+        let latents = self.txt2img_latents_with_callback(
+            prompt,
+            negative_prompt,
+            sampling_config,
+            &mut |info| {
+                estimates.push((info.step, info.pred_original_sample.to_device(Device::Cpu)))
+            },
+        )?;
+        let image = self.decode(&latents)?;
+        let mut deltas = vec![];
+        let mut previous: Option<Tensor> = None;
+        for (step, estimate) in estimates {
+            let estimate = self.decode_with_format(&estimate, OutputFormat::F32)?;
+            if let Some(previous) = previous {
+                deltas.push((step, &estimate - previous))
+            }
+            previous = Some(estimate)
+        }
+        Ok((image, deltas))
+    }
+
+    /// Same as `txt2img` but returns the latents rather than the decoded image. With
+    /// `denoising_end` set, these are only partially denoised and can be passed to
+    /// `resume_latents` on a refiner pipeline.
+    pub fn txt2img_latents(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        self.txt2img_latents_with_callback(prompt, negative_prompt, sampling_config, &mut |_| ())
+    }
+
+    fn txt2img_latents_with_callback(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+        callback: &mut dyn FnMut(&StepInfo),
+    ) -> anyhow::Result<Tensor> {
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let prompt_schedule = parse_prompt_schedule(prompt, sampling_config.n_steps);
+        if let [(_, prompt)] = prompt_schedule.as_slice() {
+            let text_embeddings = self.encode_prompt(prompt, negative_prompt)?;
+            return self.txt2img_latents_from_embeddings(
+                &text_embeddings,
+                sampling_config,
+                callback,
+            );
+        }
+        let embedding_schedule = prompt_schedule
+            .iter()
+            .map(|(start, prompt)| {
+                log::debug!("using the prompt \"{prompt}\" from step {start}");
+                Ok((*start, self.encode_prompt(prompt, negative_prompt)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let noise = {
+            let _lock = SEED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            sampling_config.initial_noise(
+                &[1, 4, self.config.height / 8, self.config.width / 8],
+                self.unet_device,
+            )
+        };
+        let scheduler = self.config.build_scheduler(sampling_config.n_steps);
+        self.try_denoise_with_embedding_schedule(
+            noise * scheduler.init_noise_sigma(),
+            &embedding_schedule,
+            &scheduler,
+            sampling_config,
+            None,
+            callback,
+        )
+    }
+
+    /// The generation loop starting from the concatenated negative and positive text
+    /// embeddings, `n_steps` should have been checked beforehand.
+    fn txt2img_latents_from_embeddings(
+        &self,
+        text_embeddings: &Tensor,
+        sampling_config: &SamplingConfig,
+        callback: &mut dyn FnMut(&StepInfo),
+    ) -> anyhow::Result<Tensor> {
+        let noise = {
+            // The random generator is global so seeding and drawing have to be done atomically
+            // for concurrent generations to be reproducible.
+            let _lock = SEED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            sampling_config.initial_noise(
+                &[1, 4, self.config.height / 8, self.config.width / 8],
+                self.unet_device,
+            )
+        };
+        self.txt2img_latents_from_noise(text_embeddings, &noise, sampling_config, callback)
+    }
+
+    /// Same as `txt2img_latents_from_embeddings` starting from the given unscaled noise.
+    fn txt2img_latents_from_noise(
+        &self,
+        text_embeddings: &Tensor,
+        noise: &Tensor,
+        sampling_config: &SamplingConfig,
+        callback: &mut dyn FnMut(&StepInfo),
+    ) -> anyhow::Result<Tensor> {
+        let scheduler = self.config.build_scheduler(sampling_config.n_steps);
+        // scale the initial noise by the standard deviation required by the scheduler
+        let latents = noise * scheduler.init_noise_sigma();
+        self.try_denoise_with_callback(
+            latents,
+            text_embeddings,
+            &scheduler,
+            sampling_config,
+            None,
+            callback,
+        )
+    }
+
+    /// Finishes the denoising of some partially denoised latents, `denoising_start` should be
+    /// set to the `denoising_end` value used to generate them. Returns the final latents.
+    pub fn resume_latents(
+        &self,
+        latents: &Tensor,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self.encode_prompt(prompt, negative_prompt)?;
+        let scheduler = self.config.build_scheduler(sampling_config.n_steps);
+        let latents = latents.to_device(self.unet_device).to_kind(Kind::Float);
+        self.try_denoise_with_callback(
+            latents,
+            &text_embeddings,
+            &scheduler,
+            sampling_config,
+            None,
+            &mut |_| (),
+        )
+    }
+
+    /// Generates an image at the configured size and upscales it to the `hires_config` size,
+    /// the upscaled latents are noised and partially denoised again so that details get added
+    /// at the higher resolution. This avoids the duplicated subjects that appear when directly
+    /// generating images much larger than the training resolution.
+    pub fn txt2img_hires(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+        hires_config: &HiresFixConfig,
+    ) -> anyhow::Result<Tensor> {
+        let HiresFixConfig { width, height, strength, upscale_method } = *hires_config;
+        if height % 8 != 0 || width % 8 != 0 {
+            anyhow::bail!("the image dimensions have to be divisible by 8, got {height}x{width}")
+        }
+        if !(strength > 0. && strength <= 1.) {
+            anyhow::bail!("the hires-fix strength has to be in (0, 1], got {strength}")
+        }
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self.encode_prompt(prompt, negative_prompt)?;
+        let latents =
+            self.txt2img_latents_from_embeddings(&text_embeddings, sampling_config, &mut |_| ())?;
+        let hires_sampling_config = SamplingConfig {
+            denoising_start: Some(1. - strength),
+            denoising_end: None,
+            ..sampling_config.clone()
+        };
+        let scheduler = self.config.build_scheduler(hires_sampling_config.n_steps);
+        let timesteps = scheduler.timesteps();
+        let range =
+            hires_sampling_config.timestep_range(timesteps, scheduler.config.train_timesteps)?;
+        let _no_grad_guard = tch::no_grad_guard();
+        let size = [height / 8, width / 8];
+        let latents = {
+            let _lock = SEED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let latents = match upscale_method {
+                LatentUpscaleMethod::Nearest => latents.upsample_nearest2d(size, None, None),
+                LatentUpscaleMethod::Bilinear => {
+                    latents.upsample_bilinear2d(size, false, None, None)
+                }
+                LatentUpscaleMethod::Bicubic => latents.upsample_bicubic2d(size, false, None, None),
+                LatentUpscaleMethod::Image => {
+                    let vae = self.vae()?;
+                    let vae_latents =
+                        self.to_vae_input(&vae.unscale_latents(&self.clamp_latents(&latents)));
+                    let image = self.vae_stage(|| vae.decode(&vae_latents));
+                    let image = crate::preprocess::resize_for_sd(
+                        &image,
+                        width,
+                        height,
+                        crate::preprocess::ResizeMode::Stretch,
+                    );
+                    // The sampling of the latent distribution uses the global random generator.
+                    tch::manual_seed(sampling_config.seed);
+                    vae.scale_latents(&self.vae_stage(|| vae.encode(&image)).sample())
+                }
+            };
+            let latents = latents.to_device(self.unet_device).to_kind(Kind::Float);
+            match timesteps.get(range.start) {
+                None => latents,
+                Some(&timestep) => {
+                    let noise =
+                        hires_sampling_config.initial_noise(&latents.size(), self.unet_device);
+                    scheduler.add_noise(&latents, noise, timestep)
+                }
+            }
+        };
+        let latents = self.try_denoise_with_callback(
+            latents,
+            &text_embeddings,
+            &scheduler,
+            &hires_sampling_config,
+            None,
+            &mut |_| (),
+        )?;
+        self.decode(&latents)
+    }
+
+    /// Generates an image of the given size, in pixels, from a text prompt using
+    /// `denoise_multi_diffusion`, the configured image size is not used. The dimensions have to
+    /// be divisible by 8, they can be much larger than the training resolution, e.g. 2048x512
+    /// for a panorama.
+    pub fn txt2img_panorama(
+        &self,
+        prompt: &str,
+        negative_prompt: &str,
+        width: i64,
+        height: i64,
+        sampling_config: &SamplingConfig,
+        multi_diffusion_config: &MultiDiffusionConfig,
+    ) -> anyhow::Result<Tensor> {
+        if height % 8 != 0 || width % 8 != 0 {
+            anyhow::bail!("the image dimensions have to be divisible by 8, got {height}x{width}")
+        }
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self.encode_prompt(prompt, negative_prompt)?;
+        let scheduler = self.config.build_scheduler(sampling_config.n_steps);
+        let latents = {
+            let _lock = SEED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            sampling_config.initial_noise(&[1, 4, height / 8, width / 8], self.unet_device)
+        };
+        let latents = latents * scheduler.init_noise_sigma();
+        let latents = self.denoise_multi_diffusion(
+            latents,
+            &text_embeddings,
+            &scheduler,
+            sampling_config,
+            multi_diffusion_config,
+        )?;
+        self.decode(&latents)
+    }
+
+    /// Repaints the region of `image` selected by `mask`, this requires a unet with 9 input
+    /// channels such as the stable-diffusion inpainting models.
+    ///
+    /// `image` is a `Uint8` tensor of shape `(3, height, width)`, as returned by
+    /// `tch::vision::image::load`, or `(1, 3, height, width)`, with dimensions that are multiples
+    /// of 8. The image size is used rather than the configured one. `mask` has shape
+    /// `(1, height, width)` or `(1, 1, height, width)` with values in `[0, 1]`, 1 meaning that
+    /// the pixel is repainted. The generated image is blended with the original one using the
+    /// mask so that the pixels outside of it are preserved exactly. Returns a `Uint8` image of
+    /// shape `(1, 3, height, width)` on the CPU.
+    ///
+    /// With `inpaint_crop_padding` set in `sampling_config`, only the region around the mask is
+    /// generated, this region being aligned on multiples of 8 pixels.
+    pub fn inpaint(
+        &self,
+        image: &Tensor,
+        mask: &Tensor,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        let in_channels = self.unet.in_channels();
+        if in_channels != 9 {
+            anyhow::bail!("inpainting requires a unet with 9 input channels, got {in_channels}")
+        }
+        let vae = self.vae()?;
+        let image = as_batch(image).to_device(Device::Cpu).to_kind(Kind::Float);
+        let mask = as_batch(mask).to_device(Device::Cpu).to_kind(Kind::Float);
+        let (_, _, height, width) = image.size4()?;
+        if height % 8 != 0 || width % 8 != 0 {
+            anyhow::bail!("the image dimensions have to be divisible by 8, got {height}x{width}")
+        }
+        if mask.size() != [1, 1, height, width] {
+            anyhow::bail!("unexpected mask shape {:?} for a {height}x{width} image", mask.size())
+        }
+        if let Some(padding) = sampling_config.inpaint_crop_padding {
+            if padding < 0 {
+                anyhow::bail!("the inpainting crop padding cannot be negative, got {padding}")
+            }
+            let output = image.round().to_kind(Kind::Uint8);
+            let Some((top, left, crop_height, crop_width)) = mask_bounding_box(&mask, padding)?
+            else {
+                // Nothing to repaint.
+                return Ok(output);
+            };
+            let crop = |xs: &Tensor| xs.narrow(2, top, crop_height).narrow(3, left, crop_width);
+            let sampling_config =
+                SamplingConfig { inpaint_crop_padding: None, ..sampling_config.clone() };
+            let generated = self.inpaint(
+                &crop(&output),
+                &crop(&mask),
+                prompt,
+                negative_prompt,
+                &sampling_config,
+            )?;
+            crop(&output).copy_(&generated);
+            return Ok(output);
+        }
+        check_inference_steps(sampling_config.n_steps, self.config.scheduler.train_timesteps)?;
+        let text_embeddings = self.encode_prompt(prompt, negative_prompt)?;
+        let scheduler = self.config.build_scheduler(sampling_config.n_steps);
+        let _no_grad_guard = tch::no_grad_guard();
+        let masked_image = (&image / 255. * 2. - 1.) * (1. - &mask);
+        let (latents, masked_image_latents) = {
+            // Both the initial noise and the masked image latents sampling use the global
+            // random generator, see `txt2img_latents`.
+            let _lock = SEED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let latents =
+                sampling_config.initial_noise(&[1, 4, height / 8, width / 8], self.unet_device);
+            let masked_image = self.to_vae_input(&masked_image);
+            let masked_image_dist = self.vae_stage(|| vae.encode(&masked_image));
+            (latents, vae.scale_latents(&masked_image_dist.sample()))
+        };
+        let latent_mask = sampling_config.mask_downsampling.apply(&mask, height / 8, width / 8);
+        let conditioning = Tensor::cat(
+            &[
+                latent_mask.to_device(self.unet_device),
+                masked_image_latents.to_device(self.unet_device).to_kind(Kind::Float),
+            ],
+            1,
+        );
+        let latents = latents * scheduler.init_noise_sigma();
+        let latents = self.try_denoise_with_callback(
+            latents,
+            &text_embeddings,
+            &scheduler,
+            sampling_config,
+            Some(&conditioning),
+            &mut |_| (),
+        )?;
+        let generated = if vae.config.mask_condition {
+            self.decode_with_mask(&latents, &image, &mask)?
+        } else {
+            self.decode(&latents)?
+        };
+        let generated = generated.to_kind(Kind::Float);
+        let image: Tensor = image * (1. - &mask) + generated * &mask;
+        Ok(image.round().to_kind(Kind::Uint8))
+    }
+
+    /// Extends the canvas of `image` by `padding` and generates the new border region using
+    /// `inpaint`, see there for the expected image format. The original pixels are preserved
+    /// exactly and the returned image has the size of the extended canvas.
+    ///
+    /// The border is initialized by replicating the edge pixels so that the masked image gives
+    /// the model some context on the colors to continue. As the unet works best with latent
+    /// dimensions that are multiples of 8, the canvas is further extended on the bottom and
+    /// right sides to multiples of 64 pixels during the generation, this extra region being
+    /// cropped from the result.
+    pub fn outpaint(
+        &self,
+        image: &Tensor,
+        padding: OutpaintPadding,
+        prompt: &str,
+        negative_prompt: &str,
+        sampling_config: &SamplingConfig,
+    ) -> anyhow::Result<Tensor> {
+        let OutpaintPadding { top, bottom, left, right } = padding;
+        if top < 0 || bottom < 0 || left < 0 || right < 0 {
+            anyhow::bail!("the outpainting padding cannot be negative, got {padding:?}")
+        }
+        let image = as_batch(image).to_device(Device::Cpu).to_kind(Kind::Float);
+        let (_, _, height, width) = image.size4()?;
+        let (out_height, out_width) = (height + top + bottom, width + left + right);
+        let align = |x: i64| (x + 63) / 64 * 64;
+        let (canvas_height, canvas_width) = (align(out_height), align(out_width));
+        let canvas = image.replication_pad2d([
+            left,
+            right + canvas_width - out_width,
+            top,
+            bottom + canvas_height - out_height,
+        ]);
+        let mask = Tensor::ones([1, 1, canvas_height, canvas_width], (Kind::Float, Device::Cpu));
+        let _ = mask.narrow(2, top, height).narrow(3, left, width).fill_(0.);
+        let canvas = canvas.to_kind(Kind::Uint8);
+        let image = self.inpaint(&canvas, &mask, prompt, negative_prompt, sampling_config)?;
+        Ok(image.narrow(2, 0, out_height).narrow(3, 0, out_width).contiguous())
+    }
+}
+
+/// The windows used by `StableDiffusionPipeline::denoise_multi_diffusion`, both values are
+/// in latent pixels, i.e. 1/8th of the image pixels.
+#[derive(Debug, Clone, Copy)]
+pub struct MultiDiffusionConfig {
+    /// The size of the square windows, this should be close to the training resolution.
+    pub window_size: i64,
+    /// The offset between consecutive windows, smaller values give smoother transitions at the
+    /// cost of more unet evaluations.
+    pub stride: i64,
+}
+
+impl Default for MultiDiffusionConfig {
+    fn default() -> Self {
+        Self { window_size: 64, stride: 8 }
+    }
+}
+
+// The offsets of the windows along a dimension, the last window is aligned with the end of the
+// dimension so that all the positions are covered.
+fn window_offsets(size: i64, window_size: i64, stride: i64) -> Vec<i64> {
+    let mut offsets: Vec<i64> = (0..=size - window_size).step_by(stride as usize).collect();
+    if offsets.last() != Some(&(size - window_size)) {
+        offsets.push(size - window_size)
+    }
+    offsets
+}
+
+/// The number of pixels added on each side of the image when outpainting, see
+/// `StableDiffusionPipeline::outpaint`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutpaintPadding {
+    pub top: i64,
+    pub bottom: i64,
+    pub left: i64,
+    pub right: i64,
+}
+
+// The `(top, left, height, width)` region covering the non-zero values of a `(1, 1, height,
+// width)` mask extended by `padding` on each side, and aligned on multiples of 8 within the
+// mask bounds. Returns `None` for an empty mask.
+fn mask_bounding_box(mask: &Tensor, padding: i64) -> anyhow::Result<Option<(i64, i64, i64, i64)>> {
+    let (_, _, height, width) = mask.size4()?;
+    let range = |dims: &[i64], size: i64| -> anyhow::Result<Option<(i64, i64)>> {
+        let masked = Vec::<f32>::try_from(mask.amax(dims, false).to_kind(Kind::Float))?;
+        let first = masked.iter().position(|&v| v > 0.);
+        let last = masked.iter().rposition(|&v| v > 0.);
+        let range = first.zip(last).map(|(first, last)| {
+            let start = (first as i64 - padding).max(0) / 8 * 8;
+            let end = ((last as i64 + 1 + padding + 7) / 8 * 8).min(size);
+            (start, end - start)
+        });
+        Ok(range)
+    };
+    let rows = range(&[0, 1, 3], height)?;
+    let columns = range(&[0, 1, 2], width)?;
+    Ok(rows.zip(columns).map(|((top, height), (left, width))| (top, left, height, width)))
+}
+
+// Adds a batch dimension to images of shape `(channels, height, width)`.
+fn as_batch(xs: &Tensor) -> Tensor {
+    if xs.dim() == 3 {
+        xs.unsqueeze(0)
+    } else {
+        xs.shallow_clone()
+    }
+}
+
+/// How `save_images` names the image files.
+#[derive(Debug, Clone)]
+pub struct NamingOptions {
+    /// The index of the first image, e.g. to save the images of a generation loop one at a time.
+    pub first_index: usize,
+    /// The total number of images, the index is only added to the file names when there is more
+    /// than one. The number of images passed to `save_images` is used when `None`.
+    pub count: Option<usize>,
+    /// A step index added after the image index, e.g. for the intermediary images.
+    pub step: Option<usize>,
+    /// Replaces the extension of the base path, this selects the file format.
+    pub extension: Option<String>,
+    /// The generation parameters stored in each PNG file, in the order of the images, see
+    /// `utils::save_image_with_metadata`. Images past the end of the list have no metadata.
+    pub metadata: Vec<String>,
+}
+
+impl Default for NamingOptions {
+    fn default() -> Self {
+        Self { first_index: 1, count: None, step: None, extension: None, metadata: vec![] }
+    }
+}
+
+impl NamingOptions {
+    /// The file name for the image `index` among `count`, e.g. `sd_final.png`, `sd_final.2.png`
+    /// for the second of several images, or `sd_final.2-10.png` with `step` set to 10. The
+    /// extension defaults to `png` when the base path has none.
+    pub fn filename(&self, base_path: &str, index: usize, count: usize) -> String {
+        let (stem, extension) = match base_path.rsplit_once('.') {
+            Some((stem, extension)) if !extension.contains('/') => (stem, extension),
+            _ => (base_path, "png"),
+        };
+        let extension = self.extension.as_deref().unwrap_or(extension);
+        let index = if count > 1 { format!(".{index}") } else { String::new() };
+        let step = self.step.map_or_else(String::new, |step| format!("-{step}"));
+        format!("{stem}{index}{step}.{extension}")
+    }
+}
+
+/// Saves some images, e.g. as returned by `StableDiffusionPipeline::txt2img_batch`, to one file
+/// each, named using `options`. The images can have a leading batch dimension which elements
+/// are saved separately. Images of kind `Int`, as returned with `OutputFormat::U16`, are saved
+/// as 16 bits PNG files without metadata. Returns the file names in the order of the images.
+pub fn save_images(
+    images: &[Tensor],
+    base_path: &str,
+    options: &NamingOptions,
+) -> anyhow::Result<Vec<String>> {
+    let images: Vec<Tensor> = images
+        .iter()
+        .flat_map(|image| {
+            let image = as_batch(image);
+            (0..image.size()[0]).map(move |index| image.get(index))
+        })
+        .collect();
+    let count = options.count.unwrap_or(images.len());
+    let mut filenames = Vec::with_capacity(images.len());
+    for (index, image) in images.iter().enumerate() {
+        let filename = options.filename(base_path, options.first_index + index, count);
+        match (image.kind(), options.metadata.get(index)) {
+            (Kind::Int, _) => {
+                if !filename.to_lowercase().ends_with(".png") {
+                    anyhow::bail!("16 bits images can only be saved as png, got {filename}")
+                }
+                crate::utils::save_image_u16(image, &filename)?
+            }
+            (_, Some(metadata)) => {
+                crate::utils::save_image_with_metadata(image, &filename, metadata)?
+            }
+            (_, None) => tch::vision::image::save(image, &filename)?,
+        }
+        filenames.push(filename)
+    }
+    Ok(filenames)
 }
+
+/// Saves some latents of shape `(batch, channels, height / 8, width / 8)`, e.g. as returned by
+/// `StableDiffusionPipeline::txt2img_latents`, so that the generation can be inspected and
+/// later resumed via `load_latents`. The format is selected by the extension of `path`:
+/// `.npz`, `.safetensors`, or the libtorch format otherwise, e.g. for `.ot` files.
+pub fn save_latents<P: AsRef<std::path::Path>>(latents: &Tensor, path: P) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let latents = latents.to_device(Device::Cpu);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("npz") => Tensor::write_npz(&[(LATENTS_NAME, &latents)], path)?,
+        Some("safetensors") => Tensor::write_safetensors(&[(LATENTS_NAME, &latents)], path)?,
+        _ => latents.save(path)?,
+    }
+    Ok(())
+}
+
+/// Loads some latents saved by `save_latents` on `device`. These can be passed to
+/// `StableDiffusionPipeline::resume_latents` to continue a partial generation, or to
+/// `StableDiffusionPipeline::decode` to get the images of a finished one.
+pub fn load_latents<P: AsRef<std::path::Path>>(path: P, device: Device) -> anyhow::Result<Tensor> {
+    let path = path.as_ref();
+    let tensors = match path.extension().and_then(|e| e.to_str()) {
+        Some("npz") => Tensor::read_npz(path)?,
+        Some("safetensors") => Tensor::read_safetensors(path)?,
+        _ => vec![(LATENTS_NAME.to_string(), Tensor::load(path)?)],
+    };
+    let latents = match tensors.into_iter().find(|(name, _)| name == LATENTS_NAME) {
+        Some((_, latents)) => latents,
+        None => anyhow::bail!("no {LATENTS_NAME} tensor in {path:?}"),
+    };
+    if latents.dim() != 4 {
+        anyhow::bail!("expected latents of shape (b, c, h, w), got {:?}", latents.size())
+    }
+    Ok(latents.to_device(device))
+}
+
+// The name of the tensor in the npz and safetensors latents files.
+const LATENTS_NAME: &str = "latents";
@@ -0,0 +1,92 @@
+//! # Image preprocessing
+//!
+//! Preparation of the input images and masks from tensors, independently of how they were
+//! loaded. Images are resized in the same way as the `VaeImageProcessor` from the python
+//! diffusers library, small differences in the resampling result in noticeably different
+//! img2img and inpainting outputs.
+use tch::{Kind, Tensor};
+
+/// How to fit an image to a target size with a different aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResizeMode {
+    /// Resize to the target size, ignoring the aspect ratio.
+    #[default]
+    Stretch,
+    /// Resize so that the image covers the target size while preserving the aspect ratio, and
+    /// crop the center of the result. This is the `crop` mode of diffusers.
+    CenterCrop,
+    /// Resize so that the image fits in the target size while preserving the aspect ratio, and
+    /// pad the border by replicating the edge pixels. This is the `fill` mode of diffusers.
+    Pad,
+}
+
+/// Resizes an image to `width`x`height` using an antialiased bicubic filter, matching the PIL
+/// resampling used by diffusers. The image can have shape `(channels, height, width)` or
+/// `(batch, channels, height, width)`, the result has the same kind as the input, `Uint8`
+/// images being rounded and clamped to `[0, 255]`.
+pub fn resize_for_sd(image: &Tensor, width: i64, height: i64, mode: ResizeMode) -> Tensor {
+    let unbatched = image.dim() == 3;
+    let xs = if unbatched { image.unsqueeze(0) } else { image.shallow_clone() };
+    let (_, _, src_height, src_width) = xs.size4().unwrap();
+    let kind = xs.kind();
+    let xs = xs.to_kind(Kind::Float);
+    // Whether the source image is wider than the target, comparing the aspect ratios without
+    // rounding.
+    let wider = src_width * height > src_height * width;
+    let xs = match mode {
+        ResizeMode::Stretch => bicubic(&xs, width, height),
+        ResizeMode::CenterCrop => {
+            let (w, h) = if wider {
+                (src_width * height / src_height, height)
+            } else {
+                (width, src_height * width / src_width)
+            };
+            let xs = bicubic(&xs, w, h);
+            xs.narrow(2, (h - height) / 2, height).narrow(3, (w - width) / 2, width)
+        }
+        ResizeMode::Pad => {
+            let (w, h) = if wider {
+                (width, src_height * width / src_width)
+            } else {
+                (src_width * height / src_height, height)
+            };
+            let xs = bicubic(&xs, w, h);
+            let (left, top) = ((width - w) / 2, (height - h) / 2);
+            xs.replication_pad2d([left, width - w - left, top, height - h - top])
+        }
+    };
+    let xs = match kind {
+        Kind::Uint8 => xs.round().clamp(0., 255.).to_kind(Kind::Uint8),
+        kind => xs.to_kind(kind),
+    };
+    if unbatched {
+        xs.squeeze_dim(0)
+    } else {
+        xs
+    }
+}
+
+fn bicubic(xs: &Tensor, width: i64, height: i64) -> Tensor {
+    crate::utils::with_mps_cpu_fallback(xs, |xs| {
+        xs.internal_upsample_bicubic2d_aa([height, width], false, None, None)
+    })
+}
+
+/// Prepares the inputs of the inpainting models from an image and a mask with values in
+/// `[0, 255]` and shapes `(channels, height, width)`, e.g. as returned by
+/// `tch::vision::image::load`. The mask channels are averaged and thresholded, masked pixels
+/// being the white ones, before applying a gaussian blur with standard deviation `mask_blur`,
+/// 0 to keep hard edges. Returns the mask with shape `(1, 1, height, width)` and values in
+/// `[0, 1]`, and the masked image with shape `(1, 3, height, width)` and values in `[-1, 1]`.
+pub fn prepare_mask_and_masked_image(
+    image: &Tensor,
+    mask: &Tensor,
+    mask_blur: f64,
+) -> (Tensor, Tensor) {
+    let image = image.to_kind(Kind::Float) / 255. * 2. - 1.;
+    let mask = mask.mean_dim(Some([0].as_slice()), true, Kind::Float);
+    let mask = mask.ge(122.5).totype(Kind::Float);
+    let mask = crate::utils::gaussian_blur(&mask.unsqueeze(0), mask_blur).squeeze_dim(0);
+    let masked_image: Tensor = image * (1 - &mask);
+    (mask.unsqueeze(0), masked_image.unsqueeze(0))
+}
@@ -0,0 +1,94 @@
+use super::{betas_for_alpha_bar, Generator};
+use tch::{Kind, Tensor};
+
+#[derive(Debug, Clone)]
+pub struct ConsistencyDecoderSchedulerConfig {
+    /// number of diffusion steps used to train the model.
+    pub train_timesteps: usize,
+    /// The standard deviation of the data distribution, used to precondition the model inputs
+    /// and outputs.
+    pub sigma_data: f64,
+    /// The source of the noise added between the steps.
+    pub generator: Generator,
+}
+
+impl Default for ConsistencyDecoderSchedulerConfig {
+    fn default() -> Self {
+        Self { train_timesteps: 1024, sigma_data: 0.5, generator: Generator::default() }
+    }
+}
+
+/// The few-step scheduler used by the consistency decoder from OpenAI, this follows the
+/// `ConsistencyDecoderScheduler` from python diffusers.
+/// https://github.com/openai/consistencydecoder
+#[derive(Debug, Clone)]
+pub struct ConsistencyDecoderScheduler {
+    timesteps: Vec<usize>,
+    sqrt_alphas_cumprod: Vec<f64>,
+    sqrt_one_minus_alphas_cumprod: Vec<f64>,
+    c_skip: Vec<f64>,
+    c_out: Vec<f64>,
+    c_in: Vec<f64>,
+    pub config: ConsistencyDecoderSchedulerConfig,
+}
+
+impl ConsistencyDecoderScheduler {
+    /// Only 2 inference steps are supported, the timesteps being the ones that the decoder was
+    /// distilled for.
+    pub fn new(inference_steps: usize, config: ConsistencyDecoderSchedulerConfig) -> Self {
+        assert!(
+            inference_steps == 2,
+            "the consistency decoder only supports 2 inference steps, got {inference_steps}"
+        );
+        assert!(config.train_timesteps > 1008, "the consistency decoder uses timestep 1008");
+        let betas = betas_for_alpha_bar(config.train_timesteps, 0.999);
+        let alphas: Tensor = 1. - betas;
+        let alphas_cumprod: Vec<f64> = alphas.cumprod(0, Kind::Double).try_into().unwrap();
+        let sigma_data2 = config.sigma_data * config.sigma_data;
+        let (mut c_skip, mut c_out, mut c_in) = (vec![], vec![], vec![]);
+        for &alpha_cumprod in alphas_cumprod.iter() {
+            let sigma2 = 1. / alpha_cumprod - 1.;
+            let sqrt_recip_alpha_cumprod = (1. / alpha_cumprod).sqrt();
+            c_skip.push(sqrt_recip_alpha_cumprod * sigma_data2 / (sigma2 + sigma_data2));
+            c_out.push(sigma2.sqrt() * config.sigma_data / (sigma2 + sigma_data2).sqrt());
+            c_in.push(sqrt_recip_alpha_cumprod / (sigma2 + sigma_data2).sqrt());
+        }
+        Self {
+            timesteps: vec![1008, 512],
+            sqrt_alphas_cumprod: alphas_cumprod.iter().map(|a| a.sqrt()).collect(),
+            sqrt_one_minus_alphas_cumprod: alphas_cumprod.iter().map(|a| (1. - a).sqrt()).collect(),
+            c_skip,
+            c_out,
+            c_in,
+            config,
+        }
+    }
+
+    pub fn timesteps(&self) -> &[usize] {
+        self.timesteps.as_slice()
+    }
+
+    /// standard deviation of the initial noise distribution
+    pub fn init_noise_sigma(&self) -> f64 {
+        self.sqrt_one_minus_alphas_cumprod[self.timesteps[0]]
+    }
+
+    pub fn scale_model_input(&self, sample: Tensor, timestep: usize) -> Tensor {
+        sample * self.c_in[timestep]
+    }
+
+    /// Computes the denoised sample from the model output and, except on the last step, noises
+    /// it again to the level of the next timestep.
+    pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
+        let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        let x_0 = model_output * self.c_out[timestep] + sample * self.c_skip[timestep];
+        match self.timesteps.get(step_index + 1) {
+            None => x_0,
+            Some(&next_timestep) => {
+                let noise = self.config.generator.randn_like_for_step(step_index, &x_0);
+                x_0 * self.sqrt_alphas_cumprod[next_timestep]
+                    + noise * self.sqrt_one_minus_alphas_cumprod[next_timestep]
+            }
+        }
+    }
+}
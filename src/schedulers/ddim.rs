@@ -7,7 +7,7 @@
 //!
 //! Denoising Diffusion Implicit Models, J. Song et al, 2020.
 //! https://arxiv.org/abs/2010.02502
-use super::{betas_for_alpha_bar, BetaSchedule, PredictionType};
+use super::{betas_for_alpha_bar, BetaSchedule, Generator, PredictionType, SchedulerOutput};
 use tch::{kind, Kind, Tensor};
 
 /// The configuration for the DDIM scheduler.
@@ -21,6 +21,8 @@ pub struct DDIMSchedulerConfig {
     pub beta_schedule: BetaSchedule,
     /// The amount of noise to be added at each step.
     pub eta: f64,
+    /// The source of the noise added at each step when `eta` is positive.
+    pub generator: Generator,
     /// Adjust the indexes of the inference schedule by this value.
     pub steps_offset: usize,
     /// prediction type of the scheduler function, one of `epsilon` (predicting
@@ -29,6 +31,15 @@ pub struct DDIMSchedulerConfig {
     pub prediction_type: PredictionType,
     /// number of diffusion steps used to train the model
     pub train_timesteps: usize,
+    /// Clip the predicted original sample to `[-clip_sample_range, clip_sample_range]` for
+    /// numerical stability, disabled by default as is the case for Stable Diffusion.
+    pub clip_sample: bool,
+    /// The maximum magnitude of the predicted original sample when `clip_sample` is set.
+    pub clip_sample_range: f64,
+    /// Whether the cumulative alpha following the first training timestep is 1, i.e. the last
+    /// step returns a fully denoised sample, rather than the cumulative alpha of timestep 0.
+    /// Stable Diffusion is trained with this disabled, which is the default.
+    pub set_alpha_to_one: bool,
 }
 
 impl Default for DDIMSchedulerConfig {
@@ -38,9 +49,13 @@ impl Default for DDIMSchedulerConfig {
             beta_end: 0.012f64,
             beta_schedule: BetaSchedule::ScaledLinear,
             eta: 0.,
+            generator: Generator::default(),
             steps_offset: 1,
             prediction_type: PredictionType::Epsilon,
             train_timesteps: 1000,
+            clip_sample: false,
+            clip_sample_range: 1.0,
+            set_alpha_to_one: false,
         }
     }
 }
@@ -55,12 +70,24 @@ pub struct DDIMScheduler {
     pub config: DDIMSchedulerConfig,
 }
 
-// clip_sample: False, set_alpha_to_one: False
+impl DDIMSchedulerConfig {
+    /// The stochastic DDIM sampler with `eta` set to 1, as the DDPM like ancestral sampling
+    /// offered by other tools. The noise is drawn on the CPU so that a given seed produces the
+    /// same images on all devices, the pipelines draw it using `SamplingConfig::generator`
+    /// instead. The other parameters are the Stable Diffusion defaults.
+    pub fn ancestral() -> Self {
+        Self { eta: 1., generator: Generator::cpu(), ..Default::default() }
+    }
+}
+
 impl DDIMScheduler {
     /// Creates a new DDIM scheduler given the number of steps to be
     /// used for inference as well as the number of steps that was used
     /// during training.
     pub fn new(inference_steps: usize, config: DDIMSchedulerConfig) -> Self {
+        if let Err(err) = super::check_inference_steps(inference_steps, config.train_timesteps) {
+            panic!("{err}")
+        }
         let step_ratio = config.train_timesteps / inference_steps;
         let timesteps: Vec<usize> =
             (0..(inference_steps)).map(|s| s * step_ratio + config.steps_offset).rev().collect();
@@ -95,14 +122,59 @@ impl DDIMScheduler {
         sample
     }
 
+    /// The timestep that follows `timestep` in the denoising schedule, i.e. the one whose
+    /// cumulative alpha is used for the sample returned by `step`. This is 0 for the last step,
+    /// `set_alpha_to_one` deciding which cumulative alpha is used then.
+    pub fn previous_timestep(&self, timestep: usize) -> usize {
+        // https://github.com/huggingface/diffusers/blob/6e099e2c8ce4c4f5c7318e970a8c093dc5c7046e/src/diffusers/schedulers/scheduling_ddim.py#L195
+        timestep.saturating_sub(self.step_ratio)
+    }
+
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop.
+    pub fn step_by_index(
+        &self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
+        let timestep = self.timesteps()[step_index];
+        self.step(model_output, timestep, sample)
+    }
+
     /// Performs a backward step during inference.
     pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
-        let timestep = if timestep >= self.alphas_cumprod.len() { timestep - 1 } else { timestep };
-        // https://github.com/huggingface/diffusers/blob/6e099e2c8ce4c4f5c7318e970a8c093dc5c7046e/src/diffusers/schedulers/scheduling_ddim.py#L195
-        let prev_timestep = if timestep > self.step_ratio { timestep - self.step_ratio } else { 0 };
+        self.step_with_output(model_output, timestep, sample).prev_sample
+    }
 
+    /// Same as `step` but also returns the predicted original sample.
+    pub fn step_with_output(
+        &self,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+    ) -> SchedulerOutput {
+        self.step_with_generator(model_output, timestep, sample, &self.config.generator)
+    }
+
+    /// Same as `step_with_output` but the noise added when `eta` is positive is drawn using
+    /// `generator` rather than `config.generator`, e.g. the `SamplingConfig::generator` of the
+    /// pipeline. Its seed policy gets the index of `timestep` in the schedule.
+    pub fn step_with_generator(
+        &self,
+        model_output: &Tensor,
+        timestep: usize,
+        sample: &Tensor,
+        generator: &Generator,
+    ) -> SchedulerOutput {
+        let step_index = self.timesteps.iter().position(|&t| t == timestep);
+        let timestep = if timestep >= self.alphas_cumprod.len() { timestep - 1 } else { timestep };
         let alpha_prod_t = self.alphas_cumprod[timestep];
-        let alpha_prod_t_prev = self.alphas_cumprod[prev_timestep];
+        let alpha_prod_t_prev = match timestep.checked_sub(self.step_ratio) {
+            Some(prev_timestep) => self.alphas_cumprod[prev_timestep],
+            None if self.config.set_alpha_to_one => 1.,
+            None => self.alphas_cumprod[0],
+        };
         let beta_prod_t = 1. - alpha_prod_t;
         let beta_prod_t_prev = 1. - alpha_prod_t_prev;
 
@@ -125,18 +197,29 @@ impl DDIMScheduler {
                 (pred_original_sample, pred_epsilon)
             }
         };
+        let pred_original_sample = if self.config.clip_sample {
+            let range = self.config.clip_sample_range;
+            pred_original_sample.clamp(-range, range)
+        } else {
+            pred_original_sample
+        };
 
         let variance = (beta_prod_t_prev / beta_prod_t) * (1. - alpha_prod_t / alpha_prod_t_prev);
         let std_dev_t = self.config.eta * variance.sqrt();
 
         let pred_sample_direction =
             (1. - alpha_prod_t_prev - std_dev_t * std_dev_t).sqrt() * pred_epsilon;
-        let prev_sample = alpha_prod_t_prev.sqrt() * pred_original_sample + pred_sample_direction;
-        if self.config.eta > 0. {
-            &prev_sample + Tensor::randn_like(&prev_sample) * std_dev_t
+        let prev_sample = alpha_prod_t_prev.sqrt() * &pred_original_sample + pred_sample_direction;
+        let prev_sample = if self.config.eta > 0. {
+            let noise = match step_index {
+                Some(step_index) => generator.randn_like_for_step(step_index, &prev_sample),
+                None => generator.randn_like(&prev_sample),
+            };
+            &prev_sample + noise * std_dev_t
         } else {
             prev_sample
-        }
+        };
+        SchedulerOutput { prev_sample, pred_original_sample }
     }
 
     pub fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Tensor {
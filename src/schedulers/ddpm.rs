@@ -1,4 +1,4 @@
-use super::{betas_for_alpha_bar, BetaSchedule, PredictionType};
+use super::{betas_for_alpha_bar, BetaSchedule, Generator, PredictionType};
 use tch::{kind, Kind, Tensor};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -32,6 +32,8 @@ pub struct DDPMSchedulerConfig {
     pub prediction_type: PredictionType,
     /// number of diffusion steps used to train the model.
     pub train_timesteps: usize,
+    /// The source of the noise added at each step.
+    pub generator: Generator,
 }
 
 impl Default for DDPMSchedulerConfig {
@@ -44,6 +46,7 @@ impl Default for DDPMSchedulerConfig {
             variance_type: DDPMVarianceType::FixedSmall,
             prediction_type: PredictionType::Epsilon,
             train_timesteps: 1000,
+            generator: Generator::default(),
         }
     }
 }
@@ -58,6 +61,9 @@ pub struct DDPMScheduler {
 
 impl DDPMScheduler {
     pub fn new(inference_steps: usize, config: DDPMSchedulerConfig) -> Self {
+        if let Err(err) = super::check_inference_steps(inference_steps, config.train_timesteps) {
+            panic!("{err}")
+        }
         let betas = match config.beta_schedule {
             BetaSchedule::ScaledLinear => Tensor::linspace(
                 config.beta_start.sqrt(),
@@ -90,11 +96,24 @@ impl DDPMScheduler {
         Self { alphas_cumprod, init_noise_sigma: 1.0, timesteps, step_ratio, config }
     }
 
+    /// The timestep that follows `timestep` in the denoising schedule, as for the other
+    /// schedulers this saturates at 0 for the last step. The sample returned by the last step is
+    /// fully denoised, i.e. uses a cumulative alpha of 1 rather than the one of timestep 0.
+    pub fn previous_timestep(&self, timestep: usize) -> usize {
+        timestep.saturating_sub(self.step_ratio)
+    }
+
+    fn alpha_prod_prev(&self, timestep: usize) -> f64 {
+        if timestep < self.step_ratio {
+            1.0
+        } else {
+            self.alphas_cumprod[self.previous_timestep(timestep)]
+        }
+    }
+
     fn get_variance(&self, timestep: usize) -> f64 {
-        let prev_t = timestep as isize - self.step_ratio as isize;
         let alpha_prod_t = self.alphas_cumprod[timestep];
-        let alpha_prod_t_prev =
-            if prev_t >= 0 { self.alphas_cumprod[prev_t as usize] } else { 1.0 };
+        let alpha_prod_t_prev = self.alpha_prod_prev(timestep);
         let current_beta_t = 1. - alpha_prod_t / alpha_prod_t_prev;
 
         // For t > 0, compute predicted variance βt (see formula (6) and (7) from https://arxiv.org/pdf/2006.11239.pdf)
@@ -126,14 +145,23 @@ impl DDPMScheduler {
         sample
     }
 
-    pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
-        let prev_t = timestep as isize - self.step_ratio as isize;
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop.
+    pub fn step_by_index(
+        &self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
+        let timestep = self.timesteps()[step_index];
+        self.step(model_output, timestep, sample)
+    }
 
+    pub fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
         // https://github.com/huggingface/diffusers/blob/df2b548e893ccb8a888467c2508756680df22821/src/diffusers/schedulers/scheduling_ddpm.py#L272
         // 1. compute alphas, betas
         let alpha_prod_t = self.alphas_cumprod[timestep];
-        let alpha_prod_t_prev =
-            if prev_t >= 0 { self.alphas_cumprod[prev_t as usize] } else { 1.0 };
+        let alpha_prod_t_prev = self.alpha_prod_prev(timestep);
         let beta_prod_t = 1. - alpha_prod_t;
         let beta_prod_t_prev = 1. - alpha_prod_t_prev;
         let current_alpha_t = alpha_prod_t / alpha_prod_t_prev;
@@ -169,7 +197,10 @@ impl DDPMScheduler {
         // 6. Add noise
         let mut variance = model_output.zeros_like();
         if timestep > 0 {
-            let variance_noise = model_output.randn_like();
+            // The step index is only used to derive the noise seed.
+            let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap_or(0);
+            let variance_noise =
+                self.config.generator.randn_like_for_step(step_index, model_output);
             if self.config.variance_type == DDPMVarianceType::FixedSmallLog {
                 variance = self.get_variance(timestep) * variance_noise;
             } else {
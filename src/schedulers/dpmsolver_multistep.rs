@@ -1,4 +1,4 @@
-use super::{betas_for_alpha_bar, BetaSchedule, PredictionType};
+use super::{betas_for_alpha_bar, BetaSchedule, Generator, PredictionType};
 use std::iter;
 use tch::{kind, Kind, Tensor};
 
@@ -11,6 +11,10 @@ pub enum DPMSolverAlgorithmType {
     DPMSolverPlusPlus,
     /// Implements the algorithms defined in <https://arxiv.org/abs/2206.00927>.
     DPMSolver,
+    /// The stochastic variant of DPM-Solver++ which adds some noise at each step, with
+    /// `solver_order: 2` this is the DPM++ 2M SDE sampler. Only the first and second orders are
+    /// supported.
+    SDEDPMSolverPlusPlus,
 }
 
 /// The solver type for the second-order solver.
@@ -56,6 +60,8 @@ pub struct DPMSolverMultistepSchedulerConfig {
     /// Whether to use lower-order solvers in the final steps. Only valid for < 15 inference steps. We empirically
     /// find this can stabilize the sampling of DPM-Solver for `steps < 15`, especially for steps <= 10.
     pub lower_order_final: bool,
+    /// The source of the noise added at each step by the SDE algorithm.
+    pub generator: Generator,
 }
 
 impl Default for DPMSolverMultistepSchedulerConfig {
@@ -73,6 +79,7 @@ impl Default for DPMSolverMultistepSchedulerConfig {
             algorithm_type: DPMSolverAlgorithmType::DPMSolverPlusPlus,
             solver_type: DPMSolverType::Midpoint,
             lower_order_final: true,
+            generator: Generator::default(),
         }
     }
 }
@@ -91,6 +98,12 @@ pub struct DPMSolverMultistepScheduler {
 
 impl DPMSolverMultistepScheduler {
     pub fn new(inference_steps: usize, config: DPMSolverMultistepSchedulerConfig) -> Self {
+        if let Err(err) = super::check_inference_steps(inference_steps, config.train_timesteps) {
+            panic!("{err}")
+        }
+        if config.algorithm_type == DPMSolverAlgorithmType::SDEDPMSolverPlusPlus {
+            assert!(config.solver_order <= 2, "SDE-DPMSolver++ only supports orders 1 and 2");
+        }
         let betas = match config.beta_schedule {
             BetaSchedule::ScaledLinear => Tensor::linspace(
                 config.beta_start.sqrt(),
@@ -155,7 +168,8 @@ impl DPMSolverMultistepScheduler {
         sample: &Tensor,
     ) -> Tensor {
         match self.config.algorithm_type {
-            DPMSolverAlgorithmType::DPMSolverPlusPlus => {
+            DPMSolverAlgorithmType::DPMSolverPlusPlus
+            | DPMSolverAlgorithmType::SDEDPMSolverPlusPlus => {
                 let mut x0_pred = match self.config.prediction_type {
                     PredictionType::Epsilon => {
                         let alpha_t = self.alpha_t[timestep];
@@ -221,6 +235,7 @@ impl DPMSolverMultistepScheduler {
         timestep: usize,
         prev_timestep: usize,
         sample: &Tensor,
+        step_index: usize,
     ) -> Tensor {
         let (lambda_t, lambda_s) = (self.lambda_t[prev_timestep], self.lambda_t[timestep]);
         let (alpha_t, alpha_s) = (self.alpha_t[prev_timestep], self.alpha_t[timestep]);
@@ -233,6 +248,12 @@ impl DPMSolverMultistepScheduler {
             DPMSolverAlgorithmType::DPMSolver => {
                 (alpha_t / alpha_s) * sample - (sigma_t * (h.exp() - 1.0)) * model_output
             }
+            DPMSolverAlgorithmType::SDEDPMSolverPlusPlus => {
+                let noise = self.config.generator.randn_like_for_step(step_index, sample);
+                (sigma_t / sigma_s * (-h).exp()) * sample
+                    + (alpha_t * (1.0 - (-2.0 * h).exp())) * model_output
+                    + sigma_t * (1.0 - (-2.0 * h).exp()).sqrt() * noise
+            }
         }
     }
 
@@ -243,6 +264,7 @@ impl DPMSolverMultistepScheduler {
         timestep_list: [usize; 2],
         prev_timestep: usize,
         sample: &Tensor,
+        step_index: usize,
     ) -> Tensor {
         let (t, s0, s1) = (
             prev_timestep,
@@ -286,6 +308,21 @@ impl DPMSolverMultistepScheduler {
                         - (sigma_t * ((h.exp() - 1.0) / h - 1.0)) * d1
                 }
             },
+            DPMSolverAlgorithmType::SDEDPMSolverPlusPlus => {
+                // https://github.com/huggingface/diffusers/blob/v0.21.0/src/diffusers/schedulers/scheduling_dpmsolver_multistep.py
+                let noise = self.config.generator.randn_like_for_step(step_index, sample);
+                let sample = (sigma_t / sigma_s0 * (-h).exp()) * sample
+                    + (alpha_t * (1.0 - (-2.0 * h).exp())) * d0;
+                let sample = match self.config.solver_type {
+                    DPMSolverType::Midpoint => {
+                        sample + 0.5 * (alpha_t * (1.0 - (-2.0 * h).exp())) * d1
+                    }
+                    DPMSolverType::Heun => {
+                        sample + (alpha_t * ((1.0 - (-2.0 * h).exp()) / (-2.0 * h) + 1.0)) * d1
+                    }
+                };
+                sample + sigma_t * (1.0 - (-2.0 * h).exp()).sqrt() * noise
+            }
         }
     }
 
@@ -332,6 +369,9 @@ impl DPMSolverMultistepScheduler {
                     - (sigma_t * ((h.exp() - 1.0) / h - 1.0)) * d1
                     - (sigma_t * ((h.exp() - 1.0 - h) / h.powi(2) - 0.5)) * d2
             }
+            DPMSolverAlgorithmType::SDEDPMSolverPlusPlus => {
+                unreachable!("SDE-DPMSolver++ does not support the third order")
+            }
         }
     }
 
@@ -346,9 +386,20 @@ impl DPMSolverMultistepScheduler {
     }
 
     pub fn step(&mut self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
-        // https://github.com/huggingface/diffusers/blob/e4fe9413121b78c4c1f109b50f0f3cc1c320a1a2/src/diffusers/schedulers/scheduling_dpmsolver_multistep.py#L457
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        self.step_by_index(model_output, step_index, sample)
+    }
 
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop, so that the noise schedule is looked up internally.
+    pub fn step_by_index(
+        &mut self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
+        // https://github.com/huggingface/diffusers/blob/e4fe9413121b78c4c1f109b50f0f3cc1c320a1a2/src/diffusers/schedulers/scheduling_dpmsolver_multistep.py#L457
+        let timestep = self.timesteps[step_index];
         let prev_timestep =
             if step_index == self.timesteps.len() - 1 { 0 } else { self.timesteps[step_index + 1] };
         let lower_order_final = (step_index == self.timesteps.len() - 1)
@@ -370,7 +421,13 @@ impl DPMSolverMultistepScheduler {
             || self.lower_order_nums < 1
             || lower_order_final
         {
-            self.dpm_solver_first_order_update(model_output, timestep, prev_timestep, sample)
+            self.dpm_solver_first_order_update(
+                model_output,
+                timestep,
+                prev_timestep,
+                sample,
+                step_index,
+            )
         } else if self.config.solver_order == 2 || self.lower_order_nums < 2 || lower_order_second {
             let timestep_list = [self.timesteps[step_index - 1], timestep];
             self.multistep_dpm_solver_second_order_update(
@@ -378,6 +435,7 @@ impl DPMSolverMultistepScheduler {
                 timestep_list,
                 prev_timestep,
                 sample,
+                step_index,
             )
         } else {
             let timestep_list =
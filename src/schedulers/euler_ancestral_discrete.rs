@@ -1,4 +1,4 @@
-use super::{interp, BetaSchedule, PredictionType};
+use super::{interp, BetaSchedule, FinalSigmasType, Generator, PredictionType};
 use tch::{kind, Kind, Tensor};
 
 #[derive(Debug, Clone)]
@@ -13,6 +13,10 @@ pub struct EulerAncestralDiscreteSchedulerConfig {
     pub train_timesteps: usize,
     /// prediction type of the scheduler function
     pub prediction_type: PredictionType,
+    /// The source of the noise added at each step.
+    pub generator: Generator,
+    /// The noise level after the last step.
+    pub final_sigmas_type: FinalSigmasType,
 }
 
 impl Default for EulerAncestralDiscreteSchedulerConfig {
@@ -23,6 +27,8 @@ impl Default for EulerAncestralDiscreteSchedulerConfig {
             beta_schedule: BetaSchedule::ScaledLinear,
             train_timesteps: 1000,
             prediction_type: PredictionType::Epsilon,
+            generator: Generator::default(),
+            final_sigmas_type: FinalSigmasType::Zero,
         }
     }
 }
@@ -35,6 +41,7 @@ impl Default for EulerAncestralDiscreteSchedulerConfig {
 pub struct EulerAncestralDiscreteScheduler {
     timesteps: Vec<f64>,
     sigmas: Vec<f64>,
+    log_sigmas: Vec<f64>,
     init_noise_sigma: f64,
     pub config: EulerAncestralDiscreteSchedulerConfig,
 }
@@ -71,19 +78,22 @@ impl EulerAncestralDiscreteScheduler {
         );
 
         let sigmas = ((1. - &alphas_cumprod) as Tensor / &alphas_cumprod).sqrt();
+        let log_sigmas = sigmas.log();
+        let final_sigma = config.final_sigmas_type.final_sigma(&sigmas);
         let sigmas = interp(
             &timesteps, // x-coordinates at which to evaluate the interpolated values
             Tensor::range(0, sigmas.size1().unwrap() - 1, kind::FLOAT_CPU),
             sigmas,
         );
 
-        let sigmas = Tensor::concat(&[sigmas, Tensor::from_slice(&[0.0])], 0);
+        let sigmas = Tensor::concat(&[sigmas, Tensor::from_slice(&[final_sigma])], 0);
 
         // standard deviation of the initial noise distribution
         let init_noise_sigma: f64 = sigmas.max().try_into().unwrap();
         Self {
             timesteps: timesteps.try_into().unwrap(),
             sigmas: sigmas.try_into().unwrap(),
+            log_sigmas: log_sigmas.try_into().unwrap(),
             init_noise_sigma,
             config,
         }
@@ -93,8 +103,24 @@ impl EulerAncestralDiscreteScheduler {
         self.timesteps.as_slice()
     }
 
+    /// The noise levels used at each of the timesteps, followed by the final sigma given by
+    /// `config.final_sigmas_type`.
+    pub fn sigmas(&self) -> &[f64] {
+        self.sigmas.as_slice()
+    }
+
+    /// Converts a noise level to the corresponding, possibly fractional, training timestep.
+    pub fn sigma_to_timestep(&self, sigma: f64) -> f64 {
+        super::sigma_to_timestep(sigma, &self.log_sigmas)
+    }
+
     pub fn scale_model_input(&self, sample: Tensor, timestep: f64) -> Tensor {
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        self.scale_model_input_by_index(sample, step_index)
+    }
+
+    /// Same as `scale_model_input` but keyed by the index of the current step in `timesteps`.
+    pub fn scale_model_input_by_index(&self, sample: Tensor, step_index: usize) -> Tensor {
         let sigma = self.sigmas[step_index];
 
         // https://github.com/huggingface/diffusers/blob/aba2a65d6ab47c0d1c12fa47e9b238c1d3e34512/src/diffusers/schedulers/scheduling_euler_ancestral_discrete.py#L132
@@ -103,6 +129,17 @@ impl EulerAncestralDiscreteScheduler {
 
     pub fn step(&self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        self.step_by_index(model_output, step_index, sample)
+    }
+
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop, so that the noise schedule is looked up internally.
+    pub fn step_by_index(
+        &self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
         let sigma = self.sigmas[step_index];
 
         // 1. compute predicted original sample (x_0) from sigma-scaled predicted noise
@@ -127,7 +164,7 @@ impl EulerAncestralDiscreteScheduler {
         let dt = sigma_down - sigma;
 
         let prev_sample = sample + derivative * dt;
-        let noise = Tensor::randn_like(model_output);
+        let noise = self.config.generator.randn_like_for_step(step_index, model_output);
 
         prev_sample + noise * sigma_up
     }
@@ -136,11 +173,14 @@ impl EulerAncestralDiscreteScheduler {
         self.init_noise_sigma
     }
 
-    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+    /// Noises `sample` to the level of `timestep` for SDEdit-like img2img, see
+    /// `schedulers::scale_noise`.
+    pub fn scale_noise(&self, sample: &Tensor, noise: &Tensor, timestep: f64) -> Tensor {
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
-        let sigma = self.sigmas[step_index];
+        super::scale_noise(sample, noise, self.sigmas[step_index])
+    }
 
-        // noisy samples
-        original_samples + noise * sigma
+    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+        self.scale_noise(original_samples, &noise, timestep)
     }
 }
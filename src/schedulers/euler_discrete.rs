@@ -1,4 +1,4 @@
-use super::{interp, BetaSchedule, PredictionType};
+use super::{interp, BetaSchedule, FinalSigmasType, Generator, PredictionType};
 use tch::{kind, Kind, Tensor};
 
 #[derive(Debug, Clone)]
@@ -13,6 +13,21 @@ pub struct EulerDiscreteSchedulerConfig {
     pub train_timesteps: usize,
     /// prediction type of the scheduler function
     pub prediction_type: PredictionType,
+    /// The source of the noise added at each step.
+    pub generator: Generator,
+    /// The amount of stochasticity added at each step, 0 gives the deterministic Euler sampler.
+    /// This is spread over the steps and capped so that the noise level is increased by at most
+    /// a factor `sqrt(2)` at each step.
+    pub s_churn: f64,
+    /// The lowest noise level at which churn is applied.
+    pub s_tmin: f64,
+    /// The highest noise level at which churn is applied.
+    pub s_tmax: f64,
+    /// The scale of the noise added when applying churn, slightly above 1 can compensate for the
+    /// loss of detail in the denoised samples.
+    pub s_noise: f64,
+    /// The noise level after the last step.
+    pub final_sigmas_type: FinalSigmasType,
 }
 
 impl Default for EulerDiscreteSchedulerConfig {
@@ -23,6 +38,12 @@ impl Default for EulerDiscreteSchedulerConfig {
             beta_schedule: BetaSchedule::ScaledLinear,
             train_timesteps: 1000,
             prediction_type: PredictionType::Epsilon,
+            generator: Generator::default(),
+            s_churn: 0.0,
+            s_tmin: 0.0,
+            s_tmax: f64::INFINITY,
+            s_noise: 1.0,
+            final_sigmas_type: FinalSigmasType::Zero,
         }
     }
 }
@@ -35,6 +56,7 @@ impl Default for EulerDiscreteSchedulerConfig {
 pub struct EulerDiscreteScheduler {
     timesteps: Vec<f64>,
     sigmas: Vec<f64>,
+    log_sigmas: Vec<f64>,
     init_noise_sigma: f64,
     pub config: EulerDiscreteSchedulerConfig,
 }
@@ -71,12 +93,14 @@ impl EulerDiscreteScheduler {
         );
 
         let sigmas = ((1. - &alphas_cumprod) as Tensor / &alphas_cumprod).sqrt();
+        let log_sigmas = sigmas.log();
+        let final_sigma = config.final_sigmas_type.final_sigma(&sigmas);
         let sigmas = interp(
             &timesteps, // x-coordinates at which to evaluate the interpolated values
             Tensor::range(0, sigmas.size1().unwrap() - 1, kind::FLOAT_CPU),
             sigmas,
         );
-        let sigmas = Tensor::concat(&[sigmas, Tensor::from_slice(&[0.0])], 0);
+        let sigmas = Tensor::concat(&[sigmas, Tensor::from_slice(&[final_sigma])], 0);
 
         // standard deviation of the initial noise distribution
         let init_noise_sigma: f64 = sigmas.max().try_into().unwrap();
@@ -84,6 +108,7 @@ impl EulerDiscreteScheduler {
         Self {
             timesteps: timesteps.try_into().unwrap(),
             sigmas: sigmas.try_into().unwrap(),
+            log_sigmas: log_sigmas.try_into().unwrap(),
             init_noise_sigma,
             config,
         }
@@ -93,8 +118,24 @@ impl EulerDiscreteScheduler {
         self.timesteps.as_slice()
     }
 
+    /// The noise levels used at each of the timesteps, followed by the final sigma given by
+    /// `config.final_sigmas_type`.
+    pub fn sigmas(&self) -> &[f64] {
+        self.sigmas.as_slice()
+    }
+
+    /// Converts a noise level to the corresponding, possibly fractional, training timestep.
+    pub fn sigma_to_timestep(&self, sigma: f64) -> f64 {
+        super::sigma_to_timestep(sigma, &self.log_sigmas)
+    }
+
     pub fn scale_model_input(&self, sample: Tensor, timestep: f64) -> Tensor {
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        self.scale_model_input_by_index(sample, step_index)
+    }
+
+    /// Same as `scale_model_input` but keyed by the index of the current step in `timesteps`.
+    pub fn scale_model_input_by_index(&self, sample: Tensor, step_index: usize) -> Tensor {
         let sigma = self.sigmas[step_index];
 
         // https://github.com/huggingface/diffusers/blob/2bd53a940c60d13421d9e8887af96b30a53c1b95/src/diffusers/schedulers/scheduling_euler_discrete.py#L133
@@ -102,9 +143,20 @@ impl EulerDiscreteScheduler {
     }
 
     pub fn step(&self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
-        let (s_churn, s_tmin, s_tmax, s_noise) = (0.0, 0.0, f64::INFINITY, 1.0);
-
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        self.step_by_index(model_output, step_index, sample)
+    }
+
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop, so that the noise schedule is looked up internally.
+    pub fn step_by_index(
+        &self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
+        let EulerDiscreteSchedulerConfig { s_churn, s_tmin, s_tmax, s_noise, .. } = self.config;
+
         let sigma = self.sigmas[step_index];
 
         let gamma = if s_tmin <= sigma && sigma <= s_tmax {
@@ -113,7 +165,7 @@ impl EulerDiscreteScheduler {
             0.0
         };
 
-        let noise = Tensor::randn_like(model_output);
+        let noise = self.config.generator.randn_like_for_step(step_index, model_output);
         let eps = noise * s_noise;
         let sigma_hat = sigma * (gamma + 1.);
 
@@ -144,10 +196,14 @@ impl EulerDiscreteScheduler {
         self.init_noise_sigma
     }
 
-    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+    /// Noises `sample` to the level of `timestep` for SDEdit-like img2img, see
+    /// `schedulers::scale_noise`.
+    pub fn scale_noise(&self, sample: &Tensor, noise: &Tensor, timestep: f64) -> Tensor {
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
-        let sigma = self.sigmas[step_index];
+        super::scale_noise(sample, noise, self.sigmas[step_index])
+    }
 
-        original_samples + noise * sigma
+    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+        self.scale_noise(original_samples, &noise, timestep)
     }
 }
@@ -30,6 +30,7 @@ impl Default for HeunDiscreteSchedulerConfig {
 pub struct HeunDiscreteScheduler {
     timesteps: Vec<f64>,
     sigmas: Vec<f64>,
+    log_sigmas: Vec<f64>,
     init_noise_sigma: f64,
     prev_derivative: Option<Tensor>,
     sample: Option<Tensor>,
@@ -69,6 +70,7 @@ impl HeunDiscreteScheduler {
         );
 
         let sigmas = ((1. - &alphas_cumprod) as Tensor / &alphas_cumprod).sqrt();
+        let log_sigmas = sigmas.log();
         let sigmas = interp(
             &timesteps, // x-coordinates at which to evaluate the interpolated values
             Tensor::range(0, sigmas.size1().unwrap() - 1, kind::FLOAT_CPU),
@@ -104,6 +106,7 @@ impl HeunDiscreteScheduler {
         Self {
             timesteps: timesteps.try_into().unwrap(),
             sigmas: sigmas.try_into().unwrap(),
+            log_sigmas: log_sigmas.try_into().unwrap(),
             prev_derivative: None,
             dt: None,
             sample: None,
@@ -116,6 +119,16 @@ impl HeunDiscreteScheduler {
         self.timesteps.as_slice()
     }
 
+    /// The noise levels used at each of the timesteps, followed by the final sigma of 0.
+    pub fn sigmas(&self) -> &[f64] {
+        self.sigmas.as_slice()
+    }
+
+    /// Converts a noise level to the corresponding, possibly fractional, training timestep.
+    pub fn sigma_to_timestep(&self, sigma: f64) -> f64 {
+        super::sigma_to_timestep(sigma, &self.log_sigmas)
+    }
+
     fn index_for_timestep(&self, timestep: f64) -> usize {
         // find all the positions of the timesteps corresponding to timestep
         let indices = self
@@ -136,6 +149,11 @@ impl HeunDiscreteScheduler {
     /// current timestep.
     pub fn scale_model_input(&self, sample: Tensor, timestep: f64) -> Tensor {
         let step_index = self.index_for_timestep(timestep);
+        self.scale_model_input_by_index(sample, step_index)
+    }
+
+    /// Same as `scale_model_input` but keyed by the index of the current step in `timesteps`.
+    pub fn scale_model_input_by_index(&self, sample: Tensor, step_index: usize) -> Tensor {
         let sigma = self.sigmas[step_index];
 
         // https://github.com/huggingface/diffusers/blob/aba2a65d6ab47c0d1c12fa47e9b238c1d3e34512/src/diffusers/schedulers/scheduling_heun_discrete.py#L106
@@ -148,7 +166,17 @@ impl HeunDiscreteScheduler {
 
     pub fn step(&mut self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
         let step_index = self.index_for_timestep(timestep);
+        self.step_by_index(model_output, step_index, sample)
+    }
 
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop, so that the noise schedule is looked up internally.
+    pub fn step_by_index(
+        &mut self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
         let (sigma, sigma_next) = if self.state_in_first_order() {
             (self.sigmas[step_index], self.sigmas[step_index + 1])
         } else {
@@ -211,11 +239,14 @@ impl HeunDiscreteScheduler {
         self.init_noise_sigma
     }
 
-    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+    /// Noises `sample` to the level of `timestep` for SDEdit-like img2img, see
+    /// `schedulers::scale_noise`.
+    pub fn scale_noise(&self, sample: &Tensor, noise: &Tensor, timestep: f64) -> Tensor {
         let step_index = self.index_for_timestep(timestep);
-        let sigma = self.sigmas[step_index];
+        super::scale_noise(sample, noise, self.sigmas[step_index])
+    }
 
-        // noisy samples
-        original_samples + noise * sigma
+    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+        self.scale_noise(original_samples, &noise, timestep)
     }
 }
@@ -1,4 +1,4 @@
-use super::{interp, BetaSchedule, PredictionType};
+use super::{interp, BetaSchedule, Generator, PredictionType};
 use tch::{kind, IndexOp, Kind, Tensor};
 
 #[derive(Debug, Clone)]
@@ -13,6 +13,8 @@ pub struct KDPM2AncestralDiscreteSchedulerConfig {
     pub train_timesteps: usize,
     /// prediction type of the scheduler function
     pub prediction_type: PredictionType,
+    /// The source of the noise added at each step.
+    pub generator: Generator,
 }
 
 impl Default for KDPM2AncestralDiscreteSchedulerConfig {
@@ -23,6 +25,7 @@ impl Default for KDPM2AncestralDiscreteSchedulerConfig {
             beta_schedule: BetaSchedule::ScaledLinear,
             train_timesteps: 1000,
             prediction_type: PredictionType::Epsilon,
+            generator: Generator::default(),
         }
     }
 }
@@ -34,6 +37,7 @@ impl Default for KDPM2AncestralDiscreteSchedulerConfig {
 pub struct KDPM2AncestralDiscreteScheduler {
     timesteps: Vec<f64>,
     sigmas: Vec<f64>,
+    log_sigmas: Vec<f64>,
     sigmas_interpol: Vec<f64>,
     sigmas_up: Vec<f64>,
     sigmas_down: Vec<f64>,
@@ -103,7 +107,7 @@ impl KDPM2AncestralDiscreteScheduler {
             sigmas_interpol.index_fill(0, &[sz - 2, sz - 1].as_slice().into(), 0.0);
 
         // interpolate timesteps
-        let timesteps_interpol = Self::sigma_to_t(&sigmas_interpol, log_sigmas);
+        let timesteps_interpol = Self::sigma_to_t(&sigmas_interpol, log_sigmas.shallow_clone());
         let interleaved_timesteps = Tensor::stack(
             &[
                 // timesteps_interpol[:-2, None]
@@ -179,6 +183,7 @@ impl KDPM2AncestralDiscreteScheduler {
         Self {
             timesteps: timesteps.try_into().unwrap(),
             sigmas: sigmas.try_into().unwrap(),
+            log_sigmas: log_sigmas.try_into().unwrap(),
             sigmas_interpol: sigmas_interpol.try_into().unwrap(),
             sigmas_up: sigmas_up.try_into().unwrap(),
             sigmas_down: sigmas_down.try_into().unwrap(),
@@ -220,6 +225,16 @@ impl KDPM2AncestralDiscreteScheduler {
         self.timesteps.as_slice()
     }
 
+    /// The noise levels used at each of the timesteps, followed by the final sigma of 0.
+    pub fn sigmas(&self) -> &[f64] {
+        self.sigmas.as_slice()
+    }
+
+    /// Converts a noise level to the corresponding, possibly fractional, training timestep.
+    pub fn sigma_to_timestep(&self, sigma: f64) -> f64 {
+        super::sigma_to_timestep(sigma, &self.log_sigmas)
+    }
+
     fn index_for_timestep(&self, timestep: f64) -> usize {
         // find all the positions of the timesteps corresponding to timestep
         let indices = self
@@ -239,6 +254,11 @@ impl KDPM2AncestralDiscreteScheduler {
     /// Scales model input by (sigma^2 + 1) ^ .5
     pub fn scale_model_input(&self, sample: Tensor, timestep: f64) -> Tensor {
         let step_index = self.index_for_timestep(timestep);
+        self.scale_model_input_by_index(sample, step_index)
+    }
+
+    /// Same as `scale_model_input` but keyed by the index of the current step in `timesteps`.
+    pub fn scale_model_input_by_index(&self, sample: Tensor, step_index: usize) -> Tensor {
         let step_index_minus_one =
             if step_index == 0 { self.sigmas.len() - 1 } else { step_index - 1 };
 
@@ -257,6 +277,17 @@ impl KDPM2AncestralDiscreteScheduler {
 
     pub fn step(&mut self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
         let step_index = self.index_for_timestep(timestep);
+        self.step_by_index(model_output, step_index, sample)
+    }
+
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop, so that the noise schedule is looked up internally.
+    pub fn step_by_index(
+        &mut self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
         let step_index_minus_one =
             if step_index == 0 { self.sigmas.len() - 1 } else { step_index - 1 };
 
@@ -283,7 +314,7 @@ impl KDPM2AncestralDiscreteScheduler {
         let gamma = 0.0;
         let sigma_hat = sigma * (gamma + 1.); // sigma_hat == sigma for now
 
-        let noise = model_output.randn_like();
+        let noise = self.config.generator.randn_like_for_step(step_index, model_output);
 
         // 1. compute predicted original sample (x_0) from sigma-scaled predicted noise
         let sigma_input = if self.state_in_first_order() { sigma_hat } else { sigma_interpol };
@@ -327,11 +358,14 @@ impl KDPM2AncestralDiscreteScheduler {
         self.init_noise_sigma
     }
 
-    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+    /// Noises `sample` to the level of `timestep` for SDEdit-like img2img, see
+    /// `schedulers::scale_noise`.
+    pub fn scale_noise(&self, sample: &Tensor, noise: &Tensor, timestep: f64) -> Tensor {
         let step_index = self.index_for_timestep(timestep);
-        let sigma = self.sigmas[step_index];
+        super::scale_noise(sample, noise, self.sigmas[step_index])
+    }
 
-        // noisy samples
-        original_samples + noise * sigma
+    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+        self.scale_noise(original_samples, &noise, timestep)
     }
 }
@@ -34,6 +34,7 @@ impl Default for KDPM2DiscreteSchedulerConfig {
 pub struct KDPM2DiscreteScheduler {
     timesteps: Vec<f64>,
     sigmas: Vec<f64>,
+    log_sigmas: Vec<f64>,
     sigmas_interpol: Vec<f64>,
     init_noise_sigma: f64,
     sample: Option<Tensor>,
@@ -101,7 +102,7 @@ impl KDPM2DiscreteScheduler {
         let init_noise_sigma: f64 = sigmas.max().try_into().unwrap();
 
         // interpolate timesteps
-        let timesteps_interpol = Self::sigma_to_t(&sigmas_interpol, log_sigmas);
+        let timesteps_interpol = Self::sigma_to_t(&sigmas_interpol, log_sigmas.shallow_clone());
         let interleaved_timesteps = Tensor::stack(
             &[
                 // timesteps_interpol[1:-1, None]
@@ -138,6 +139,7 @@ impl KDPM2DiscreteScheduler {
         Self {
             timesteps: timesteps.try_into().unwrap(),
             sigmas: sigmas.try_into().unwrap(),
+            log_sigmas: log_sigmas.try_into().unwrap(),
             sigmas_interpol: sigmas_interpol.try_into().unwrap(),
             init_noise_sigma,
             sample: None,
@@ -177,6 +179,16 @@ impl KDPM2DiscreteScheduler {
         self.timesteps.as_slice()
     }
 
+    /// The noise levels used at each of the timesteps, followed by the final sigma of 0.
+    pub fn sigmas(&self) -> &[f64] {
+        self.sigmas.as_slice()
+    }
+
+    /// Converts a noise level to the corresponding, possibly fractional, training timestep.
+    pub fn sigma_to_timestep(&self, sigma: f64) -> f64 {
+        super::sigma_to_timestep(sigma, &self.log_sigmas)
+    }
+
     fn index_for_timestep(&self, timestep: f64) -> usize {
         // find all the positions of the timesteps corresponding to timestep
         let indices = self
@@ -196,7 +208,11 @@ impl KDPM2DiscreteScheduler {
     /// Scales model input by (sigma^2 + 1) ^ .5
     pub fn scale_model_input(&self, sample: Tensor, timestep: f64) -> Tensor {
         let step_index = self.index_for_timestep(timestep);
+        self.scale_model_input_by_index(sample, step_index)
+    }
 
+    /// Same as `scale_model_input` but keyed by the index of the current step in `timesteps`.
+    pub fn scale_model_input_by_index(&self, sample: Tensor, step_index: usize) -> Tensor {
         let sigma = if self.state_in_first_order() {
             self.sigmas[step_index]
         } else {
@@ -212,7 +228,17 @@ impl KDPM2DiscreteScheduler {
 
     pub fn step(&mut self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
         let step_index = self.index_for_timestep(timestep);
+        self.step_by_index(model_output, step_index, sample)
+    }
 
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop, so that the noise schedule is looked up internally.
+    pub fn step_by_index(
+        &mut self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
         let (sigma, sigma_interpol, sigma_next) = if self.state_in_first_order() {
             (
                 self.sigmas[step_index],
@@ -278,11 +304,14 @@ impl KDPM2DiscreteScheduler {
         self.init_noise_sigma
     }
 
-    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+    /// Noises `sample` to the level of `timestep` for SDEdit-like img2img, see
+    /// `schedulers::scale_noise`.
+    pub fn scale_noise(&self, sample: &Tensor, noise: &Tensor, timestep: f64) -> Tensor {
         let step_index = self.index_for_timestep(timestep);
-        let sigma = self.sigmas[step_index];
+        super::scale_noise(sample, noise, self.sigmas[step_index])
+    }
 
-        // noisy samples
-        original_samples + noise * sigma
+    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+        self.scale_noise(original_samples, &noise, timestep)
     }
 }
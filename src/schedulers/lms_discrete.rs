@@ -1,5 +1,5 @@
 use super::integrate::integrate;
-use super::{interp, BetaSchedule, PredictionType};
+use super::{interp, BetaSchedule, FinalSigmasType, PredictionType};
 use tch::{kind, Kind, Tensor};
 
 #[derive(Debug, Clone)]
@@ -17,6 +17,8 @@ pub struct LMSDiscreteSchedulerConfig {
     pub order: usize,
     /// prediction type of the scheduler function
     pub prediction_type: PredictionType,
+    /// The noise level after the last step.
+    pub final_sigmas_type: FinalSigmasType,
 }
 
 impl Default for LMSDiscreteSchedulerConfig {
@@ -28,6 +30,7 @@ impl Default for LMSDiscreteSchedulerConfig {
             train_timesteps: 1000,
             order: 4,
             prediction_type: PredictionType::Epsilon,
+            final_sigmas_type: FinalSigmasType::Zero,
         }
     }
 }
@@ -35,6 +38,7 @@ impl Default for LMSDiscreteSchedulerConfig {
 pub struct LMSDiscreteScheduler {
     timesteps: Vec<f64>,
     sigmas: Vec<f64>,
+    log_sigmas: Vec<f64>,
     init_noise_sigma: f64,
     derivatives: Vec<Tensor>,
     pub config: LMSDiscreteSchedulerConfig,
@@ -72,12 +76,14 @@ impl LMSDiscreteScheduler {
         );
 
         let sigmas = ((1. - &alphas_cumprod) as Tensor / &alphas_cumprod).sqrt();
+        let log_sigmas = sigmas.log();
+        let final_sigma = config.final_sigmas_type.final_sigma(&sigmas);
         let sigmas = interp(
             &timesteps, // x-coordinates at which to evaluate the interpolated values
             Tensor::range(0, sigmas.size1().unwrap() - 1, kind::FLOAT_CPU),
             sigmas,
         );
-        let sigmas = Tensor::concat(&[sigmas, Tensor::from_slice(&[0.0])], 0);
+        let sigmas = Tensor::concat(&[sigmas, Tensor::from_slice(&[final_sigma])], 0);
 
         // standard deviation of the initial noise distribution
         let init_noise_sigma: f64 = sigmas.max().try_into().unwrap();
@@ -85,6 +91,7 @@ impl LMSDiscreteScheduler {
         Self {
             timesteps: timesteps.try_into().unwrap(),
             sigmas: sigmas.try_into().unwrap(),
+            log_sigmas: log_sigmas.try_into().unwrap(),
             init_noise_sigma,
             derivatives: vec![],
             config,
@@ -95,9 +102,25 @@ impl LMSDiscreteScheduler {
         self.timesteps.as_slice()
     }
 
+    /// The noise levels used at each of the timesteps, followed by the final sigma given by
+    /// `config.final_sigmas_type`.
+    pub fn sigmas(&self) -> &[f64] {
+        self.sigmas.as_slice()
+    }
+
+    /// Converts a noise level to the corresponding, possibly fractional, training timestep.
+    pub fn sigma_to_timestep(&self, sigma: f64) -> f64 {
+        super::sigma_to_timestep(sigma, &self.log_sigmas)
+    }
+
     /// Scales the denoising model input by `(sigma^2 + 1)^0.5` to match the K-LMS algorithm.
     pub fn scale_model_input(&self, sample: Tensor, timestep: f64) -> Tensor {
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        self.scale_model_input_by_index(sample, step_index)
+    }
+
+    /// Same as `scale_model_input` but keyed by the index of the current step in `timesteps`.
+    pub fn scale_model_input_by_index(&self, sample: Tensor, step_index: usize) -> Tensor {
         let sigma = self.sigmas[step_index];
 
         // https://github.com/huggingface/diffusers/blob/769f0be8fb41daca9f3cbcffcfd0dbf01cc194b8/src/diffusers/schedulers/scheduling_lms_discrete.py#L132
@@ -130,6 +153,17 @@ impl LMSDiscreteScheduler {
 
     pub fn step(&mut self, model_output: &Tensor, timestep: f64, sample: &Tensor) -> Tensor {
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
+        self.step_by_index(model_output, step_index, sample)
+    }
+
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop, so that the noise schedule is looked up internally.
+    pub fn step_by_index(
+        &mut self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
         let sigma = self.sigmas[step_index];
 
         // 1. compute predicted original sample (x_0) from sigma-scaled predicted noise
@@ -170,11 +204,14 @@ impl LMSDiscreteScheduler {
         self.init_noise_sigma
     }
 
-    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+    /// Noises `sample` to the level of `timestep` for SDEdit-like img2img, see
+    /// `schedulers::scale_noise`.
+    pub fn scale_noise(&self, sample: &Tensor, noise: &Tensor, timestep: f64) -> Tensor {
         let step_index = self.timesteps.iter().position(|&t| t == timestep).unwrap();
-        let sigma = self.sigmas[step_index];
+        super::scale_noise(sample, noise, self.sigmas[step_index])
+    }
 
-        // noisy samples
-        original_samples + noise * sigma
+    pub fn add_noise(&self, original_samples: &Tensor, noise: Tensor, timestep: f64) -> Tensor {
+        self.scale_noise(original_samples, &noise, timestep)
     }
 }
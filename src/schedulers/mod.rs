@@ -3,8 +3,9 @@
 //! Noise schedulers can be used to set the trade-off between
 //! inference speed and quality.
 
-use tch::{IndexOp, Kind, Tensor};
+use tch::{Device, IndexOp, Kind, Tensor};
 
+pub mod consistency_decoder;
 pub mod ddim;
 pub mod ddpm;
 pub mod dpmsolver_multistep;
@@ -36,22 +37,197 @@ pub enum PredictionType {
     Sample,
 }
 
+/// The noise level reached at the end of the last step by the sigma based schedulers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FinalSigmasType {
+    /// Fully denoise the sample on the last step.
+    #[default]
+    Zero,
+    /// Stop at the lowest noise level seen during training, some checkpoints give sharper
+    /// results this way.
+    SigmaMin,
+}
+
+impl FinalSigmasType {
+    /// The final sigma given the training sigmas, sorted by increasing noise level.
+    pub(crate) fn final_sigma(&self, train_sigmas: &Tensor) -> f64 {
+        match self {
+            Self::Zero => 0.,
+            Self::SigmaMin => train_sigmas.double_value(&[0]),
+        }
+    }
+}
+
+/// The result of a scheduler step, as returned by the `step_with_output` methods.
+#[derive(Debug)]
+pub struct SchedulerOutput {
+    /// The sample to be used for the next step, this is what `step` returns.
+    pub prev_sample: Tensor,
+    /// The current estimate of the fully denoised sample, aka x0.
+    pub pred_original_sample: Tensor,
+}
+
+/// Where the random noise used by the stochastic schedulers is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseSource {
+    /// Draw the noise directly on the device of the sample it is added to. This matches python
+    /// diffusers when no generator is passed to the pipeline, the values differ between CPU and
+    /// CUDA for the same seed.
+    Sample,
+    /// Always draw the noise on the CPU using single precision and then move it to the sample
+    /// device. This matches python diffusers with a CPU `torch.Generator`, as well as tools that
+    /// always generate the noise on the CPU, and gives the same values on all devices. This only
+    /// applies to the draws from the global random generator, see `SeedPolicy::PerStepDerived`.
+    Cpu,
+}
+
+/// How the noise added by the schedulers at each step is seeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeedPolicy {
+    /// Continue drawing from the global random generator, the noise of a step then depends on
+    /// all the previous draws.
+    #[default]
+    Streaming,
+    /// Draw the noise of each step from a generator seeded with `base_seed` and the step index.
+    /// The noise of a given step is then the same whatever the previous draws, e.g. when
+    /// regenerating the frames of an animation with slightly different settings. This does not
+    /// use the global random generator, so concurrent generations cannot change the noise. The
+    /// noise is computed on the CPU for all the noise sources and, unlike `Streaming`, does not
+    /// match the one of python diffusers for the same seed.
+    PerStepDerived { base_seed: i64 },
+}
+
+/// The source of random noise for the stochastic schedulers, e.g. the ancestral ones, as well
+/// as for the initial latents of the pipelines.
+///
+/// The draws use the global libtorch random generator, seeded via `tch::manual_seed`, except for
+/// the step noise with `SeedPolicy::PerStepDerived`.
+#[derive(Debug, Clone, Copy)]
+pub struct Generator {
+    pub source: NoiseSource,
+    pub seed_policy: SeedPolicy,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self { source: NoiseSource::Sample, seed_policy: SeedPolicy::Streaming }
+    }
+}
+
+impl Generator {
+    pub fn cpu() -> Self {
+        Self { source: NoiseSource::Cpu, seed_policy: SeedPolicy::Streaming }
+    }
+
+    /// Returns some normally distributed noise with the given shape, kind and device.
+    pub fn randn(&self, size: &[i64], (kind, device): (Kind, Device)) -> Tensor {
+        match self.source {
+            NoiseSource::Sample => Tensor::randn(size, (kind, device)),
+            NoiseSource::Cpu => {
+                Tensor::randn(size, (Kind::Float, Device::Cpu)).to_kind(kind).to_device(device)
+            }
+        }
+    }
+
+    /// Returns some normally distributed noise with the same shape, kind and device as `xs`.
+    pub fn randn_like(&self, xs: &Tensor) -> Tensor {
+        self.randn(&xs.size(), (xs.kind(), xs.device()))
+    }
+
+    /// Same as `randn_like` for the noise added by the scheduler at step `step_index`.
+    pub fn randn_like_for_step(&self, step_index: usize, xs: &Tensor) -> Tensor {
+        match self.seed_policy {
+            SeedPolicy::Streaming => self.randn_like(xs),
+            SeedPolicy::PerStepDerived { base_seed } => {
+                let seed = (base_seed as u64).rotate_left(32) ^ step_index as u64;
+                seeded_randn(seed, &xs.size(), (xs.kind(), xs.device()))
+            }
+        }
+    }
+}
+
+/// Returns some normally distributed noise drawn from a SplitMix64 generator seeded with
+/// `seed`, the values are computed on the CPU without using the global libtorch generator.
+fn seeded_randn(seed: u64, size: &[i64], (kind, device): (Kind, Device)) -> Tensor {
+    let numel = size.iter().product::<i64>() as usize;
+    let mut state = seed;
+    // Uniformly distributed in (0, 1].
+    let mut uniform = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        ((z >> 11) + 1) as f64 / (1u64 << 53) as f64
+    };
+    let mut values = Vec::with_capacity(numel + 1);
+    while values.len() < numel {
+        // Box-Muller transform.
+        let radius = (-2. * uniform().ln()).sqrt();
+        let angle = 2. * std::f64::consts::PI * uniform();
+        values.push((radius * angle.cos()) as f32);
+        values.push((radius * angle.sin()) as f32);
+    }
+    values.truncate(numel);
+    Tensor::from_slice(&values).view(size).to_kind(kind).to_device(device)
+}
+
+/// Noises a sample with the convention of the sigma based schedulers, `sample + sigma * noise`,
+/// whereas the alpha based ones such as DDIM use `sqrt(alpha) * sample + sqrt(1 - alpha) *
+/// noise` in `add_noise`. The two are not interchangeable: the sigma schedulers expect unscaled
+/// samples which are only divided by `sqrt(sigma^2 + 1)` in `scale_model_input`.
+pub fn scale_noise(sample: &Tensor, noise: &Tensor, sigma: f64) -> Tensor {
+    sample + noise * sigma
+}
+
+/// Checks that the number of inference steps is between 1 and the number of timesteps used
+/// during training. The inference timesteps are picked among the training ones so using more
+/// steps would result in an invalid schedule, the schedulers panic in this case.
+pub fn check_inference_steps(inference_steps: usize, train_timesteps: usize) -> anyhow::Result<()> {
+    if inference_steps == 0 || inference_steps > train_timesteps {
+        anyhow::bail!(
+            "the number of inference steps ({inference_steps}) has to be between 1 and the number of training timesteps ({train_timesteps})"
+        )
+    }
+    Ok(())
+}
+
+/// Converts a noise level to a fractional training timestep by linearly interpolating the log
+/// of the training sigmas, `log_sigmas` being indexed by timestep, as done in k-diffusion.
+pub(crate) fn sigma_to_timestep(sigma: f64, log_sigmas: &[f64]) -> f64 {
+    let log_sigma = sigma.max(1e-10).ln();
+    let low_idx = log_sigmas
+        .iter()
+        .rposition(|&s| s <= log_sigma)
+        .unwrap_or(0)
+        .min(log_sigmas.len() - 2);
+    let (low, high) = (log_sigmas[low_idx], log_sigmas[low_idx + 1]);
+    let w = ((low - log_sigma) / (low - high)).clamp(0., 1.);
+    low_idx as f64 + w
+}
+
 /// Create a beta schedule that discretizes the given alpha_t_bar function, which defines the cumulative product of
 /// `(1-beta)` over time from `t = [0,1]`.
 ///
 /// Contains a function `alpha_bar` that takes an argument `t` and transforms it to the cumulative product of `(1-beta)`
 /// up to that part of the diffusion process.
 pub(crate) fn betas_for_alpha_bar(num_diffusion_timesteps: usize, max_beta: f64) -> Tensor {
-    let alpha_bar = |time_step: usize| {
-        f64::cos((time_step as f64 + 0.008) / 1.008 * std::f64::consts::FRAC_PI_2).powi(2)
+    Tensor::from_slice(&alpha_bar_betas(num_diffusion_timesteps, max_beta))
+}
+
+fn alpha_bar_betas(num_diffusion_timesteps: usize, max_beta: f64) -> Vec<f64> {
+    let alpha_bar = |time_step: f64| {
+        f64::cos((time_step + 0.008) / 1.008 * std::f64::consts::FRAC_PI_2).powi(2)
     };
     let mut betas = Vec::with_capacity(num_diffusion_timesteps);
     for i in 0..num_diffusion_timesteps {
-        let t1 = i / num_diffusion_timesteps;
-        let t2 = (i + 1) / num_diffusion_timesteps;
+        // The times are fractions of the diffusion process, an integer division would make all
+        // of them 0 except for the last one.
+        let t1 = i as f64 / num_diffusion_timesteps as f64;
+        let t2 = (i + 1) as f64 / num_diffusion_timesteps as f64;
         betas.push((1.0 - alpha_bar(t2) / alpha_bar(t1)).min(max_beta));
     }
-    Tensor::from_slice(&betas)
+    betas
 }
 
 /// One-dimensional linear interpolation for monotonically increasing sample
@@ -76,3 +252,18 @@ pub fn interp(x: &Tensor, xp: Tensor, yp: Tensor) -> Tensor {
 
     m.take(&indices) * x + b.take(&indices)
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn alpha_bar_betas() {
+        // Reference values from the `squaredcos_cap_v2` schedule of python diffusers.
+        let betas = super::alpha_bar_betas(1000, 0.999);
+        assert_eq!(betas.len(), 1000);
+        for (index, expected) in [(0, 4.128422482196914e-05), (500, 0.003155691441585007)] {
+            assert!((betas[index] - expected).abs() < 1e-12, "beta {index}: {}", betas[index]);
+        }
+        assert!((betas[998] - 0.7499993929011166).abs() < 1e-9);
+        assert_eq!(betas[999], 0.999);
+    }
+}
@@ -20,6 +20,11 @@ pub struct PNDMSchedulerConfig {
     pub steps_offset: usize,
     /// number of diffusion steps used to train the model.
     pub train_timesteps: usize,
+    /// When set, follow the original CompVis PLMS sampler rather than the diffusers one: the
+    /// betas are computed in double precision and the timesteps are taken from
+    /// `range(0, train_timesteps, train_timesteps / inference_steps)`, the latter resulting in
+    /// an additional step when `inference_steps` does not divide `train_timesteps`.
+    pub compvis_compat: bool,
 }
 
 impl Default for PNDMSchedulerConfig {
@@ -32,12 +37,25 @@ impl Default for PNDMSchedulerConfig {
             prediction_type: PredictionType::Epsilon,
             steps_offset: 1,
             train_timesteps: 1000,
+            compvis_compat: false,
         }
     }
 }
 
+impl PNDMSchedulerConfig {
+    /// The default config with `compvis_compat` set, following the schedule of the original
+    /// CompVis PLMS sampler.
+    pub fn compvis_compat() -> Self {
+        Self { compvis_compat: true, ..Default::default() }
+    }
+}
+
 /// Pseudo numerical methods for diffusion models (PNDM) proposes using more advanced ODE
 /// integration techniques, namely Runge-Kutta method and a linear multi-step method.
+///
+/// Only the linear multi-step (PLMS) steps are implemented. The first step is run twice with a
+/// pseudo improved Euler warmup, as done by the original CompVis PLMS sampler, set
+/// `compvis_compat` in the config to also use its betas and timesteps.
 pub struct PNDMScheduler {
     alphas_cumprod: Vec<f64>,
     final_alpha_cumprod: f64,
@@ -52,19 +70,24 @@ pub struct PNDMScheduler {
 
 impl PNDMScheduler {
     pub fn new(inference_steps: usize, config: PNDMSchedulerConfig) -> Self {
+        if let Err(err) = super::check_inference_steps(inference_steps, config.train_timesteps) {
+            panic!("{err}")
+        }
+        // https://github.com/CompVis/stable-diffusion/blob/21f890f9da3cfbeaba8e2ac3c425ee9e998d5229/ldm/modules/diffusionmodules/util.py#L21
+        let options = if config.compvis_compat { kind::DOUBLE_CPU } else { kind::FLOAT_CPU };
         let betas = match config.beta_schedule {
             BetaSchedule::ScaledLinear => Tensor::linspace(
                 config.beta_start.sqrt(),
                 config.beta_end.sqrt(),
                 config.train_timesteps as i64,
-                kind::FLOAT_CPU,
+                options,
             )
             .square(),
             BetaSchedule::Linear => Tensor::linspace(
                 config.beta_start,
                 config.beta_end,
                 config.train_timesteps as i64,
-                kind::FLOAT_CPU,
+                options,
             ),
 
             BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(config.train_timesteps, 0.999),
@@ -78,8 +101,15 @@ impl PNDMScheduler {
         // creates integer timesteps by multiplying by ratio
         // casting to int to avoid issues when num_inference_step is power of 3
         let step_ratio = config.train_timesteps / inference_steps;
-        let timesteps: Vec<usize> =
-            (0..(inference_steps)).map(|s| s * step_ratio + config.steps_offset).collect();
+        let timesteps: Vec<usize> = if config.compvis_compat {
+            // https://github.com/CompVis/stable-diffusion/blob/21f890f9da3cfbeaba8e2ac3c425ee9e998d5229/ldm/modules/diffusionmodules/util.py#L46
+            (0..config.train_timesteps)
+                .step_by(step_ratio)
+                .map(|s| s + config.steps_offset)
+                .collect()
+        } else {
+            (0..(inference_steps)).map(|s| s * step_ratio + config.steps_offset).collect()
+        };
 
         let n_ts = timesteps.len();
         // https://github.com/huggingface/diffusers/blob/8f581591598255eff72cce8858f365eace47481f/src/diffusers/schedulers/scheduling_pndm.py#L173
@@ -113,6 +143,18 @@ impl PNDMScheduler {
         sample
     }
 
+    /// Same as `step` but keyed by the index of the current step in `timesteps`, i.e. the
+    /// iteration index of the denoising loop.
+    pub fn step_by_index(
+        &mut self,
+        model_output: &Tensor,
+        step_index: usize,
+        sample: &Tensor,
+    ) -> Tensor {
+        let timestep = self.timesteps()[step_index];
+        self.step(model_output, timestep, sample)
+    }
+
     pub fn step(&mut self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Tensor {
         self.step_plms(model_output, timestep, sample)
     }
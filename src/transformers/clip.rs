@@ -8,7 +8,7 @@ use std::collections::{HashMap, HashSet};
 use std::io::BufRead;
 use tch::{nn, nn::Module, Device, Kind, Tensor};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Activation {
     QuickGelu,
     Gelu,
@@ -23,7 +23,7 @@ impl Module for Activation {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Config {
     vocab_size: i64,
     embed_dim: i64,         // aka config.hidden_size
@@ -32,9 +32,10 @@ pub struct Config {
     max_position_embeddings: usize,
     // The character to use for padding, use EOS when not set.
     pad_with: Option<String>,
+    // The token id used for padding, takes precedence over `pad_with` when set.
+    pad_token_id: Option<usize>,
     num_hidden_layers: i64,
     num_attention_heads: i64,
-    #[allow(dead_code)]
     projection_dim: i64,
 }
 
@@ -48,6 +49,7 @@ impl Config {
             intermediate_size: 3072,
             max_position_embeddings: 77,
             pad_with: None,
+            pad_token_id: None,
             num_hidden_layers: 12,
             num_attention_heads: 12,
             projection_dim: 768,
@@ -63,12 +65,37 @@ impl Config {
             intermediate_size: 4096,
             max_position_embeddings: 77,
             pad_with: Some("!".to_string()),
+            pad_token_id: None,
             num_hidden_layers: 23,
             num_attention_heads: 16,
             projection_dim: 512,
             activation: Activation::Gelu,
         }
     }
+
+    /// The dimension of the embeddings returned by the text transformer.
+    pub fn embed_dim(&self) -> i64 {
+        self.embed_dim
+    }
+
+    /// The number of parameters of the text transformer, e.g. to estimate the memory used by the
+    /// weights before loading them.
+    pub fn num_parameters(&self) -> i64 {
+        let (d, i) = (self.embed_dim, self.intermediate_size);
+        let embeddings = (self.vocab_size + self.max_position_embeddings as i64) * d;
+        // The attention projections, the two layer norms, and the mlp.
+        let layer = 4 * (d * d + d) + 4 * d + (d + 1) * i + (i + 1) * d;
+        embeddings + self.num_hidden_layers * layer + 2 * d
+    }
+
+    /// Pads the token sequences with the given token id rather than with the default for this
+    /// config, `None` restores the default. The padding has to match the one used when training
+    /// the model for the embeddings to be correct: v1.5 pads with the end of text token, id
+    /// 49407, and v2.1 pads with `!`, id 0.
+    pub fn with_pad_token_id(mut self, pad_token_id: Option<usize>) -> Self {
+        self.pad_token_id = pad_token_id;
+        self
+    }
 }
 
 const BYTES_TO_UNICODE: [(u8, char); 256] = [
@@ -338,6 +365,7 @@ const PAT: &str =
 /// A tokenizer for CLIP.
 pub struct Tokenizer {
     re: regex::Regex,
+    re_break: regex::Regex,
     encoder: HashMap<String, usize>,
     decoder: HashMap<usize, String>,
     bpe_ranks: HashMap<(String, String), usize>,
@@ -385,9 +413,11 @@ impl Tokenizer {
         let bpe_ranks: HashMap<_, _> =
             bpe_lines.into_iter().enumerate().map(|(i, v)| (v, i)).collect();
         let re = regex::Regex::new(PAT)?;
+        let re_break = regex::Regex::new(r"\bBREAK\b")?;
         let tokenizer = Tokenizer {
             encoder,
             re,
+            re_break,
             bpe_ranks,
             decoder,
             start_of_text_token,
@@ -397,6 +427,11 @@ impl Tokenizer {
         Ok(tokenizer)
     }
 
+    /// The number of tokens of the vocabulary, including the start and end of text tokens.
+    pub fn vocab_size(&self) -> usize {
+        self.encoder.len()
+    }
+
     fn get_pairs(word: &[String]) -> HashSet<(String, String)> {
         let mut pairs = HashSet::new();
         for (i, v) in word.iter().enumerate() {
@@ -452,41 +487,131 @@ impl Tokenizer {
         word.iter().filter_map(|x| self.encoder.get(x)).copied().collect()
     }
 
-    pub fn encode_pad(&self, s: &str, pad_size_to: Option<usize>) -> anyhow::Result<Vec<usize>> {
+    // Returns the tokens for a string, without the start and end of text tokens.
+    fn tokenize(&self, s: &str) -> Vec<usize> {
         let s = s.to_lowercase();
-        let mut bpe_tokens: Vec<usize> = vec![self.start_of_text_token];
+        let mut bpe_tokens: Vec<usize> = vec![];
         for token in self.re.captures_iter(&s) {
             let token = token.get(0).unwrap().as_str();
             bpe_tokens.extend(self.bpe(token))
         }
+        bpe_tokens
+    }
+
+    /// The token used to pad sequences to `max_len`, this is the end of text token unless the
+    /// config specifies a padding token id or character.
+    pub fn pad_token(&self) -> anyhow::Result<usize> {
+        if let Some(pad_token_id) = self.config.pad_token_id {
+            if pad_token_id as i64 >= self.config.vocab_size {
+                anyhow::bail!(
+                    "pad token id {pad_token_id} is out of the vocabulary of size {}",
+                    self.config.vocab_size
+                )
+            }
+            return Ok(pad_token_id);
+        }
+        match &self.config.pad_with {
+            None => Ok(self.end_of_text_token),
+            Some(pad_with) => match self.encoder.get(pad_with) {
+                None => anyhow::bail!("no encoding for padding character {}", pad_with),
+                Some(v) => Ok(*v),
+            },
+        }
+    }
+
+    /// Tokenizes a string adding the start and end of text tokens. When `pad_size_to` is set,
+    /// the sequence is truncated or padded with the pad token so that it has exactly this
+    /// length, truncated sequences still end with the end of text token.
+    pub fn encode_pad(&self, s: &str, pad_size_to: Option<usize>) -> anyhow::Result<Vec<usize>> {
+        let mut bpe_tokens: Vec<usize> = vec![self.start_of_text_token];
+        bpe_tokens.extend(self.tokenize(s));
         match pad_size_to {
             None => bpe_tokens.push(self.end_of_text_token),
             Some(pad_size_to) => {
+                bpe_tokens.truncate(pad_size_to - 1);
                 bpe_tokens.push(self.end_of_text_token);
-                bpe_tokens.resize_with(
-                    std::cmp::min(bpe_tokens.len(), pad_size_to - 1),
-                    Default::default,
-                );
-                let pad_with = match &self.config.pad_with {
-                    None => self.end_of_text_token,
-                    Some(pad_with) => match self.encoder.get(pad_with) {
-                        None => anyhow::bail!("no encoding for padding character {}", pad_with),
-                        Some(v) => *v,
-                    },
-                };
-                while bpe_tokens.len() < pad_size_to {
-                    bpe_tokens.push(pad_with)
-                }
+                bpe_tokens.resize(pad_size_to, self.pad_token()?);
             }
         }
         Ok(bpe_tokens)
     }
 
+    /// Same as `encode` when `pad` is true, otherwise the tokens are returned without padding,
+    /// ending with the end of text token. The text transformer is trained on padded sequences
+    /// so the unpadded ones may give slightly different embeddings.
+    pub fn encode_with_padding(&self, s: &str, pad: bool) -> anyhow::Result<Vec<usize>> {
+        if pad {
+            self.encode(s)
+        } else {
+            let mut tokens = self.encode(s)?;
+            let eos = tokens.iter().position(|&t| t == self.end_of_text_token).unwrap();
+            tokens.truncate(eos + 1);
+            Ok(tokens)
+        }
+    }
+
+    /// Same as `encode` when `add_special_tokens` is true, otherwise returns the raw tokens of
+    /// the string without the start and end of text tokens, padding, or truncation. This can
+    /// be used to assemble custom sequences, e.g. with `start_of_text_token` and
+    /// `end_of_text_token`, that have to be `max_len` long for the text transformer.
+    pub fn encode_with_special_tokens(
+        &self,
+        s: &str,
+        add_special_tokens: bool,
+    ) -> anyhow::Result<Vec<usize>> {
+        if add_special_tokens {
+            self.encode(s)
+        } else {
+            Ok(self.tokenize(s))
+        }
+    }
+
+    pub fn start_of_text_token(&self) -> usize {
+        self.start_of_text_token
+    }
+
+    pub fn end_of_text_token(&self) -> usize {
+        self.end_of_text_token
+    }
+
+    /// The length of the padded token sequences.
+    pub fn max_len(&self) -> usize {
+        self.config.max_position_embeddings
+    }
+
     /// The main tokenization entry point, takes as input a string and returns the list of tokens.
+    /// The tokens are padded to exactly `max_position_embeddings`, i.e. 77, with the pad token
+    /// as expected by the text transformer, longer prompts being truncated.
     pub fn encode(&self, s: &str) -> anyhow::Result<Vec<usize>> {
         self.encode_pad(s, Some(self.config.max_position_embeddings))
     }
 
+    /// Tokenizes a prompt of arbitrary length by splitting it in chunks of at most
+    /// `max_position_embeddings - 2` tokens, each chunk is wrapped with the start and end of text
+    /// tokens and padded to `max_position_embeddings`. As in the AUTOMATIC1111 web UI, the
+    /// `BREAK` keyword ends the current chunk so that the following text starts a new one.
+    pub fn encode_chunks(&self, s: &str) -> anyhow::Result<Vec<Vec<usize>>> {
+        let max_len = self.config.max_position_embeddings;
+        let pad_with = self.pad_token()?;
+        let mut chunks = vec![];
+        for segment in self.re_break.split(s) {
+            let tokens = self.tokenize(segment);
+            let mut segment_chunks: Vec<&[usize]> = tokens.chunks(max_len - 2).collect();
+            if segment_chunks.is_empty() {
+                segment_chunks.push(&[])
+            }
+            for chunk in segment_chunks {
+                let mut tokens = Vec::with_capacity(max_len);
+                tokens.push(self.start_of_text_token);
+                tokens.extend_from_slice(chunk);
+                tokens.push(self.end_of_text_token);
+                tokens.resize(max_len, pad_with);
+                chunks.push(tokens)
+            }
+        }
+        Ok(chunks)
+    }
+
     /// The inverse of the tokenization process, takes as input a list of tokens and returns a
     /// string that produces this tokenization.
     pub fn decode(&self, tokens: &[usize]) -> String {
@@ -505,9 +630,13 @@ struct ClipTextEmbeddings {
 }
 
 impl ClipTextEmbeddings {
-    fn new(vs: nn::Path, c: &Config) -> Self {
-        let token_embedding =
-            nn::embedding(&vs / "token_embedding", c.vocab_size, c.embed_dim, Default::default());
+    fn new(vs: nn::Path, token_embedding_vs: nn::Path, c: &Config) -> Self {
+        let token_embedding = nn::embedding(
+            &token_embedding_vs / "token_embedding",
+            c.vocab_size,
+            c.embed_dim,
+            Default::default(),
+        );
         let position_embedding = nn::embedding(
             &vs / "position_embedding",
             c.max_position_embeddings as i64,
@@ -523,8 +652,17 @@ impl ClipTextEmbeddings {
 
 impl Module for ClipTextEmbeddings {
     fn forward(&self, xs: &Tensor) -> Tensor {
-        let token_embedding = self.token_embedding.forward(xs);
-        let position_embedding = self.position_embedding.forward(&self.position_ids);
+        // The token embedding table may live on a different device from the rest of the model,
+        // the lookup is then done there and only the gathered embeddings are transferred.
+        let device = self.position_ids.device();
+        let token_embedding = self
+            .token_embedding
+            .forward(&xs.to_device(self.token_embedding.ws.device()))
+            .to_device(device);
+        // Only use the positions of the actual tokens for unpadded sequences.
+        let seq_len = xs.size()[1];
+        let position_ids = self.position_ids.narrow(1, 0, seq_len);
+        let position_embedding = self.position_embedding.forward(&position_ids);
         token_embedding + position_embedding
     }
 }
@@ -572,7 +710,9 @@ impl ClipAttention {
         let attn_weights = attn_weights.view((bsz, self.num_attention_heads, tgt_len, src_len))
             + causal_attention_mask;
         let attn_weights = attn_weights.view((bsz * self.num_attention_heads, tgt_len, src_len));
-        let attn_weights = attn_weights.softmax(-1, Kind::Float);
+        // The softmax is computed in single precision, the result is converted back to the kind
+        // of the weights so that the model can be run in half precision without autocast.
+        let attn_weights = attn_weights.softmax(-1, Kind::Float).to_kind(value_states.kind());
 
         let attn_output = attn_weights.bmm(&value_states);
         attn_output
@@ -668,16 +808,67 @@ pub struct ClipTextTransformer {
     embeddings: ClipTextEmbeddings,
     encoder: ClipEncoder,
     final_layer_norm: nn::LayerNorm,
+    text_projection: Option<nn::Linear>,
 }
 
 impl ClipTextTransformer {
     pub fn new(vs: nn::Path, c: &Config) -> Self {
+        Self::new_with_token_embedding(vs.clone(), vs, c)
+    }
+
+    /// Creates a text transformer whose token embedding table is stored in `token_embedding_vs`
+    /// rather than `vs`, e.g. to keep this table on the CPU while the transformer layers run on
+    /// a GPU. Both paths should be at the root of the weight naming hierarchy.
+    pub fn new_with_token_embedding(
+        vs: nn::Path,
+        token_embedding_vs: nn::Path,
+        c: &Config,
+    ) -> Self {
         let vs = &vs / "text_model";
-        let embeddings = ClipTextEmbeddings::new(&vs / "embeddings", c);
+        let token_embedding_vs = &token_embedding_vs / "text_model" / "embeddings";
+        let embeddings = ClipTextEmbeddings::new(&vs / "embeddings", token_embedding_vs, c);
         let encoder = ClipEncoder::new(&vs / "encoder", c);
         let final_layer_norm =
             nn::layer_norm(&vs / "final_layer_norm", vec![c.embed_dim], Default::default());
-        ClipTextTransformer { embeddings, encoder, final_layer_norm }
+        ClipTextTransformer { embeddings, encoder, final_layer_norm, text_projection: None }
+    }
+
+    /// Adds the `text_projection` linear layer applied to the pooled output, as found in the
+    /// `CLIPTextModelWithProjection` weights, `vs` should be at the root of the weight naming
+    /// hierarchy.
+    pub fn with_text_projection(mut self, vs: nn::Path, c: &Config) -> Self {
+        let no_bias = nn::LinearConfig { bias: false, ..Default::default() };
+        let text_projection =
+            nn::linear(&vs / "text_projection", c.embed_dim, c.projection_dim, no_bias);
+        self.text_projection = Some(text_projection);
+        self
+    }
+
+    /// The number of rows of the token embedding table.
+    pub fn vocab_size(&self) -> i64 {
+        self.embeddings.token_embedding.ws.size()[0]
+    }
+
+    pub fn has_text_projection(&self) -> bool {
+        self.text_projection.is_some()
+    }
+
+    /// Returns the embeddings, as computed by `forward`, together with the pooled output of shape
+    /// `(batch, dim)`. This is the embedding of the first end of text token of each sequence,
+    /// projected by `text_projection` when loaded, in which case `dim` is the projection
+    /// dimension rather than the embedding one.
+    pub fn forward_with_pooled(&self, xs: &Tensor) -> (Tensor, Tensor) {
+        let embeddings = self.forward(xs);
+        // The end of text token has the largest id in the vocabulary, argmax returns the first
+        // occurrence in sequences padded with end of text tokens.
+        let eos_positions = xs.argmax(-1, false).to_device(embeddings.device());
+        let batch = Tensor::arange(xs.size()[0], (Kind::Int64, embeddings.device()));
+        let pooled = embeddings.index(&[Some(batch), Some(eos_positions)]);
+        let pooled = match &self.text_projection {
+            None => pooled,
+            Some(text_projection) => pooled.apply(text_projection),
+        };
+        (embeddings, pooled)
     }
 
     // https://github.com/huggingface/transformers/blob/674f750a57431222fa2832503a108df3badf1564/src/transformers/models/clip/modeling_clip.py#L678
@@ -696,3 +887,139 @@ impl Module for ClipTextTransformer {
         xs.apply(&self.final_layer_norm)
     }
 }
+
+/// A CLIP tokenizer and text transformer bundled together, mapping prompts to embeddings.
+pub struct TextEncoder {
+    pub tokenizer: Tokenizer,
+    pub model: ClipTextTransformer,
+    device: Device,
+}
+
+impl TextEncoder {
+    /// Creates a text encoder, `device` has to be the device of the model weights. This logs a
+    /// warning when the vocabulary of the tokenizer and the embedding table of the model have
+    /// different sizes, e.g. for a vocabulary file that does not match the model version, as
+    /// the token ids then refer to unrelated embeddings.
+    pub fn new(tokenizer: Tokenizer, model: ClipTextTransformer, device: Device) -> Self {
+        let (vocab_size, table_size) = (tokenizer.vocab_size(), model.vocab_size());
+        if vocab_size as i64 != table_size {
+            log::warn!(
+                "the tokenizer vocabulary has {vocab_size} tokens but the text encoder embedding \
+                 table has {table_size} rows, check that the vocabulary file matches the model"
+            )
+        }
+        Self { tokenizer, model, device }
+    }
+
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    fn forward_tokens(&self, tokens: &[Vec<usize>]) -> Tensor {
+        let _no_grad_guard = tch::no_grad_guard();
+        let tokens: Vec<i64> = tokens.concat().into_iter().map(|x| x as i64).collect();
+        let tokens = Tensor::from_slice(&tokens).view((-1, self.tokenizer.max_len() as i64));
+        self.model.forward(&tokens.to(self.device))
+    }
+
+    /// Tokenizes each prompt, padding or truncating it to the maximum sequence length of 77
+    /// tokens, and returns the embeddings as a tensor of shape `(batch, 77, embed_dim)`.
+    pub fn encode_prompts(&self, prompts: &[&str]) -> anyhow::Result<Tensor> {
+        let tokens =
+            prompts.iter().map(|p| self.tokenizer.encode(p)).collect::<Result<Vec<_>, _>>()?;
+        Ok(self.forward_tokens(&tokens))
+    }
+
+    /// Same as `encode_prompts` but long prompts are split in chunks of 77 tokens, see
+    /// `Tokenizer::encode_chunks`, and the chunk embeddings are concatenated along the sequence
+    /// dimension. Prompts with fewer chunks are extended with empty chunks, so the result has
+    /// shape `(batch, 77 * max_chunks, embed_dim)`.
+    pub fn encode_prompts_chunked(&self, prompts: &[&str]) -> anyhow::Result<Tensor> {
+        let mut chunks = prompts
+            .iter()
+            .map(|p| self.tokenizer.encode_chunks(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        let n_chunks = chunks.iter().map(|c| c.len()).max().unwrap_or(1);
+        let empty_chunk = self.tokenizer.encode_chunks("")?.remove(0);
+        for chunks in chunks.iter_mut() {
+            chunks.resize(n_chunks, empty_chunk.clone());
+        }
+        let embeddings = self.forward_tokens(&chunks.concat());
+        let (_, _, dim) = embeddings.size3()?;
+        Ok(embeddings.reshape([prompts.len() as i64, -1, dim]))
+    }
+
+    /// Returns the embeddings of a single prompt already tokenized with
+    /// `Tokenizer::encode_chunks`, with shape `(1, 77 * chunks.len(), embed_dim)`.
+    pub fn encode_token_chunks(&self, chunks: &[Vec<usize>]) -> anyhow::Result<Tensor> {
+        let embeddings = self.forward_tokens(chunks);
+        let (_, _, dim) = embeddings.size3()?;
+        Ok(embeddings.reshape([1, -1, dim]))
+    }
+}
+
+/// Runs multiple text encoders on the same tokens and concatenates their outputs along the
+/// feature dimension, an optional linear projection is then applied, e.g. to map the result
+/// to the unet cross-attention dimension.
+///
+/// All the encoders get the same tokens so they should share the same vocabulary, and have the
+/// same maximum sequence length.
+#[derive(Debug)]
+pub struct CombinedTextEncoder {
+    encoders: Vec<Box<dyn Module>>,
+    projection: Option<nn::Linear>,
+}
+
+impl CombinedTextEncoder {
+    pub fn new(encoders: Vec<Box<dyn Module>>, projection: Option<nn::Linear>) -> Self {
+        assert!(!encoders.is_empty(), "at least one text encoder is required");
+        Self { encoders, projection }
+    }
+
+    /// Same as `forward` but the second encoder gets the `tokens_2` tokens, e.g. the ones of the
+    /// SDXL `prompt_2`, the other encoders getting `tokens`. All the encoders use `tokens` when
+    /// `tokens_2` is `None`.
+    pub fn forward_with_tokens_2(&self, tokens: &Tensor, tokens_2: Option<&Tensor>) -> Tensor {
+        let xs: Vec<Tensor> = self
+            .encoders
+            .iter()
+            .enumerate()
+            .map(|(index, e)| match tokens_2 {
+                Some(tokens_2) if index == 1 => e.forward(tokens_2),
+                _ => e.forward(tokens),
+            })
+            .collect();
+        let xs = Tensor::cat(&xs, -1);
+        match &self.projection {
+            None => xs,
+            Some(projection) => xs.apply(projection),
+        }
+    }
+
+    /// Returns the embeddings of `prompt`, with shape `(1, 77, dim)`, the second encoder using
+    /// `prompt_2` instead when set. The prompts are padded or truncated to the maximum sequence
+    /// length, see `Tokenizer::encode`, and `device` has to be the device of the weights.
+    pub fn encode_prompt(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        prompt_2: Option<&str>,
+        device: Device,
+    ) -> anyhow::Result<Tensor> {
+        let _no_grad_guard = tch::no_grad_guard();
+        let to_tensor = |prompt: &str| -> anyhow::Result<Tensor> {
+            let tokens: Vec<i64> =
+                tokenizer.encode(prompt)?.into_iter().map(|x| x as i64).collect();
+            Ok(Tensor::from_slice(&tokens).view((1, -1)).to(device))
+        };
+        let tokens = to_tensor(prompt)?;
+        let tokens_2 = prompt_2.map(to_tensor).transpose()?;
+        Ok(self.forward_with_tokens_2(&tokens, tokens_2.as_ref()))
+    }
+}
+
+impl Module for CombinedTextEncoder {
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        self.forward_with_tokens_2(xs, None)
+    }
+}
@@ -10,18 +10,67 @@ pub(crate) fn file_open<P: AsRef<Path>>(path: P) -> anyhow::Result<std::fs::File
     })
 }
 
+/// Reads the shape of the tensor `name` from the header of a `.safetensors` weight file without
+/// loading the weights. Returns `None` if the weight file uses a different format.
+pub fn safetensors_shape<P: AsRef<Path>>(path: P, name: &str) -> anyhow::Result<Option<Vec<i64>>> {
+    use std::io::Read;
+    let path = path.as_ref();
+    if path.extension() != Some(std::ffi::OsStr::new("safetensors")) {
+        return Ok(None);
+    }
+    let mut file = file_open(path)?;
+    let mut header_len = [0u8; 8];
+    file.read_exact(&mut header_len)?;
+    let mut header = vec![0u8; u64::from_le_bytes(header_len) as usize];
+    file.read_exact(&mut header)?;
+    let header = String::from_utf8_lossy(&header);
+    let re = regex::Regex::new(&format!(
+        r#""{}"\s*:\s*\{{[^}}]*"shape"\s*:\s*\[([^\]]*)\]"#,
+        regex::escape(name)
+    ))?;
+    match re.captures(&header) {
+        None => anyhow::bail!("no {name} tensor in {path:?}"),
+        Some(c) => {
+            let shape = c[1]
+                .split(',')
+                .map(|d| d.trim())
+                .filter(|d| !d.is_empty())
+                .map(|d| d.parse())
+                .collect::<Result<_, _>>()?;
+            Ok(Some(shape))
+        }
+    }
+}
+
 pub struct DeviceSetup {
     accelerator_device: Device,
     cpu: Vec<String>,
 }
 
 impl DeviceSetup {
+    /// Uses the Metal backend when available, e.g. on Apple silicon, and otherwise the first
+    /// cuda device if any.
     pub fn new(cpu: Vec<String>) -> Self {
         let accelerator_device =
             if tch::utils::has_mps() { Device::Mps } else { Device::cuda_if_available() };
         Self { accelerator_device, cpu }
     }
 
+    /// Same as `new` but with an explicit device for the models that are not on the CPU, this
+    /// fails if the device is not available.
+    pub fn with_accelerator(accelerator_device: Device, cpu: Vec<String>) -> anyhow::Result<Self> {
+        let available = match accelerator_device {
+            Device::Cpu => true,
+            Device::Cuda(index) => (index as i64) < tch::Cuda::device_count(),
+            Device::Mps => tch::utils::has_mps(),
+            Device::Vulkan => tch::utils::has_vulkan(),
+        };
+        if !available {
+            anyhow::bail!("device {accelerator_device:?} is not available")
+        }
+        Ok(Self { accelerator_device, cpu })
+    }
+
     pub fn get(&self, name: &str) -> Device {
         if self.cpu.iter().any(|c| c == "all" || c == name) {
             Device::Cpu
@@ -30,3 +79,425 @@ impl DeviceSetup {
         }
     }
 }
+
+/// Makes the CUDA computations bit-reproducible between runs, at the cost of some speed, or
+/// restores the default settings. This is a process wide setting.
+///
+/// The cuDNN autotuner is disabled as it can pick different convolution algorithms between
+/// runs. tch does not expose `torch.use_deterministic_algorithms` nor the cuDNN deterministic
+/// flag, so cuDNN is disabled altogether and the convolutions use the deterministic native
+/// kernels. The cuBLAS workspace size is also fixed via `CUBLAS_WORKSPACE_CONFIG` when not
+/// already set, this only has an effect when called before the first CUDA matrix product.
+pub fn set_deterministic(deterministic: bool) {
+    tch::Cuda::cudnn_set_benchmark(false);
+    tch::Cuda::set_user_enabled_cudnn(!deterministic);
+    if deterministic && std::env::var_os("CUBLAS_WORKSPACE_CONFIG").is_none() {
+        std::env::set_var("CUBLAS_WORKSPACE_CONFIG", ":4096:8")
+    }
+}
+
+/// Runs `f` on a CPU copy of `xs` when it is on an MPS device and moves the result back, for the
+/// few operations that are not implemented by the Metal backend, e.g. the antialiased bicubic
+/// resampling. On other devices `f` is applied directly.
+pub(crate) fn with_mps_cpu_fallback(
+    xs: &tch::Tensor,
+    f: impl FnOnce(&tch::Tensor) -> tch::Tensor,
+) -> tch::Tensor {
+    match xs.device() {
+        Device::Mps => f(&xs.to_device(Device::Cpu)).to_device(Device::Mps),
+        _ => f(xs),
+    }
+}
+
+/// Applies a gaussian blur with standard deviation `sigma` to a `(batch, channels, height,
+/// width)` tensor, e.g. to smooth the edges of an inpainting mask. This uses a separable
+/// convolution with a kernel spanning three standard deviations on each side, the borders
+/// being extended by replicating the edge values.
+pub fn gaussian_blur(xs: &tch::Tensor, sigma: f64) -> tch::Tensor {
+    if sigma <= 0. {
+        return xs.shallow_clone();
+    }
+    let (_, channels, _, _) = xs.size4().unwrap();
+    let radius = (3. * sigma).ceil() as i64;
+    let kernel: Vec<f64> =
+        (-radius..=radius).map(|x| (-((x * x) as f64) / (2. * sigma * sigma)).exp()).collect();
+    let sum: f64 = kernel.iter().sum();
+    let kernel: Vec<f64> = kernel.iter().map(|v| v / sum).collect();
+    let kernel = tch::Tensor::from_slice(&kernel).to_kind(xs.kind()).to_device(xs.device());
+    let size = 2 * radius + 1;
+    let kernel_h = kernel.view([1, 1, size, 1]).expand([channels, 1, size, 1], false);
+    let kernel_w = kernel.view([1, 1, 1, size]).expand([channels, 1, 1, size], false);
+    xs.replication_pad2d([radius, radius, radius, radius])
+        .conv2d(&kernel_h, None::<tch::Tensor>, [1, 1], [0, 0], [1, 1], channels)
+        .conv2d(&kernel_w, None::<tch::Tensor>, [1, 1], [0, 0], [1, 1], channels)
+}
+
+/// Spherical linear interpolation between two tensors of the same shape, `t = 0` returning
+/// `a` and `t = 1` returning `b`. Contrary to a linear interpolation, this preserves the norm of
+/// gaussian noise so the result can be used as the initial latents. Falls back to a linear
+/// interpolation when the tensors are almost colinear.
+pub fn slerp(a: &tch::Tensor, b: &tch::Tensor, t: f64) -> tch::Tensor {
+    // Double precision is not available on MPS devices.
+    let (a_cpu, b_cpu) = (a.to_device(Device::Cpu), b.to_device(Device::Cpu));
+    let dot = (&a_cpu * &b_cpu).sum(tch::Kind::Double)
+        / (a_cpu.norm() * b_cpu.norm()).to_kind(tch::Kind::Double);
+    let dot = f64::try_from(dot).unwrap().clamp(-1., 1.);
+    if dot.abs() > 0.9995 {
+        return a * (1. - t) + b * t;
+    }
+    let theta = dot.acos();
+    (a * ((1. - t) * theta).sin() + b * (t * theta).sin()) / theta.sin()
+}
+
+/// The parameters used to generate an image, formatted in the same way as the AUTOMATIC1111
+/// web UI so that the image can be reproduced by other tools.
+#[derive(Debug, Clone)]
+pub struct GenerationParameters {
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub seed: i64,
+    pub steps: usize,
+    pub guidance_scale: f64,
+    pub scheduler: String,
+    pub width: i64,
+    pub height: i64,
+    pub model_hash: Option<String>,
+}
+
+impl std::fmt::Display for GenerationParameters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.prompt)?;
+        if !self.negative_prompt.is_empty() {
+            writeln!(f, "Negative prompt: {}", self.negative_prompt)?;
+        }
+        write!(
+            f,
+            "Steps: {}, Sampler: {}, CFG scale: {}, Seed: {}, Size: {}x{}",
+            self.steps, self.scheduler, self.guidance_scale, self.seed, self.width, self.height
+        )?;
+        if let Some(model_hash) = &self.model_hash {
+            write!(f, ", Model hash: {model_hash}")?;
+        }
+        Ok(())
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32_update(adler: u32, bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Saves an image with 16 bits per channel in the PNG format, e.g. as returned by
+/// `StableDiffusionPipeline::decode_with_format` with `OutputFormat::U16`. The image should
+/// have shape `(channels, height, width)`, a leading batch dimension of size 1 being ignored,
+/// with 1 (grayscale) or 3 (RGB) channels and values in `[0, 65535]`.
+///
+/// `tch::vision::image::save` only supports 8 bits images, the data is stored uncompressed
+/// here to avoid depending on a deflate implementation.
+pub fn save_image_u16<P: AsRef<Path>>(image: &tch::Tensor, path: P) -> anyhow::Result<()> {
+    let image = match image.size().as_slice() {
+        [1, _, _, _] => image.squeeze_dim(0),
+        [_, _, _] => image.shallow_clone(),
+        size => anyhow::bail!("unexpected image shape {size:?}"),
+    };
+    let (channels, height, width) = image.size3()?;
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut png = PngStreamWriter::new(file, width, height, channels, 16)?;
+    png.write_rows(&image)?;
+    png.finish()?;
+    Ok(())
+}
+
+/// Writes a PNG image band by band, so that the whole image never has to be held in memory,
+/// e.g. for `StableDiffusionPipeline::decode_to_png_streaming`. The images have 1 (grayscale)
+/// or 3 (RGB) channels and 8 or 16 bits per channel, the data is stored uncompressed as for
+/// `save_image_u16`.
+pub struct PngStreamWriter<W: std::io::Write> {
+    writer: W,
+    width: i64,
+    height: i64,
+    channels: i64,
+    bit_depth: u8,
+    rows_written: i64,
+    adler: u32,
+}
+
+impl<W: std::io::Write> PngStreamWriter<W> {
+    /// Writes the PNG header, the rows are then added with `write_rows`.
+    pub fn new(
+        mut writer: W,
+        width: i64,
+        height: i64,
+        channels: i64,
+        bit_depth: u8,
+    ) -> anyhow::Result<Self> {
+        let color_type = match channels {
+            1 => 0u8,
+            3 => 2u8,
+            _ => anyhow::bail!("unsupported number of channels {channels}"),
+        };
+        if bit_depth != 8 && bit_depth != 16 {
+            anyhow::bail!("unsupported bit depth {bit_depth}")
+        }
+        let mut ihdr = vec![];
+        ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+        // Bit depth, color type, compression, filter and interlace methods.
+        ihdr.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+
+        let mut header = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_png_chunk(&mut header, b"IHDR", &ihdr);
+        // The zlib stream header, the deflate blocks follow in the next IDAT chunks.
+        write_png_chunk(&mut header, b"IDAT", &[0x78, 0x01]);
+        writer.write_all(&header)?;
+        Ok(Self { writer, width, height, channels, bit_depth, rows_written: 0, adler: 1 })
+    }
+
+    /// Appends some rows to the image, `rows` has shape `(channels, rows, width)` and values in
+    /// `[0, 255]` or `[0, 65535]` depending on the bit depth.
+    pub fn write_rows(&mut self, rows: &tch::Tensor) -> anyhow::Result<()> {
+        let (channels, n_rows, width) = rows.size3()?;
+        if channels != self.channels || width != self.width {
+            anyhow::bail!(
+                "expected rows with {} channels and width {}, got {:?}",
+                self.channels,
+                self.width,
+                rows.size()
+            )
+        }
+        if self.rows_written + n_rows > self.height {
+            anyhow::bail!("too many rows for an image of height {}", self.height)
+        }
+        let max_value = (1 << self.bit_depth) - 1;
+        let values = rows
+            .to_kind(tch::Kind::Int)
+            .clamp(0, max_value)
+            .permute([1, 2, 0])
+            .contiguous()
+            .view(-1)
+            .to_device(tch::Device::Cpu);
+        let values = Vec::<i32>::try_from(&values)?;
+
+        // Each scanline starts with a filter type byte, 0 for no filtering.
+        let row_len = (width * channels) as usize;
+        let bytes_per_value = self.bit_depth as usize / 8;
+        let mut raw = Vec::with_capacity(n_rows as usize * (1 + bytes_per_value * row_len));
+        for row in values.chunks(row_len) {
+            raw.push(0u8);
+            for &v in row {
+                if self.bit_depth == 16 {
+                    raw.extend_from_slice(&(v as u16).to_be_bytes());
+                } else {
+                    raw.push(v as u8);
+                }
+            }
+        }
+        self.adler = adler32_update(self.adler, &raw);
+
+        // Non-final stored deflate blocks, these are limited to 65535 bytes each.
+        let mut zlib = Vec::with_capacity(raw.len() + 5 * raw.chunks(65535).len());
+        for block in raw.chunks(65535) {
+            zlib.push(0);
+            zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        let mut chunk = vec![];
+        write_png_chunk(&mut chunk, b"IDAT", &zlib);
+        self.writer.write_all(&chunk)?;
+        self.rows_written += n_rows;
+        Ok(())
+    }
+
+    /// Completes the image and returns the underlying writer, this returns an error if fewer
+    /// rows than the image height have been written.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        if self.rows_written != self.height {
+            anyhow::bail!("only {} rows written out of {}", self.rows_written, self.height)
+        }
+        // An empty final stored block followed by the zlib checksum.
+        let mut zlib = vec![1, 0, 0, 0xff, 0xff];
+        zlib.extend_from_slice(&self.adler.to_be_bytes());
+        let mut trailer = vec![];
+        write_png_chunk(&mut trailer, b"IDAT", &zlib);
+        write_png_chunk(&mut trailer, b"IEND", &[]);
+        self.writer.write_all(&trailer)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Converts some RGB images of kind `Uint8`, e.g. as returned by
+/// `StableDiffusionPipeline::txt2img`, to `image::RgbImage` values. The tensor has shape
+/// `(batch, 3, height, width)`, or `(3, height, width)` for a single image, and can be on any
+/// device.
+#[cfg(feature = "image")]
+pub fn to_rgb_images(images: &tch::Tensor) -> anyhow::Result<Vec<image::RgbImage>> {
+    let images = match images.dim() {
+        3 => images.unsqueeze(0),
+        4 => images.shallow_clone(),
+        _ => anyhow::bail!("unexpected image shape {:?}", images.size()),
+    };
+    let (bsize, channels, height, width) = images.size4()?;
+    if channels != 3 {
+        anyhow::bail!("expected 3 channels, got {channels}")
+    }
+    // The image crate uses interleaved channels, i.e. a (height, width, channels) layout.
+    let images =
+        images.to_device(Device::Cpu).to_kind(tch::Kind::Uint8).permute([0, 2, 3, 1]).contiguous();
+    (0..bsize)
+        .map(|index| {
+            let data = Vec::<u8>::try_from(&images.get(index).view(-1))?;
+            image::RgbImage::from_raw(width as u32, height as u32, data)
+                .ok_or_else(|| anyhow::anyhow!("unexpected image buffer size"))
+        })
+        .collect()
+}
+
+/// Saves an image using `tch::vision::image::save`, the text is then stored under the
+/// `parameters` keyword in a `tEXt` chunk, or an `iTXt` one for non-ASCII text, when saving to
+/// the PNG format. Other formats are saved without metadata.
+pub fn save_image_with_metadata<P: AsRef<Path>>(
+    image: &tch::Tensor,
+    path: P,
+    parameters: &str,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    tch::vision::image::save(image, path)?;
+    let is_png = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("png"));
+    if !is_png {
+        return Ok(());
+    }
+    let png = std::fs::read(path)?;
+    // The signature is followed by the IHDR chunk which has a 13 bytes payload.
+    const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+    if png.len() < IHDR_END || &png[12..16] != b"IHDR" {
+        anyhow::bail!("unexpected png header in {path:?}")
+    }
+    let mut chunk: Vec<u8> = vec![];
+    if parameters.is_ascii() {
+        chunk.extend_from_slice(b"tEXtparameters\0");
+    } else {
+        // No compression, empty language tag and translated keyword.
+        chunk.extend_from_slice(b"iTXtparameters\0\0\0\0\0");
+    }
+    chunk.extend_from_slice(parameters.as_bytes());
+    let mut out = Vec::with_capacity(png.len() + chunk.len() + 8);
+    out.extend_from_slice(&png[..IHDR_END]);
+    out.extend_from_slice(&(chunk.len() as u32 - 4).to_be_bytes());
+    out.extend_from_slice(&chunk);
+    out.extend_from_slice(&crc32(&chunk).to_be_bytes());
+    out.extend_from_slice(&png[IHDR_END..]);
+    std::fs::write(path, out)?;
+    Ok(())
+}
+
+/// The `(height, width)` aspect ratio buckets used to train SDXL, from the appendix of the SDXL
+/// paper, all around one megapixel and with sides multiple of 64.
+/// https://arxiv.org/abs/2307.01952
+pub const SDXL_BUCKETS: [(i64, i64); 41] = [
+    (512, 2048),
+    (512, 1984),
+    (512, 1920),
+    (512, 1856),
+    (576, 1792),
+    (576, 1728),
+    (576, 1664),
+    (640, 1600),
+    (640, 1536),
+    (704, 1472),
+    (704, 1408),
+    (704, 1344),
+    (768, 1344),
+    (768, 1280),
+    (832, 1216),
+    (832, 1152),
+    (896, 1152),
+    (896, 1088),
+    (960, 1088),
+    (960, 1024),
+    (1024, 1024),
+    (1024, 960),
+    (1088, 960),
+    (1088, 896),
+    (1152, 896),
+    (1152, 832),
+    (1216, 832),
+    (1280, 768),
+    (1344, 768),
+    (1344, 704),
+    (1408, 704),
+    (1472, 704),
+    (1536, 640),
+    (1600, 640),
+    (1664, 576),
+    (1728, 576),
+    (1792, 576),
+    (1856, 512),
+    (1920, 512),
+    (1984, 512),
+    (2048, 512),
+];
+
+/// The size conditioning of the SDXL unet, all the sizes being `(height, width)` in pixels. The
+/// unet of this crate does not take it as input yet, the values can be passed to other tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeConditioning {
+    pub original_size: (i64, i64),
+    pub crop_coords_top_left: (i64, i64),
+    pub target_size: (i64, i64),
+}
+
+impl SizeConditioning {
+    /// The values in the order of the `add_time_ids` of the python diffusers SDXL pipelines.
+    pub fn time_ids(&self) -> [i64; 6] {
+        let Self { original_size: (oh, ow), crop_coords_top_left: (ct, cl), target_size } = *self;
+        [oh, ow, ct, cl, target_size.0, target_size.1]
+    }
+}
+
+/// Snaps a requested image size to the SDXL bucket with the closest aspect ratio, scaled to
+/// about `megapixels` million pixels, 1 megapixel being `1024x1024`, with sides rounded to
+/// multiples of 64. The returned conditioning uses the bucket as both the original and the
+/// target size without cropping, SDXL produces its best images for these sizes.
+pub fn sdxl_bucket(width: i64, height: i64, megapixels: f64) -> SizeConditioning {
+    assert!(width > 0 && height > 0, "invalid image size {width}x{height}");
+    assert!(megapixels > 0., "the megapixel budget has to be positive, got {megapixels}");
+    let log_ratio = (height as f64 / width as f64).ln();
+    let (bucket_height, bucket_width) = SDXL_BUCKETS
+        .into_iter()
+        .min_by(|&(h1, w1), &(h2, w2)| {
+            let d1 = ((h1 as f64 / w1 as f64).ln() - log_ratio).abs();
+            let d2 = ((h2 as f64 / w2 as f64).ln() - log_ratio).abs();
+            d1.total_cmp(&d2)
+        })
+        .unwrap();
+    let scale = (megapixels * 1048576. / (bucket_height * bucket_width) as f64).sqrt();
+    let round = |size: i64| i64::max(64, (size as f64 * scale / 64.).round() as i64 * 64);
+    let size = (round(bucket_height), round(bucket_width));
+    SizeConditioning { original_size: size, crop_coords_top_left: (0, 0), target_size: size }
+}
@@ -0,0 +1,144 @@
+// End-to-end regression tests on the txt2img pipeline.
+//
+// These run a couple of denoising steps with a fixed seed on the cpu and compare a hash of the
+// resulting latents with the one recorded in `tests/golden/`. They require the v1.5 weights in
+// the data directory, see the stable-diffusion example, and are skipped when the weights are not
+// available. A test whose hash has not been recorded fails, reporting the hash of the latents
+// it generated.
+//
+// cargo test --features golden-tests --test golden
+//
+// When a change is expected to modify the generated latents, the reference hashes can be
+// regenerated by running the tests with `DIFFUSERS_BLESS=1`. The hashes only guard against
+// regressions, the latents are not compared with the ones of other implementations.
+use diffusers::pipelines::stable_diffusion;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+const VOCAB_FILE: &str = "data/bpe_simple_vocab_16e6.txt";
+const CLIP_WEIGHTS: &str = "data/pytorch_model.safetensors";
+const VAE_WEIGHTS: &str = "data/vae.safetensors";
+const UNET_WEIGHTS: &str = "data/unet.safetensors";
+const GOLDEN_FILE: &str = "tests/golden/txt2img_latents.sha256";
+const PNDM_COMPVIS_GOLDEN_FILE: &str = "tests/golden/txt2img_pndm_compvis_latents.sha256";
+const PROMPT: &str = "A rusty robot holding a fire torch";
+const SEED: i64 = 42;
+
+/// Hashes the latents after rounding them to 3 decimals so that the hash is not sensitive to
+/// tiny floating point differences between libtorch builds.
+fn latents_hash(latents: &tch::Tensor) -> anyhow::Result<String> {
+    let values = Vec::<f32>::try_from(latents.flatten(0, -1))?;
+    let mut hasher = Sha256::new();
+    for v in values {
+        hasher.update(((v * 1000.).round() as i32).to_le_bytes());
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Builds a pipeline running on the cpu with the given scheduler config, returns `None` when
+/// the weights are not available.
+fn build_pipeline(
+    scheduler: diffusers::schedulers::ddim::DDIMSchedulerConfig,
+) -> anyhow::Result<Option<stable_diffusion::StableDiffusionPipeline>> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let missing: Vec<_> = [VOCAB_FILE, CLIP_WEIGHTS, VAE_WEIGHTS, UNET_WEIGHTS]
+        .into_iter()
+        .filter(|f| !root.join(f).exists())
+        .collect();
+    if !missing.is_empty() {
+        eprintln!("skipping the golden test, missing files: {missing:?}");
+        return Ok(None);
+    }
+    let path = |f: &str| root.join(f).to_string_lossy().to_string();
+
+    let mut sd_config = stable_diffusion::StableDiffusionConfig::v1_5(None, Some(256), Some(256));
+    sd_config.scheduler = scheduler;
+    let device_setup = diffusers::utils::DeviceSetup::new(vec!["all".to_string()]);
+    let pipeline = stable_diffusion::StableDiffusionPipeline::new(
+        sd_config,
+        &path(VOCAB_FILE),
+        &path(CLIP_WEIGHTS),
+        &path(VAE_WEIGHTS),
+        &path(UNET_WEIGHTS),
+        4,
+        &device_setup,
+    )?;
+    Ok(Some(pipeline))
+}
+
+/// Generates some latents on the cpu using the given scheduler config, returns `None` when the
+/// weights are not available.
+fn generate_latents(
+    scheduler: diffusers::schedulers::ddim::DDIMSchedulerConfig,
+    n_steps: usize,
+) -> anyhow::Result<Option<tch::Tensor>> {
+    let pipeline = match build_pipeline(scheduler)? {
+        None => return Ok(None),
+        Some(pipeline) => pipeline,
+    };
+    let sampling_config =
+        stable_diffusion::SamplingConfig { n_steps, seed: SEED, ..Default::default() };
+    let latents = tch::no_grad(|| pipeline.txt2img_latents(PROMPT, "", &sampling_config))?;
+    Ok(Some(latents))
+}
+
+/// Compares the hash of `latents` with the one recorded in `golden_file`, or records it when
+/// `DIFFUSERS_BLESS` is set.
+fn check_golden(latents: &tch::Tensor, golden_file: &str) -> anyhow::Result<()> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let hash = latents_hash(latents)?;
+    let golden_path = root.join(golden_file);
+    if std::env::var_os("DIFFUSERS_BLESS").is_some() {
+        std::fs::create_dir_all(golden_path.parent().unwrap())?;
+        std::fs::write(&golden_path, format!("{hash}\n"))?;
+        eprintln!("recorded golden hash {hash}");
+        return Ok(());
+    }
+    let expected = match std::fs::read_to_string(&golden_path) {
+        Ok(expected) => expected,
+        Err(_) => anyhow::bail!(
+            "no golden hash in {golden_file}, the generated latents hash to {hash}, run with \
+             DIFFUSERS_BLESS=1 to record it"
+        ),
+    };
+    assert_eq!(hash, expected.trim(), "the generated latents changed");
+    Ok(())
+}
+
+#[test]
+fn txt2img_latents_golden() -> anyhow::Result<()> {
+    let scheduler = Default::default();
+    match generate_latents(scheduler, 2)? {
+        None => Ok(()),
+        Some(latents) => check_golden(&latents, GOLDEN_FILE),
+    }
+}
+
+#[test]
+fn txt2img_pndm_compvis_golden() -> anyhow::Result<()> {
+    use diffusers::schedulers::pndm::{PNDMScheduler, PNDMSchedulerConfig};
+    let pipeline = match build_pipeline(Default::default())? {
+        None => return Ok(()),
+        Some(pipeline) => pipeline,
+    };
+    // The pipeline only runs DDIM so the PLMS sampling loop is written out here.
+    let mut scheduler = PNDMScheduler::new(4, PNDMSchedulerConfig::compvis_compat());
+    let latents = tch::no_grad(|| -> anyhow::Result<tch::Tensor> {
+        let text_embeddings = pipeline.encode_prompt(PROMPT, "")?;
+        tch::manual_seed(SEED);
+        let size = [1, 4, pipeline.config.height / 8, pipeline.config.width / 8];
+        let mut latents = tch::Tensor::randn(size, (tch::Kind::Float, pipeline.unet_device))
+            * scheduler.init_noise_sigma();
+        for timestep in scheduler.timesteps().to_vec() {
+            let latent_model_input = tch::Tensor::cat(&[&latents, &latents], 0);
+            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep);
+            let noise_pred =
+                pipeline.unet.forward(&latent_model_input, timestep as f64, &text_embeddings);
+            let (noise_pred_uncond, noise_pred_text) = pipeline.cfg_order().split(&noise_pred);
+            let noise_pred = &noise_pred_uncond + (noise_pred_text - &noise_pred_uncond) * 7.5;
+            latents = scheduler.step(&noise_pred, timestep, &latents);
+        }
+        Ok(latents)
+    })?;
+    check_golden(&latents, PNDM_COMPVIS_GOLDEN_FILE)
+}